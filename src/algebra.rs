@@ -0,0 +1,218 @@
+use anyhow::anyhow;
+use std::ops::{Index, IndexMut, Mul};
+
+// Matriz densa por filas. Alcanza para los sistemas chicos de las
+// calibraciones y los ajustes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matriz {
+    pub filas: usize,
+    pub columnas: usize,
+    datos: Vec<f64>,
+}
+
+impl Index<(usize, usize)> for Matriz {
+    type Output = f64;
+    fn index(&self, (i, j): (usize, usize)) -> &f64 {
+        &self.datos[i * self.columnas + j]
+    }
+}
+
+impl IndexMut<(usize, usize)> for Matriz {
+    fn index_mut(&mut self, (i, j): (usize, usize)) -> &mut f64 {
+        &mut self.datos[i * self.columnas + j]
+    }
+}
+
+impl Mul for &Matriz {
+    type Output = Matriz;
+    fn mul(self, otra: &Matriz) -> Matriz {
+        assert_eq!(self.columnas, otra.filas);
+        let mut m = Matriz::ceros(self.filas, otra.columnas);
+        for i in 0..self.filas {
+            for k in 0..self.columnas {
+                let v = self[(i, k)];
+                if v != 0. {
+                    for j in 0..otra.columnas {
+                        m[(i, j)] += v * otra[(k, j)];
+                    }
+                }
+            }
+        }
+        m
+    }
+}
+
+impl Matriz {
+    pub fn ceros(filas: usize, columnas: usize) -> Self {
+        Matriz { filas, columnas, datos: vec![0.; filas * columnas] }
+    }
+
+    pub fn identidad(n: usize) -> Self {
+        let mut m = Matriz::ceros(n, n);
+        for i in 0..n {
+            m[(i, i)] = 1.;
+        }
+        m
+    }
+
+    pub fn de_filas(filas: &[Vec<f64>]) -> Self {
+        let columnas = filas.first().map(|f| f.len()).unwrap_or(0);
+        Matriz { filas: filas.len(), columnas, datos: filas.concat() }
+    }
+
+    pub fn transpuesta(&self) -> Self {
+        let mut t = Matriz::ceros(self.columnas, self.filas);
+        for i in 0..self.filas {
+            for j in 0..self.columnas {
+                t[(j, i)] = self[(i, j)];
+            }
+        }
+        t
+    }
+
+    pub fn por_vector(&self, v: &[f64]) -> Vec<f64> {
+        (0..self.filas)
+            .map(|i| (0..self.columnas).map(|j| self[(i, j)] * v[j]).sum())
+            .collect()
+    }
+
+    // Factor triangular inferior L con L·Lᵀ = self. Falla si la matriz no
+    // es definida positiva, que en un ajuste es falta de datos.
+    pub fn cholesky(&self) -> Result<Matriz, anyhow::Error> {
+        if self.filas != self.columnas {
+            return Err(anyhow!(format!("Cholesky de una matriz no cuadrada: {}x{}", self.filas, self.columnas)));
+        }
+        let n = self.filas;
+        let mut l = Matriz::ceros(n, n);
+        for j in 0..n {
+            let d = self[(j, j)] - (0..j).map(|k| l[(j, k)].powi(2)).sum::<f64>();
+            if d <= 1e-12 * self[(j, j)].abs().max(1.) {
+                return Err(anyhow!(format!("Sistema singular en la incógnita {}", j)));
+            }
+            l[(j, j)] = d.sqrt();
+            for i in j + 1..n {
+                let s = self[(i, j)] - (0..j).map(|k| l[(i, k)] * l[(j, k)]).sum::<f64>();
+                l[(i, j)] = s / l[(j, j)];
+            }
+        }
+        Ok(l)
+    }
+
+    // Resuelve self·x = b con self simétrica definida positiva
+    pub fn resolver(&self, b: &[f64]) -> Result<Vec<f64>, anyhow::Error> {
+        let l = self.cholesky()?;
+        Ok(sustituir(&l, b))
+    }
+
+    pub fn inversa(&self) -> Result<Matriz, anyhow::Error> {
+        let l = self.cholesky()?;
+        let n = self.filas;
+        let mut inv = Matriz::ceros(n, n);
+        for j in 0..n {
+            let mut e = vec![0.; n];
+            e[j] = 1.;
+            for (i, v) in sustituir(&l, &e).into_iter().enumerate() {
+                inv[(i, j)] = v;
+            }
+        }
+        Ok(inv)
+    }
+}
+
+// Adelante con L y atrás con Lᵀ
+fn sustituir(l: &Matriz, b: &[f64]) -> Vec<f64> {
+    let n = l.filas;
+    let mut y = vec![0.; n];
+    for i in 0..n {
+        y[i] = (b[i] - (0..i).map(|k| l[(i, k)] * y[k]).sum::<f64>()) / l[(i, i)];
+    }
+    let mut x = vec![0.; n];
+    for i in (0..n).rev() {
+        x[i] = (y[i] - (i + 1..n).map(|k| l[(k, i)] * x[k]).sum::<f64>()) / l[(i, i)];
+    }
+    x
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AjusteLineal {
+    pub incognitas: Vec<f64>,
+    // v = A·x - l
+    pub residuos: Vec<f64>,
+    pub grados_de_libertad: usize,
+    // Varianza a posteriori de la unidad de peso, vᵀPv / gl; 1 si gl = 0
+    pub varianza_unitaria: f64,
+    // (AᵀPA)⁻¹
+    pub cofactores: Matriz,
+}
+
+// Mínimos cuadrados ponderados de A·x ≈ l, con pesos diagonales
+pub fn minimos_cuadrados(a: &Matriz, l: &[f64], pesos: Option<&[f64]>) -> Result<AjusteLineal, anyhow::Error> {
+    if a.filas != l.len() {
+        return Err(anyhow!(format!("{} ecuaciones y {} observaciones", a.filas, l.len())));
+    }
+    if a.filas < a.columnas {
+        return Err(anyhow!(format!("Faltan observaciones: {} para {} incógnitas", a.filas, a.columnas)));
+    }
+    let p = |i: usize| pesos.map(|w| w[i]).unwrap_or(1.);
+
+    let mut n = Matriz::ceros(a.columnas, a.columnas);
+    let mut u = vec![0.; a.columnas];
+    for k in 0..a.filas {
+        for i in 0..a.columnas {
+            let pa = p(k) * a[(k, i)];
+            if pa == 0. {
+                continue;
+            }
+            u[i] += pa * l[k];
+            for j in 0..a.columnas {
+                n[(i, j)] += pa * a[(k, j)];
+            }
+        }
+    }
+    let cofactores = n.inversa()?;
+    let incognitas = cofactores.por_vector(&u);
+    let residuos: Vec<f64> = a.por_vector(&incognitas).iter().zip(l).map(|(c, o)| c - o).collect();
+    let grados_de_libertad = a.filas - a.columnas;
+    let vpv: f64 = residuos.iter().enumerate().map(|(i, v)| p(i) * v * v).sum();
+    Ok(AjusteLineal {
+        incognitas,
+        residuos,
+        grados_de_libertad,
+        varianza_unitaria: if grados_de_libertad > 0 { vpv / grados_de_libertad as f64 } else { 1. },
+        cofactores,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cholesky_e_inversa() {
+        let m = Matriz::de_filas(&[vec![4., 12., -16.], vec![12., 37., -43.], vec![-16., -43., 98.]]);
+        let l = m.cholesky().unwrap();
+        assert_eq!(l, Matriz::de_filas(&[vec![2., 0., 0.], vec![6., 1., 0.], vec![-8., 5., 3.]]));
+        let i = &m * &m.inversa().unwrap();
+        for f in 0..3 {
+            for c in 0..3 {
+                assert!((i[(f, c)] - Matriz::identidad(3)[(f, c)]).abs() < 1e-9);
+            }
+        }
+        assert!(Matriz::de_filas(&[vec![1., 2.], vec![2., 4.]]).cholesky().is_err());
+    }
+
+    #[test]
+    fn test_minimos_cuadrados() {
+        // Recta y = 1 + 2x con un residuo simétrico
+        let a = Matriz::de_filas(&[vec![1., 0.], vec![1., 1.], vec![1., 2.], vec![1., 3.]]);
+        let l = [1.1, 2.9, 5.1, 6.9];
+        let ajuste = minimos_cuadrados(&a, &l, None).unwrap();
+        assert!((ajuste.incognitas[0] - 1.06).abs() < 1e-9);
+        assert!((ajuste.incognitas[1] - 1.96).abs() < 1e-9);
+        assert_eq!(ajuste.grados_de_libertad, 2);
+        let suma: f64 = ajuste.residuos.iter().sum();
+        assert!(suma.abs() < 1e-9);
+
+        assert!(minimos_cuadrados(&a, &l[..3], None).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::{fs, fs::File, path::PathBuf};
+
+use rw5_file_parser::{
+    correccion_base::{corregir_lineas, leer_coordenadas, CorreccionBase},
+    crs::crs_de_lineas,
+    exportar::{desplazamientos_a_csv, promedios_a_csv},
+    file_parser::{combinar_hasta_estabilizar, lineas_a_registros},
+    geoide::tipo_de_altura_de_lineas,
+    post_parse_gps::RelevamientoGNSS,
+};
+use clap::Parser;
+
+/// Corrige las sesiones de una base con su posición postprocesada y escribe
+/// el RW5 corregido. Las coordenadas salen de un archivo
+/// "nombre,latitud,longitud,elevacion" o de --base con --latitud,
+/// --longitud y --elevacion.
+#[derive(Parser,Debug)]
+struct Arguments {
+    rwpath: PathBuf,
+    outpath: PathBuf,
+    #[arg(long)]
+    coordenadas: Option<PathBuf>,
+    #[arg(long)]
+    base: Option<String>,
+    #[arg(long, allow_negative_numbers = true)]
+    latitud: Option<f64>,
+    #[arg(long, allow_negative_numbers = true)]
+    longitud: Option<f64>,
+    #[arg(long, allow_negative_numbers = true)]
+    elevacion: Option<f64>,
+    /// Distancia máxima entre la base del archivo y la corregida, en metros
+    #[arg(long, default_value_t = 5.)]
+    tolerancia: f64,
+    /// Promedios de los puntos corregidos
+    #[arg(long)]
+    csv: Option<PathBuf>,
+    /// Detalle de cada registro movido
+    #[arg(long)]
+    desplazamientos: Option<PathBuf>,
+}
+
+fn main() {
+
+    let args = Arguments::parse();
+
+    let mut correcciones = match &args.coordenadas {
+        Some(p) => leer_coordenadas(p).expect("No se pudo leer el archivo de coordenadas"),
+        None => vec![],
+    };
+    if let Some(nombre) = &args.base {
+        match (args.latitud, args.longitud, args.elevacion) {
+            (Some(latitud), Some(longitud), Some(elevacion)) =>
+                correcciones.push(CorreccionBase { nombre: nombre.clone(), latitud, longitud, elevacion }),
+            _ => panic!("--base necesita --latitud, --longitud y --elevacion"),
+        }
+    }
+    if correcciones.is_empty() {
+        panic!("No hay coordenadas de base para aplicar");
+    }
+
+    let contenido = fs::read_to_string(&args.rwpath).expect("No se pudo abrir el archivo");
+    let lineas: Vec<&str> = contenido.lines().collect();
+    let crs = crs_de_lineas(&lineas).unwrap_or(None);
+    let altura = tipo_de_altura_de_lineas(&lineas);
+
+    let resultado = corregir_lineas(&lineas, &correcciones, args.tolerancia, crs.as_ref().map(|c| &c.proyeccion))
+        .expect("No se pudo corregir el archivo");
+    eprintln!("{} registros corregidos", resultado.desplazamientos.len());
+
+    let fin = if contenido.contains("\r\n") { "\r\n" } else { "\n" };
+    fs::write(&args.outpath, resultado.lineas.join(fin) + fin)
+        .expect("Falló la escritura del RW5");
+
+    if let Some(p) = &args.desplazamientos {
+        let mut salida = File::create(p).expect("No se pudo crear el CSV");
+        desplazamientos_a_csv(&resultado.desplazamientos, &mut salida).expect("Falló la escritura del CSV");
+    }
+
+    if let Some(p) = &args.csv {
+        let lineas: Vec<&str> = resultado.lineas.iter().map(|l| l.as_str()).collect();
+        let parseo = lineas_a_registros(lineas).expect("No se pudo procesar el archivo corregido");
+        let registros = combinar_hasta_estabilizar(parseo.registros);
+
+        let mut rel = RelevamientoGNSS::new(&registros);
+        rel.con_altura(altura);
+        rel.estimar_reloj();
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+        let promedios = rel.promediar_puntos(3.);
+
+        let mut salida = File::create(p).expect("No se pudo crear el CSV");
+        promedios_a_csv(&promedios, &mut salida).expect("Falló la escritura del CSV");
+        if let Some(crs) = &crs {
+            crs.escribir_prj(p).expect("Falló la escritura del .prj");
+        }
+    }
+}
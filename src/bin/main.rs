@@ -1,7 +1,7 @@
-use std::path::PathBuf;
+use std::{fs, path::PathBuf};
 
 use rinex::{observation::{event, EpochFlag}, record, Rinex};
-use rw5_file_parser::{file_parser::de_archivo_a_registros, post_parse_gps::registros_a_eventos};
+use rw5_file_parser::{file_parser::{combinar_hasta_estabilizar, lineas_a_registros}, filtros::FiltroCalidad, geoide::tipo_de_altura_de_lineas, post_parse_gps::registros_a_eventos};
 use clap::Parser;
 
 #[derive(Parser,Debug)]
@@ -9,15 +9,24 @@ struct Arguments {
     rwpath: PathBuf,
     rxpath: PathBuf,
     outpath: PathBuf,
+    /// Por defecto sólo las soluciones fijas pasan a eventos
+    #[arg(long)]
+    incluir_flotantes: bool,
 }
 
 fn main() {
         
     let args = Arguments::parse();
 
-    let registros_gps = de_archivo_a_registros(&args.rwpath);
+    let contenido = fs::read_to_string(&args.rwpath).expect("No se pudo abrir el archivo");
+    let lineas: Vec<&str> = contenido.lines().collect();
+    let altura = tipo_de_altura_de_lineas(&lineas);
+    let parseo = lineas_a_registros(lineas).expect("No se pudo procesar el archivo");
+    let registros_gps = combinar_hasta_estabilizar(parseo.registros);
 
-    let all_evt_record = registros_a_eventos(registros_gps);
+    let filtro = if args.incluir_flotantes { FiltroCalidad::default() } else { FiltroCalidad::fijas() };
+
+    let all_evt_record = registros_a_eventos(registros_gps, &filtro, altura);
 
     
     let rx = Rinex::from_file(&args.rxpath.to_string_lossy()).unwrap();
@@ -0,0 +1,285 @@
+use crate::algebra::{minimos_cuadrados, Matriz};
+use crate::proyeccion::{Geodesicas, TransversaMercator};
+use anyhow::anyhow;
+use serde::Serialize;
+use std::{fs, path::Path};
+
+// Coordenadas locales conocidas de un punto de control
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PuntoDeControl {
+    pub nombre: String,
+    pub norte: f64,
+    pub este: f64,
+    pub elevacion: f64,
+}
+
+// "nombre,norte,este,elevacion" por línea. Sólo la primera puede no tener
+// números (el encabezado); en las demás es un error.
+pub fn leer_puntos_de_control(archivo: &Path) -> Result<Vec<PuntoDeControl>, anyhow::Error> {
+    let contenido = fs::read_to_string(archivo)?;
+    let mut puntos = vec![];
+    let lineas = contenido.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#'));
+    for (i, linea) in lineas.enumerate() {
+        let campos: Vec<&str> = linea.split(',').map(|c| c.trim()).collect();
+        if campos.len() != 4 {
+            return Err(anyhow!(format!("Línea de puntos de control inválida: {}", linea)));
+        }
+        let numeros: Result<Vec<f64>, _> = campos[1..].iter().map(|c| c.parse::<f64>()).collect();
+        match numeros {
+            Ok(v) => puntos.push(PuntoDeControl { nombre: campos[0].to_string(), norte: v[0], este: v[1], elevacion: v[2] }),
+            Err(_) if i == 0 => {},
+            Err(e) => return Err(anyhow!(format!("Número inválido en el punto de control {}: {}", linea, e))),
+        }
+    }
+    Ok(puntos)
+}
+
+// Similaridad de 4 parámetros reducida a los baricentros:
+// local = origen_local + [[a, -b], [b, a]]·(grilla - origen_grilla), con
+// vectores (este, norte)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Similaridad {
+    pub a: f64,
+    pub b: f64,
+    pub origen_grilla: (f64, f64),
+    pub origen_local: (f64, f64),
+}
+
+impl Similaridad {
+    pub fn escala(&self) -> f64 {
+        self.a.hypot(self.b)
+    }
+
+    // Giro en grados, antihorario de la grilla a lo local
+    pub fn rotacion(&self) -> f64 {
+        self.b.atan2(self.a).to_degrees()
+    }
+
+    pub fn aplicar(&self, norte: f64, este: f64) -> (f64, f64) {
+        let (dn, de) = (norte - self.origen_grilla.0, este - self.origen_grilla.1);
+        (self.origen_local.0 + self.b * de + self.a * dn,
+         self.origen_local.1 + self.a * de - self.b * dn)
+    }
+}
+
+// Diferencia local - medida de la altura como un plano inclinado sobre la
+// grilla: constante + pendiente_norte·(N - N0) + pendiente_este·(E - E0)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PlanoInclinado {
+    pub constante: f64,
+    pub pendiente_norte: f64,
+    pub pendiente_este: f64,
+    pub origen: (f64, f64),
+}
+
+impl PlanoInclinado {
+    pub fn separacion(&self, norte: f64, este: f64) -> f64 {
+        self.constante
+            + self.pendiente_norte * (norte - self.origen.0)
+            + self.pendiente_este * (este - self.origen.1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Calibracion {
+    pub proyeccion: TransversaMercator,
+    pub horizontal: Similaridad,
+    pub vertical: PlanoInclinado,
+}
+
+// Residuo = local conocida - calibrada
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResiduoCalibracion {
+    pub nombre: String,
+    pub residuo_norte: f64,
+    pub residuo_este: f64,
+    pub residuo_elevacion: f64,
+}
+
+impl ResiduoCalibracion {
+    pub fn horizontal(&self) -> f64 {
+        self.residuo_norte.hypot(self.residuo_este)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CoordenadaLocal {
+    pub nombre: String,
+    pub norte: f64,
+    pub este: f64,
+    pub elevacion: f64,
+}
+
+impl Calibracion {
+    pub fn aplicar<G: Geodesicas>(&self, registro: &G) -> CoordenadaLocal {
+        let (latitud, longitud, altura) = registro.geodesicas();
+        let (n, e) = self.proyeccion.a_plano(latitud, longitud);
+        let (norte, este) = self.horizontal.aplicar(n, e);
+        CoordenadaLocal {
+            nombre: registro.nombre().to_string(),
+            norte,
+            este,
+            elevacion: altura + self.vertical.separacion(n, e),
+        }
+    }
+
+    pub fn residuos<G: Geodesicas>(&self, pares: &[(&PuntoDeControl, &G)]) -> Vec<ResiduoCalibracion> {
+        pares.iter()
+            .map(|(c, r)| {
+                let l = self.aplicar(*r);
+                ResiduoCalibracion {
+                    nombre: c.nombre.clone(),
+                    residuo_norte: c.norte - l.norte,
+                    residuo_este: c.este - l.este,
+                    residuo_elevacion: c.elevacion - l.elevacion,
+                }
+            })
+            .collect()
+    }
+}
+
+// Une cada punto de control con el registro medido del mismo nombre
+pub fn emparejar<'a, G: Geodesicas>(control: &'a [PuntoDeControl], medidos: &'a [G]) -> Vec<(&'a PuntoDeControl, &'a G)> {
+    control.iter()
+        .filter_map(|c| medidos.iter().find(|m| m.nombre() == c.nombre).map(|m| (c, m)))
+        .collect()
+}
+
+fn baricentro(puntos: &[(f64, f64)]) -> (f64, f64) {
+    let n = puntos.len() as f64;
+    (puntos.iter().map(|p| p.0).sum::<f64>() / n, puntos.iter().map(|p| p.1).sum::<f64>() / n)
+}
+
+// Con dos puntos la horizontal queda determinada; el plano necesita tres
+// y con menos se reduce a un corrimiento constante.
+pub fn calibrar<G: Geodesicas>(proyeccion: &TransversaMercator, pares: &[(&PuntoDeControl, &G)]) -> Result<Calibracion, anyhow::Error> {
+    if pares.len() < 2 {
+        return Err(anyhow!(format!("La calibración necesita al menos dos puntos de control, hay {}", pares.len())));
+    }
+    let grilla: Vec<(f64, f64)> = pares.iter()
+        .map(|(_, r)| { let (la, lo, _) = r.geodesicas(); proyeccion.a_plano(la, lo) })
+        .collect();
+    let local: Vec<(f64, f64)> = pares.iter().map(|(c, _)| (c.norte, c.este)).collect();
+    let origen_grilla = baricentro(&grilla);
+    let origen_local = baricentro(&local);
+
+    // Cerrado: a = Σ(de·dE + dn·dN) / Σ(de² + dn²), b = Σ(de·dN - dn·dE) / Σ(de² + dn²)
+    let (mut sa, mut sb, mut s2) = (0., 0., 0.);
+    for (g, l) in grilla.iter().zip(local.iter()) {
+        let (dn, de) = (g.0 - origen_grilla.0, g.1 - origen_grilla.1);
+        let (dnl, del) = (l.0 - origen_local.0, l.1 - origen_local.1);
+        sa += de * del + dn * dnl;
+        sb += de * dnl - dn * del;
+        s2 += de * de + dn * dn;
+    }
+    if s2 < 1e-6 {
+        return Err(anyhow!("Los puntos de control medidos coinciden"));
+    }
+    let horizontal = Similaridad { a: sa / s2, b: sb / s2, origen_grilla, origen_local };
+
+    let separaciones: Vec<f64> = pares.iter().map(|(c, r)| c.elevacion - r.geodesicas().2).collect();
+    let vertical = if pares.len() >= 3 {
+        let a = Matriz::de_filas(&grilla.iter()
+            .map(|g| vec![1., g.0 - origen_grilla.0, g.1 - origen_grilla.1])
+            .collect::<Vec<_>>());
+        match minimos_cuadrados(&a, &separaciones, None) {
+            Ok(ajuste) => PlanoInclinado {
+                constante: ajuste.incognitas[0],
+                pendiente_norte: ajuste.incognitas[1],
+                pendiente_este: ajuste.incognitas[2],
+                origen: origen_grilla,
+            },
+            // Puntos alineados: no hay plano, sólo la media
+            Err(_) => PlanoInclinado {
+                constante: separaciones.iter().sum::<f64>() / separaciones.len() as f64,
+                pendiente_norte: 0., pendiente_este: 0., origen: origen_grilla,
+            },
+        }
+    } else {
+        PlanoInclinado {
+            constante: separaciones.iter().sum::<f64>() / separaciones.len() as f64,
+            pendiente_norte: 0., pendiente_este: 0., origen: origen_grilla,
+        }
+    };
+
+    Ok(Calibracion { proyeccion: *proyeccion, horizontal, vertical })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proyeccion::GRS80;
+    use crate::record_parser_gps::{parse_gps_record, GPSRecord};
+    use std::io::Write;
+
+    fn proyeccion() -> TransversaMercator {
+        TransversaMercator { latitud_origen: -90., ..TransversaMercator::new(GRS80, -58.5, 1., 500_000., 0.) }
+    }
+
+    // Control local inventado con giro, escala, traslación y un plano
+    fn control(medidos: &[GPSRecord]) -> Vec<PuntoDeControl> {
+        let proy = proyeccion();
+        let (theta, k) = (0.3f64.to_radians(), 1.00002);
+        medidos.iter().map(|m| {
+            let (n, e) = proy.a_plano(m.latitude, m.longitude);
+            let (dn, de) = (n - 6_124_950., e - 521_380.);
+            PuntoDeControl {
+                nombre: m.occupy_point.clone(),
+                norte: 1000. + k * (theta.sin() * de + theta.cos() * dn),
+                este: 5000. + k * (theta.cos() * de - theta.sin() * dn),
+                elevacion: m.elevation + 17.5 + 1e-4 * dn - 2e-4 * de,
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn test_calibrar() {
+        let medidos: Vec<GPSRecord> = [
+            "GPS,PN1,LA-35.02153129,LN-58.26579176,EL1.628,--",
+            "GPS,PN2,LA-35.02255202,LN-58.26477676,EL22.072,--",
+            "GPS,PN3,LA-35.01954763,LN-58.26877623,EL5.244,--",
+            "GPS,PN4,LA-35.02454763,LN-58.26177623,EL3.101,--",
+        ].iter().map(|l| parse_gps_record(l).unwrap()).collect();
+        let control = control(&medidos);
+        let pares = emparejar(&control, &medidos);
+        assert_eq!(pares.len(), 4);
+
+        let cal = calibrar(&proyeccion(), &pares).unwrap();
+        assert!((cal.horizontal.escala() - 1.00002).abs() < 1e-9);
+        assert!((cal.horizontal.rotacion() - 0.3).abs() < 1e-7);
+        assert!((cal.vertical.pendiente_norte - 1e-4).abs() < 1e-9);
+        assert!((cal.vertical.pendiente_este + 2e-4).abs() < 1e-9);
+        for r in cal.residuos(&pares) {
+            assert!(r.horizontal() < 1e-6 && r.residuo_elevacion.abs() < 1e-6);
+        }
+
+        // Con un punto movido 5 cm aparecen residuos que suman cero
+        let mut movido = control.clone();
+        movido[0].norte += 0.05;
+        let pares = emparejar(&movido, &medidos);
+        let residuos = calibrar(&proyeccion(), &pares).unwrap().residuos(&pares);
+        assert!(residuos[0].residuo_norte > 0.02);
+        assert!(residuos.iter().map(|r| r.residuo_norte).sum::<f64>().abs() < 1e-6);
+
+        // Dos puntos: horizontal exacta, vertical con corrimiento medio
+        let pares = emparejar(&control[..2], &medidos);
+        let cal = calibrar(&proyeccion(), &pares).unwrap();
+        assert_eq!(cal.vertical.pendiente_norte, 0.);
+        assert!(cal.residuos(&pares).iter().all(|r| r.horizontal() < 1e-6));
+
+        assert!(calibrar(&proyeccion(), &pares[..1]).is_err());
+    }
+
+    #[test]
+    fn test_leer_puntos_de_control() {
+        let mut archivo = tempfile::NamedTempFile::new().unwrap();
+        write!(archivo, "nombre,norte,este,elevacion\nPF1,1000.000,5000.000,17.5\n").unwrap();
+        let p = leer_puntos_de_control(archivo.path()).unwrap();
+        assert_eq!(p, vec![PuntoDeControl { nombre: "PF1".to_string(), norte: 1000., este: 5000., elevacion: 17.5 }]);
+
+        // Un número mal escrito no hace desaparecer el punto
+        let mut archivo = tempfile::NamedTempFile::new().unwrap();
+        write!(archivo, "nombre,norte,este,elevacion\nPF1,1000.000,5000.000,17.5\nPF2,1000.0o0,5100.000,17.5\n").unwrap();
+        assert!(leer_puntos_de_control(archivo.path()).is_err());
+    }
+}
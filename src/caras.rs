@@ -0,0 +1,171 @@
+use crate::cogo::{dms_a_grados, grados_a_dms, normalizar};
+use crate::file_parser::Record;
+use crate::record_parser::{AngleOption, DistanceOption, TipoDeVisual, TraverseRecord, ZenithOption};
+use serde::Serialize;
+
+// Diferencia máxima entre directa e inversa, en segundos: 2c en el
+// horizontal y 2i en el vertical
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ToleranciaCaras {
+    pub horizontal: f64,
+    pub vertical: f64,
+}
+
+impl Default for ToleranciaCaras {
+    fn default() -> Self {
+        ToleranciaCaras { horizontal: 20., vertical: 20. }
+    }
+}
+
+// Una puntería en directa y otra en inversa al mismo punto desde la misma
+// estación. Ángulos en grados, errores en segundos.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ParDeCaras {
+    pub estacion: String,
+    pub punto: String,
+    pub atras: bool,
+    pub horizontal: f64,
+    pub cenit: Option<f64>,
+    // c = (Hd - Hi ± 180°) / 2
+    pub colimacion: f64,
+    // i = (Zd + Zi - 360°) / 2
+    pub indice: Option<f64>,
+    pub fuera_de_tolerancia: bool,
+    pub promedio: TraverseRecord,
+}
+
+fn es_directa(t: TipoDeVisual) -> bool {
+    matches!(t, TipoDeVisual::AtrasDirecta | TipoDeVisual::AdelanteDirecta)
+}
+
+fn inversa_de(t: TipoDeVisual) -> Option<TipoDeVisual> {
+    match t {
+        TipoDeVisual::AtrasDirecta => Some(TipoDeVisual::AtrasInversa),
+        TipoDeVisual::AdelanteDirecta => Some(TipoDeVisual::AdelanteInversa),
+        _ => None,
+    }
+}
+
+// Sólo se promedian lecturas de círculo (AR o AL). Un azimut o rumbo ya
+// viene reducido por la controladora.
+pub fn promediar_par(directa: &TraverseRecord, inversa: &TraverseRecord, tolerancia: &ToleranciaCaras) -> Option<ParDeCaras> {
+    let (hd, hi, angulo): (f64, f64, fn(f64) -> AngleOption) = match (directa.angle_option, inversa.angle_option) {
+        (AngleOption::AngleRight(d), AngleOption::AngleRight(i)) => (dms_a_grados(d), dms_a_grados(i), AngleOption::AngleRight),
+        (AngleOption::AngleLeft(d), AngleOption::AngleLeft(i)) => (dms_a_grados(d), dms_a_grados(i), AngleOption::AngleLeft),
+        _ => return None,
+    };
+    let colimacion = normalizar(hd - hi + 180.) / 2.;
+    let horizontal = (hd - colimacion).rem_euclid(360.);
+
+    let (cenit, indice, zenith_option) = match (directa.zenith_option, inversa.zenith_option) {
+        (ZenithOption::Zenith(d), ZenithOption::Zenith(i)) => {
+            let (zd, zi) = (dms_a_grados(d), dms_a_grados(i));
+            let indice = normalizar(zd + zi - 360.) / 2.;
+            (Some(zd - indice), Some(indice), ZenithOption::Zenith(grados_a_dms(zd - indice)))
+        },
+        (z, _) => (None, None, z),
+    };
+
+    let distance_option = match (directa.distance_option, inversa.distance_option) {
+        (DistanceOption::SlopeDistance(d), DistanceOption::SlopeDistance(i)) => DistanceOption::SlopeDistance((d + i) / 2.),
+        (DistanceOption::HorizontalDistance(d), DistanceOption::HorizontalDistance(i)) => DistanceOption::HorizontalDistance((d + i) / 2.),
+        (d, _) => d,
+    };
+
+    let fuera_de_tolerancia = (2. * colimacion * 3600.).abs() > tolerancia.horizontal
+        || indice.map(|i| (2. * i * 3600.).abs() > tolerancia.vertical).unwrap_or(false);
+
+    Some(ParDeCaras {
+        estacion: directa.occupy_point.clone(),
+        punto: directa.foresight_point.clone(),
+        atras: directa.tipo == TipoDeVisual::AtrasDirecta,
+        horizontal,
+        cenit,
+        colimacion: colimacion * 3600.,
+        indice: indice.map(|i| i * 3600.),
+        fuera_de_tolerancia,
+        promedio: TraverseRecord {
+            angle_option: angulo(grados_a_dms(horizontal)),
+            zenith_option,
+            distance_option,
+            ..directa.clone()
+        },
+    })
+}
+
+// Reemplaza cada par directa/inversa por una visual promediada, en el
+// lugar de la directa. La inversa que se empareja es la siguiente al mismo
+// punto dentro de la misma estación; lo que no se empareja queda igual.
+pub fn promediar_caras(registros: &[&Record], tolerancia: &ToleranciaCaras) -> (Vec<Record>, Vec<ParDeCaras>) {
+    let mut salida: Vec<Option<Record>> = registros.iter().map(|r| Some((*r).clone())).collect();
+    let mut pares = vec![];
+
+    for i in 0..registros.len() {
+        let directa = match registros[i] {
+            Record::SS(v) if es_directa(v.tipo) => v,
+            _ => continue,
+        };
+        let buscada = inversa_de(directa.tipo);
+        let inversa = registros.iter().enumerate().skip(i + 1)
+            .take_while(|(_, r)| !matches!(r, Record::OC(_)))
+            .find(|(j, r)| salida[*j].is_some() && matches!(r, Record::SS(v)
+                if Some(v.tipo) == buscada && v.foresight_point == directa.foresight_point));
+        if let Some((j, Record::SS(inversa))) = inversa {
+            if let Some(par) = promediar_par(directa, inversa, tolerancia) {
+                salida[i] = Some(Record::SS(par.promedio.clone()));
+                salida[j] = None;
+                pares.push(par);
+            }
+        }
+    }
+    (salida.into_iter().flatten().collect(), pares)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cogo::reducir;
+    use crate::file_parser::registros_de_lineas;
+
+    #[test]
+    fn test_promediar_caras() {
+        let registros = registros_de_lineas(vec![
+            "OC,OP1,N 0.000,E 0.000,EL10.000",
+            "BK,OP1,BP2,BS0.0000,BC0.0000",
+            "LS,HI1.500000,HR1.500000",
+            "BD,OP1,FP2,AR0.0000,ZE90.0000,SD50.000",
+            "FD,OP1,FP3,AR90.0000,ZE85.0000,SD100.000",
+            "BR,OP1,FP2,AR180.0010,ZE270.0000,SD50.000",
+            "FR,OP1,FP3,AR270.0015,ZE275.0010,SD100.002",
+            "FD,OP1,FP4,AR45.0000,ZE90.0000,SD10.000",
+        ]);
+        let (promediados, pares) = promediar_caras(&registros.iter().collect::<Vec<_>>(), &ToleranciaCaras::default());
+        assert_eq!(pares.len(), 2);
+        // Se van las dos inversas, la FD a 4 queda sola
+        assert_eq!(promediados.len(), registros.len() - 2);
+
+        let atras = &pares[0];
+        assert!(atras.atras);
+        assert!((atras.colimacion + 5.).abs() < 1e-6);
+        assert!((atras.horizontal * 3600. - 5.).abs() < 1e-6);
+        assert_eq!(atras.indice, Some(0.));
+
+        let adelante = &pares[1];
+        assert!((adelante.colimacion + 7.5).abs() < 1e-6);
+        assert!((adelante.indice.unwrap() - 5.).abs() < 1e-6);
+        assert!((adelante.cenit.unwrap() - (85. - 5. / 3600.)).abs() < 1e-9);
+        assert_eq!(adelante.promedio.distance_option, DistanceOption::SlopeDistance(100.001));
+        assert!(!adelante.fuera_de_tolerancia);
+
+        let estricta = ToleranciaCaras { horizontal: 12., vertical: 12. };
+        let (_, pares) = promediar_caras(&registros.iter().collect::<Vec<_>>(), &estricta);
+        assert!(!pares[0].fuera_de_tolerancia && pares[1].fuera_de_tolerancia);
+
+        // La puntería atrás promediada fija la lectura del círculo: el
+        // punto 3 queda a 90°00'07.5" - 5" del norte
+        let r = reducir(&promediados.iter().collect::<Vec<_>>());
+        assert_eq!(r.puntos.len(), 2);
+        assert!((r.estaciones[0].circulo_atras * 3600. - 5.).abs() < 1e-3);
+        assert!((r.puntos[0].azimut - (90. + 2.5 / 3600.)).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,639 @@
+use crate::correcciones::Correcciones;
+use crate::file_parser::Record;
+use crate::proyeccion::TransversaMercator;
+use crate::record_parser::{AngleOption, DistanceOption, Observacion, TipoDeVisual, TraverseRecord, ZenithOption};
+use crate::record_parser_gps::GPSRecord;
+use anyhow::anyhow;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// Los ángulos del RW5 vienen como DDD.MMSS
+pub fn dms_a_grados(v: f64) -> f64 {
+    let a = v.abs();
+    let grados = a.trunc();
+    let minutos = ((a - grados) * 100. + 1e-9).trunc();
+    let segundos = ((a - grados) * 100. - minutos) * 100.;
+    (grados + minutos / 60. + segundos / 3600.).copysign(v)
+}
+
+pub fn grados_a_dms(v: f64) -> f64 {
+    let a = v.abs();
+    let total = (a * 3600. * 1e4).round() / 1e4;
+    let grados = (total / 3600.).trunc();
+    let minutos = ((total - grados * 3600.) / 60.).trunc();
+    let segundos = total - grados * 3600. - minutos * 60.;
+    (grados + minutos / 100. + segundos / 10_000.).copysign(v)
+}
+
+// Lleva un ángulo a (-180, 180]
+pub fn normalizar(a: f64) -> f64 {
+    let a = a.rem_euclid(360.);
+    if a > 180. { a - 360. } else { a }
+}
+
+// Azimut en grados de `desde` a `hasta`, ambos como (norte, este)
+pub fn azimut(desde: (f64, f64), hasta: (f64, f64)) -> f64 {
+    (hasta.1 - desde.1).atan2(hasta.0 - desde.0).to_degrees().rem_euclid(360.)
+}
+
+// Rumbo con el cuadrante en el primer dígito: 1 NE, 2 SE, 3 SO, 4 NO,
+// seguido de DD.MMSS. 145.3000 es N 45°30' E.
+pub fn rumbo_a_azimut(v: f64) -> Result<f64, anyhow::Error> {
+    let cuadrante = (v / 100.).trunc() as u8;
+    let angulo = dms_a_grados(v - cuadrante as f64 * 100.);
+    if angulo > 90. {
+        return Err(anyhow!(format!("Rumbo inválido: {}", v)));
+    }
+    match cuadrante {
+        1 => Ok(angulo),
+        2 => Ok(180. - angulo),
+        3 => Ok(180. + angulo),
+        4 => Ok(360. - angulo),
+        _ => Err(anyhow!(format!("Cuadrante de rumbo inválido: {}", v))),
+    }
+}
+
+// Estación ocupada y su orientación. Azimut y círculo atrás en grados.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Estacion {
+    pub nombre: String,
+    pub norte: f64,
+    pub este: f64,
+    pub elevacion: f64,
+    pub altura_instrumento: f64,
+    pub altura_prisma: f64,
+    pub atras: Option<String>,
+    pub azimut_atras: Option<f64>,
+    pub circulo_atras: f64,
+}
+
+impl Estacion {
+    // Azimut de la visual según la opción de ángulo. AR y AL son lecturas
+    // del mismo círculo, que se refieren a la lectura atrás (BC, en sentido
+    // horario): AL es 360 - AR. Las deflexiones se miden desde la
+    // prolongación de la visual atrás.
+    pub fn azimut(&self, angulo: AngleOption) -> Result<f64, anyhow::Error> {
+        let atras = || self.azimut_atras
+            .ok_or_else(|| anyhow!(format!("La estación {} no está orientada", self.nombre)));
+        let az = match angulo {
+            AngleOption::Azimuth(a) => dms_a_grados(a),
+            AngleOption::Bearing(r) => rumbo_a_azimut(r)?,
+            AngleOption::AngleRight(a) => atras()? + dms_a_grados(a) - self.circulo_atras,
+            AngleOption::AngleLeft(a) => atras()? - dms_a_grados(a) - self.circulo_atras,
+            AngleOption::DeflectionRight(a) => atras()? + 180. + dms_a_grados(a),
+            AngleOption::DeflectionLeft(a) => atras()? + 180. - dms_a_grados(a),
+        };
+        Ok(az.rem_euclid(360.))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PuntoTopografico {
+    pub nombre: String,
+    pub norte: f64,
+    pub este: f64,
+    pub elevacion: f64,
+    pub estacion: String,
+    // Índice de la estación en ReduccionTopografica::estaciones
+    pub puesta: usize,
+    pub tipo: TipoDeVisual,
+    pub azimut: f64,
+    pub distancia_horizontal: f64,
+    // De terreno a terreno
+    pub desnivel: f64,
+    pub nota: String,
+    // Observación de la visual antes del OF, para auditar
+    pub original: Option<Observacion>,
+}
+
+impl PuntoTopografico {
+    // Al mismo modelo que los puntos GPS, con la proyección del trabajo
+    pub fn a_gps(&self, proyeccion: &TransversaMercator) -> GPSRecord {
+        let (latitude, longitude) = proyeccion.a_geodesicas(self.norte, self.este);
+        GPSRecord {
+            occupy_point: self.nombre.clone(),
+            latitude,
+            longitude,
+            elevation: self.elevacion,
+            elevation_alt: None,
+            note: self.nota.clone(),
+            north: Some(self.norte),
+            east: Some(self.este),
+            start_time: None,
+            end_time: None,
+        }
+    }
+}
+
+// Una visual en segunda posición (cenit mayor a 180°) mira 180° más allá
+// de su lectura horizontal
+pub fn giro_de_posicion(cenit: ZenithOption) -> f64 {
+    let z = match cenit {
+        ZenithOption::Zenith(z) => dms_a_grados(z),
+        ZenithOption::VerticalAngle(v) => 90. - dms_a_grados(v),
+        ZenithOption::ChangeElevation(_) => return 0.,
+    };
+    if z.rem_euclid(360.) > 180. { 180. } else { 0. }
+}
+
+// Distancia horizontal y desnivel de terreno a terreno de una visual. La
+// constante del EDM va a la distancia inclinada, la curvatura y refracción
+// a los desniveles trigonométricos y el factor a la horizontal.
+pub fn reducir_distancia(estacion: &Estacion, cenit: ZenithOption, distancia: DistanceOption,
+                         correcciones: &Correcciones) -> (f64, f64) {
+    let alturas = estacion.altura_instrumento - estacion.altura_prisma;
+    let distancia = match distancia {
+        DistanceOption::SlopeDistance(sd) => DistanceOption::SlopeDistance(sd + correcciones.constante_edm),
+        d => d,
+    };
+    let trigonometrico = |cenit: f64| {
+        // En segunda posición la misma visual tiene cenit 360° - z
+        let cenit = cenit.rem_euclid(360.);
+        let z = if cenit > 180. { 360. - cenit } else { cenit }.to_radians();
+        let (hd, dh) = match distancia {
+            DistanceOption::SlopeDistance(sd) => (sd * z.sin(), sd * z.cos() + alturas),
+            DistanceOption::HorizontalDistance(hd) => (hd, hd * z.cos() / z.sin() + alturas),
+        };
+        (hd, dh + correcciones.curvatura_y_refraccion(hd))
+    };
+    let (hd, desnivel) = match cenit {
+        ZenithOption::ChangeElevation(ce) => match distancia {
+            DistanceOption::SlopeDistance(sd) => ((sd * sd - ce * ce).max(0.).sqrt(), ce),
+            DistanceOption::HorizontalDistance(hd) => (hd, ce),
+        },
+        ZenithOption::Zenith(z) => trigonometrico(dms_a_grados(z)),
+        ZenithOption::VerticalAngle(v) => trigonometrico(90. - dms_a_grados(v)),
+    };
+    let factor = correcciones.factor_de_distancia(estacion.norte, estacion.este, estacion.elevacion + desnivel / 2.);
+    (hd * factor, desnivel)
+}
+
+pub fn reducir_visual(estacion: &Estacion, visual: &TraverseRecord, correcciones: &Correcciones) -> Result<PuntoTopografico, anyhow::Error> {
+    if visual.occupy_point != estacion.nombre {
+        return Err(anyhow!(format!("La visual a {} es desde {} y la estación es {}",
+                                   visual.foresight_point, visual.occupy_point, estacion.nombre)));
+    }
+    let azimut = (estacion.azimut(visual.angle_option)? + giro_de_posicion(visual.zenith_option)).rem_euclid(360.);
+    let (distancia_horizontal, desnivel) = reducir_distancia(estacion, visual.zenith_option, visual.distance_option, correcciones);
+    let (s, c) = azimut.to_radians().sin_cos();
+    Ok(PuntoTopografico {
+        nombre: visual.foresight_point.clone(),
+        norte: estacion.norte + distancia_horizontal * c,
+        este: estacion.este + distancia_horizontal * s,
+        elevacion: estacion.elevacion + desnivel,
+        estacion: estacion.nombre.clone(),
+        puesta: 0,
+        tipo: visual.tipo,
+        azimut,
+        distancia_horizontal,
+        desnivel,
+        nota: visual.note.clone(),
+        original: visual.original,
+    })
+}
+
+// Control de la orientación de una puesta. El azimut y la distancia atrás
+// salen de las coordenadas guardadas; los residuos angulares van en
+// segundos y son observado menos calculado.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChequeoAtras {
+    pub puesta: usize,
+    pub estacion: String,
+    pub atras: String,
+    pub azimut_atras: f64,
+    pub azimut_calculado: Option<f64>,
+    pub distancia_calculada: Option<f64>,
+    // BS contra el inverso
+    pub residuo_azimut: Option<f64>,
+    // Visual de chequeo al punto atrás, con la lectura del BK
+    pub azimut_medido: Option<f64>,
+    pub distancia_medida: Option<f64>,
+    pub desnivel_medido: Option<f64>,
+    pub residuo_angular: Option<f64>,
+    pub residuo_distancia: Option<f64>,
+}
+
+impl ChequeoAtras {
+    fn chequear(&mut self, azimut: f64, distancia: f64, desnivel: f64) {
+        self.azimut_medido = Some(azimut);
+        self.distancia_medida = Some(distancia);
+        self.desnivel_medido = Some(desnivel);
+        self.residuo_angular = self.azimut_calculado.map(|c| normalizar(azimut - c) * 3600.);
+        self.residuo_distancia = self.distancia_calculada.map(|c| distancia - c);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReduccionTopografica {
+    pub estaciones: Vec<Estacion>,
+    pub puntos: Vec<PuntoTopografico>,
+    // Coordenadas de OC, SP y puntos calculados, por nombre
+    pub conocidos: BTreeMap<String, (f64, f64, f64)>,
+    // Sólo los SP: coordenadas que no salen de esta medición
+    pub control: BTreeMap<String, (f64, f64, f64)>,
+    pub chequeos: Vec<ChequeoAtras>,
+    pub errores: Vec<String>,
+    // Las correcciones vigentes en el OC de cada estación, en el orden de
+    // `estaciones`: escala, proyección y unidades de sus distancias
+    pub correcciones: Vec<Correcciones>,
+}
+
+impl ReduccionTopografica {
+    pub fn a_gps(&self, proyeccion: &TransversaMercator) -> Vec<GPSRecord> {
+        self.puntos.iter().map(|p| p.a_gps(proyeccion)).collect()
+    }
+}
+
+// Recorre los registros llevando la estación vigente (OC), su orientación
+// (BK), las alturas (LS) y las correcciones del MO y calcula cada visual.
+// Las visuales que no se pueden calcular quedan en `errores`.
+pub fn reducir(registros: &[&Record]) -> ReduccionTopografica {
+    reducir_con(registros, &Correcciones::default())
+}
+
+// `correcciones` vale hasta el primer MO; desde ahí cada MO cambia
+// curvatura, constante, escala y unidades
+pub fn reducir_con(registros: &[&Record], correcciones: &Correcciones) -> ReduccionTopografica {
+    let mut correcciones = *correcciones;
+    let mut r = ReduccionTopografica::default();
+    let mut estacion: Option<Estacion> = None;
+    let (mut hi, mut hr) = (0., 0.);
+
+    for registro in registros {
+        // La primera visual al punto atrás desde la puesta es el chequeo,
+        // salvo que sea en segunda posición
+        if let (Record::SS(visual), Some(e)) = (registro, estacion.as_ref()) {
+            let puesta = r.estaciones.len();
+            if let Some(c) = r.chequeos.last_mut().filter(|c| c.puesta == puesta && visual.tipo != TipoDeVisual::AtrasInversa
+                    && c.atras == visual.foresight_point && c.azimut_medido.is_none()) {
+                if let Ok(azimut) = e.azimut(visual.angle_option) {
+                    let (distancia, desnivel) = reducir_distancia(e, visual.zenith_option, visual.distance_option, &correcciones);
+                    c.chequear((azimut + giro_de_posicion(visual.zenith_option)).rem_euclid(360.), distancia, desnivel);
+                }
+            }
+        }
+        match registro {
+            Record::MO(mo) => correcciones = correcciones.con_modo(mo),
+            Record::OC(oc) => {
+                if let Some(e) = estacion.take() {
+                    r.estaciones.push(e);
+                }
+                r.conocidos.insert(oc.op.clone(), (oc.n, oc.e, oc.el));
+                r.correcciones.push(correcciones);
+                estacion = Some(Estacion {
+                    nombre: oc.op.clone(), norte: oc.n, este: oc.e, elevacion: oc.el,
+                    altura_instrumento: hi, altura_prisma: hr,
+                    atras: None, azimut_atras: None, circulo_atras: 0.,
+                });
+            },
+            Record::BK(bk) => match estacion.as_mut() {
+                Some(e) if e.nombre == bk.op => {
+                    e.atras = Some(bk.bp.clone());
+                    e.azimut_atras = Some(dms_a_grados(bk.bs));
+                    e.circulo_atras = dms_a_grados(bk.bc);
+                    // Un SP manda sobre un punto calculado con el mismo nombre
+                    let inverso = r.control.get(&bk.bp).or_else(|| r.conocidos.get(&bk.bp)).map(|(n, es, _)| {
+                        let (dn, de) = (n - e.norte, es - e.este);
+                        (de.atan2(dn).to_degrees().rem_euclid(360.), dn.hypot(de))
+                    });
+                    let azimut_atras = dms_a_grados(bk.bs);
+                    r.chequeos.push(ChequeoAtras {
+                        puesta: r.estaciones.len(),
+                        estacion: e.nombre.clone(),
+                        atras: bk.bp.clone(),
+                        azimut_atras,
+                        azimut_calculado: inverso.map(|i| i.0),
+                        distancia_calculada: inverso.map(|i| i.1),
+                        residuo_azimut: inverso.map(|i| normalizar(azimut_atras - i.0) * 3600.),
+                        azimut_medido: None,
+                        distancia_medida: None,
+                        desnivel_medido: None,
+                        residuo_angular: None,
+                        residuo_distancia: None,
+                    });
+                },
+                _ => r.errores.push(format!("Atrás desde {} sin estación ocupada", bk.op)),
+            },
+            Record::HI(ls) => {
+                hi = ls.hi;
+                hr = ls.hr.unwrap_or(hr);
+                if let Some(e) = estacion.as_mut() {
+                    e.altura_instrumento = hi;
+                    e.altura_prisma = hr;
+                }
+            },
+            // Cambio de altura de prisma sin HI
+            Record::LS(ls) => if let Some(e) = estacion.as_mut() {
+                hr = ls.height_rod;
+                e.altura_prisma = hr;
+            },
+            Record::SP(sp) => {
+                r.conocidos.insert(sp.pn.clone(), (sp.n, sp.e, sp.el));
+                r.control.insert(sp.pn.clone(), (sp.n, sp.e, sp.el));
+            },
+            // Puntería directa (o promediada) al punto atrás: su lectura es
+            // la del círculo en la orientación, no un punto nuevo. AL es
+            // 360 - AR.
+            Record::SS(visual) if visual.tipo == TipoDeVisual::AtrasDirecta
+                && estacion.as_ref().and_then(|e| e.atras.as_ref()) == Some(&visual.foresight_point) => {
+                match (estacion.as_mut(), visual.angle_option) {
+                    (Some(e), AngleOption::AngleRight(a)) => e.circulo_atras = dms_a_grados(a),
+                    (Some(e), AngleOption::AngleLeft(a)) => e.circulo_atras = (360. - dms_a_grados(a)).rem_euclid(360.),
+                    _ => r.errores.push(format!("Puntería directa a {} sin lectura AR ni AL", visual.foresight_point)),
+                }
+            },
+            // La inversa sin su directa: el círculo atrás es la lectura menos
+            // 180°
+            Record::SS(visual) if visual.tipo == TipoDeVisual::AtrasInversa
+                && estacion.as_ref().and_then(|e| e.atras.as_ref()) == Some(&visual.foresight_point) => {
+                match (estacion.as_mut(), visual.angle_option) {
+                    (Some(e), AngleOption::AngleRight(a)) => e.circulo_atras = (dms_a_grados(a) - 180.).rem_euclid(360.),
+                    (Some(e), AngleOption::AngleLeft(a)) => e.circulo_atras = (180. - dms_a_grados(a)).rem_euclid(360.),
+                    _ => r.errores.push(format!("Puntería inversa a {} sin lectura AR ni AL", visual.foresight_point)),
+                }
+            },
+            Record::SS(visual) => match estacion.as_ref().map(|e| reducir_visual(e, visual, &correcciones)) {
+                Some(Ok(mut p)) => {
+                    p.puesta = r.estaciones.len();
+                    r.conocidos.insert(p.nombre.clone(), (p.norte, p.este, p.elevacion));
+                    r.puntos.push(p);
+                },
+                Some(Err(e)) => r.errores.push(e.to_string()),
+                None => r.errores.push(format!("Visual a {} sin estación ocupada", visual.foresight_point)),
+            },
+            // Un OF que sigue suelto no encontró visual a la que aplicarse
+            Record::OF(of) => r.errores.push(format!("Excéntrica AR{} SD{} sin visual que la siga", of.ar, of.sd)),
+            _ => {},
+        }
+    }
+    if let Some(e) = estacion {
+        r.estaciones.push(e);
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file_parser::registros_de_lineas;
+    use crate::proyeccion::GRS80;
+
+    fn reducir_lineas(lineas: Vec<&str>) -> ReduccionTopografica {
+        let registros = registros_de_lineas(lineas);
+        reducir(&registros.iter().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_dms() {
+        assert!((dms_a_grados(90.3000) - 90.5).abs() < 1e-12);
+        assert!((dms_a_grados(0.0044) - 44. / 3600.).abs() < 1e-12);
+        assert!((dms_a_grados(-12.3456) + 12. + 34. / 60. + 56. / 3600.).abs() < 1e-12);
+        assert!((grados_a_dms(dms_a_grados(359.5959)) - 359.5959).abs() < 1e-9);
+
+        for (rumbo, azimut) in [(145.3, 45.5), (210., 170.), (330., 210.), (400.3, 359.5)] {
+            assert!((rumbo_a_azimut(rumbo).unwrap() - azimut).abs() < 1e-9);
+        }
+        assert!(rumbo_a_azimut(545.).is_err());
+    }
+
+    #[test]
+    fn test_reducir() {
+        let r = reducir_lineas(vec![
+            "JB,NMSAMPLE,DT06-27-2003,TM14:21:53",
+            "MO,AD0,UN0,SF1.00000000,EC1,EO0.0,AU0",
+            "--SP,PN1,N 5000.000,E 5000.000,EL100.000,--PP",
+            "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+            "BK,OP1,BP2,BS0.0000,BC0.0000",
+            "LS,HI1.500000,HR1.800000",
+            "SS,OP1,FP3,AR90.0000,ZE90.0000,SD100.000000,--POSTE",
+            "SS,OP1,FP4,AZ180.0000,VA10.0000,HD50.000000",
+            "SS,OP1,FP5,AL45.0000,CE-2.5,SD10.000000",
+            "LS,HR2.000000",
+            "TR,OP1,FP6,DR30.0000,ZE90.0000,HD20.000000",
+            "SS,OP1,FP7,BR230.0000,ZE80.0000,SD10.000000",
+            "OC,OP6,N 4982.67949,E 4990.00000,EL99.500",
+            "SS,OP6,FP8,AR10.0000,ZE90.0000,HD1.000000",
+        ]);
+        assert_eq!(r.puntos.len(), 5);
+        assert_eq!(r.estaciones.len(), 2);
+        // La última visual no tiene orientación: la estación 6 no tiene BK
+        assert_eq!(r.errores.len(), 1);
+        // El MO pide curvatura y refracción, en pies
+        let cr = |hd: f64| Correcciones { curvatura: true, metros_por_unidad: 0.3048, ..Correcciones::default() }
+            .curvatura_y_refraccion(hd);
+
+        let p3 = &r.puntos[0];
+        assert!((p3.norte - 5000.).abs() < 1e-9 && (p3.este - 5100.).abs() < 1e-9);
+        assert!((p3.elevacion - (100. + 1.5 - 1.8 + cr(100.))).abs() < 1e-9);
+        assert_eq!(p3.nota, "POSTE");
+
+        // 10° sobre el horizonte a 50 m horizontales
+        let p4 = &r.puntos[1];
+        assert!((p4.norte - 4950.).abs() < 1e-9);
+        assert!((p4.desnivel - (50. * 10f64.to_radians().tan() - 0.3 + cr(50.))).abs() < 1e-9);
+
+        let p5 = &r.puntos[2];
+        assert!((p5.azimut - 315.).abs() < 1e-9);
+        assert!((p5.distancia_horizontal - (100f64 - 6.25).sqrt()).abs() < 1e-9);
+        assert!((p5.elevacion - 97.5).abs() < 1e-9);
+
+        // Deflexión de 30° desde la prolongación de la visual atrás al norte
+        let p6 = &r.puntos[3];
+        assert_eq!(p6.tipo, TipoDeVisual::Poligonal);
+        assert!((p6.azimut - 210.).abs() < 1e-9);
+        assert!((p6.norte - (5000. - 20. * 30f64.to_radians().cos())).abs() < 1e-9);
+        assert!((p6.elevacion - 99.5 - cr(20.)).abs() < 1e-9);
+
+        let p7 = &r.puntos[4];
+        assert!((p7.azimut - 150.).abs() < 1e-9);
+        let hd7 = 10. * 80f64.to_radians().sin();
+        assert!((p7.desnivel - (10. * 80f64.to_radians().cos() - 0.5 + cr(hd7))).abs() < 1e-9);
+
+        // 1 y 6 son OC y también SP o visual
+        assert_eq!(r.conocidos.len(), 6);
+    }
+
+    #[test]
+    fn test_mo_a_mitad_del_trabajo() {
+        // Cambio de prisma: la constante nueva vale desde su MO
+        let r = reducir_lineas(vec![
+            "MO,AD0,UN1,SF1.00000000,EC0,EO0.0,AU0",
+            "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+            "BK,OP1,BP2,BS0.0000,BC0.0000",
+            "SS,OP1,FP3,AR90.0000,ZE90.0000,SD100.000000",
+            "MO,AD0,UN1,SF1.00000000,EC0,EO0.030,AU0",
+            "SS,OP1,FP4,AR90.0000,ZE90.0000,SD100.000000",
+        ]);
+        assert!((r.puntos[0].distancia_horizontal - 100.).abs() < 1e-9);
+        assert!((r.puntos[1].distancia_horizontal - 100.03).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_segunda_posicion() {
+        let r = reducir_lineas(vec![
+            "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+            "BK,OP1,BP2,BS0.0000,BC0.0000",
+            "FR,OP1,FP3,AR270.0000,ZE265.0000,SD10.000000",
+            "FR,OP1,FP4,AR180.0000,ZE265.0000,HD10.000000",
+        ]);
+        // Es la visual con AR90 y ZE95
+        let p3 = &r.puntos[0];
+        let hd = 10. * 95f64.to_radians().sin();
+        assert!((p3.distancia_horizontal - hd).abs() < 1e-9);
+        assert!((p3.azimut - 90.).abs() < 1e-9);
+        assert!((p3.este - (5000. + hd)).abs() < 1e-9 && (p3.norte - 5000.).abs() < 1e-9);
+        assert!((p3.desnivel - 10. * 95f64.to_radians().cos()).abs() < 1e-9);
+        // Con distancia horizontal el desnivel baja igual
+        let p4 = &r.puntos[1];
+        assert!((p4.distancia_horizontal - 10.).abs() < 1e-9);
+        assert!(p4.azimut.abs() < 1e-9);
+        assert!((p4.desnivel - 10. / 95f64.to_radians().tan()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_circulo_atras() {
+        // Con el círculo en 10° atrás, AR100 y AL260 son la misma dirección
+        let r = reducir_lineas(vec![
+            "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+            "BK,OP1,BP2,BS90.0000,BC10.0000",
+            "SS,OP1,FP3,AR100.0000,ZE90.0000,HD10.000000",
+            "SS,OP1,FP4,AL260.0000,ZE90.0000,HD10.000000",
+        ]);
+        assert!((r.puntos[0].azimut - 180.).abs() < 1e-9);
+        assert!((r.puntos[1].azimut - 180.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_atras_inversa_sola() {
+        let r = reducir_lineas(vec![
+            "--SP,PN2,N 5100.000,E 5000.000,EL100.000,--PP",
+            "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+            "BK,OP1,BP2,BS0.0000,BC0.0000",
+            "BR,OP1,FP2,AR180.0010,ZE270.0000,SD100.000000",
+            "SS,OP1,FP3,AR90.0010,ZE90.0000,HD10.000000",
+        ]);
+        // No es un punto nuevo ni pisa al 2
+        assert_eq!(r.puntos.len(), 1);
+        assert_eq!(r.conocidos["2"], (5100., 5000., 100.));
+        assert!((r.estaciones[0].circulo_atras * 3600. - 10.).abs() < 1e-6);
+        assert!((r.puntos[0].azimut - 90.).abs() < 1e-9);
+        assert_eq!(r.chequeos[0].azimut_medido, None);
+    }
+
+    #[test]
+    fn test_atras_con_al() {
+        // AL359°59'50" y AL179°59'50" son el círculo en 10"
+        for atras in ["BD,OP1,FP2,AL359.5950,ZE90.0000,SD100.000000", "BR,OP1,FP2,AL179.5950,ZE270.0000,SD100.000000"].iter() {
+            let r = reducir_lineas(vec![
+                "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+                "BK,OP1,BP2,BS0.0000,BC0.0000",
+                atras,
+                "SS,OP1,FP3,AR90.0010,ZE90.0000,HD10.000000",
+            ]);
+            assert!(r.errores.is_empty());
+            assert_eq!(r.puntos.len(), 1);
+            assert!((r.estaciones[0].circulo_atras * 3600. - 10.).abs() < 1e-6);
+            assert!((r.puntos[0].azimut - 90.).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_a_gps() {
+        let proy = TransversaMercator { latitud_origen: -90., ..TransversaMercator::new(GRS80, -58.5, 1., 500_000., 0.) };
+        let (n, e) = proy.a_plano(-35.02153129, -58.26579176);
+        let estacion = Estacion {
+            nombre: "1".to_string(), norte: n, este: e, elevacion: 10.,
+            altura_instrumento: 1.5, altura_prisma: 1.5,
+            atras: None, azimut_atras: None, circulo_atras: 0.,
+        };
+        let visual = crate::record_parser::parse_traverse_record("SS,OP1,FP9,AZ0.0000,ZE90.0000,SD30.82").unwrap();
+        let gps = reducir_visual(&estacion, &visual, &Correcciones::default()).unwrap().a_gps(&proy);
+        assert_eq!(gps.occupy_point, "9");
+        assert_eq!(gps.north, Some(n + 30.82));
+        // Unos 30.8 m por segundo de latitud, algo menos por la convergencia
+        assert!((gps.latitude - (-35.02153129 + 1. / 3600.)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_chequeo_atras() {
+        let r = reducir_lineas(vec![
+            "--SP,PN2,N 5100.000,E 5100.000,EL100.000,--PP",
+            "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+            "BK,OP1,BP2,BS45.0010,BC0.0000",
+            "LS,HI1.500000,HR1.500000",
+            "BD,OP1,FP2,AR0.0005,ZE90.0000,SD141.400000",
+            "SS,OP1,FP3,AR90.0000,ZE90.0000,SD10.000000",
+            "OC,OP3,N 4990.00000,E 5000.00000,EL100.000",
+            "BK,OP3,BP9,BS0.0000,BC0.0000",
+        ]);
+        assert_eq!(r.chequeos.len(), 2);
+        let c = &r.chequeos[0];
+        assert!((c.azimut_calculado.unwrap() - 45.).abs() < 1e-9);
+        assert!((c.distancia_calculada.unwrap() - 100. * 2f64.sqrt()).abs() < 1e-9);
+        assert!((c.residuo_azimut.unwrap() - 10.).abs() < 1e-6);
+        // 10" del BS más 5" de lectura contra el BC
+        assert!((c.residuo_angular.unwrap() - 15.).abs() < 1e-6);
+        assert!((c.residuo_distancia.unwrap() - (141.4 - 100. * 2f64.sqrt())).abs() < 1e-9);
+        // El punto atrás no se recalcula
+        assert_eq!(r.puntos.len(), 1);
+
+        // Sin coordenadas del punto atrás no hay contra qué comparar
+        let c = &r.chequeos[1];
+        assert_eq!((c.puesta, c.azimut_calculado, c.residuo_azimut), (1, None, None));
+
+        let mut csv = vec![];
+        crate::exportar::chequeos_atras_a_csv(&r.chequeos, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().nth(1).unwrap(), "0,1,2,45.002778,45.000000,10.0,45.004167,15.0,141.4214,141.4000,-0.0214");
+        assert_eq!(csv.lines().nth(2).unwrap(), "1,3,9,0.000000,,,,,,,");
+    }
+
+    #[test]
+    fn test_chequeo_en_segunda_posicion() {
+        let r = reducir_lineas(vec![
+            "--SP,PN2,N 5100.000,E 5000.000,EL100.000,--PP",
+            "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+            "BK,OP1,BP2,BS0.0000,BC0.0000",
+            "LS,HI1.500000,HR1.500000",
+            "SS,OP1,FP2,AR180.0000,ZE270.0000,SD100.000000",
+        ]);
+        let c = &r.chequeos[0];
+        assert!(c.azimut_medido.unwrap().abs() < 1e-9);
+        assert!(c.residuo_angular.unwrap().abs() < 1e-6);
+        assert!((c.distancia_medida.unwrap() - 100.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_reducir_con_of() {
+        let r = reducir_lineas(vec![
+            "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+            "BK,OP1,BP2,BS0.0000,BC0.0000",
+            "LS,HI1.500000,HR1.500000",
+            "OF,AR90.0000,ZE90.0000,SD10.500000",
+            "SS,OP1,FP3,AR89.0000,ZE90.0000,SD10.000000,--ARBOL",
+            "SS,OP1,FP4,AR180.0000,ZE90.0000,SD10.000000",
+        ]);
+        assert_eq!(r.puntos.len(), 2);
+        // El OF da la distancia a la visual que le sigue, que conserva su
+        // ángulo, y no toca a las demás
+        let p3 = &r.puntos[0];
+        let az = 89f64.to_radians();
+        assert!((p3.este - (5000. + 10.5 * az.sin())).abs() < 1e-9);
+        assert!((p3.norte - (5000. + 10.5 * az.cos())).abs() < 1e-9);
+        assert_eq!(p3.original.unwrap().angle_option, AngleOption::AngleRight(89.));
+        assert!(r.puntos[1].original.is_none());
+    }
+
+    #[test]
+    fn test_of_suelto() {
+        let r = reducir_lineas(vec![
+            "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+            "BK,OP1,BP2,BS0.0000,BC0.0000",
+            "OF,AR90.0000,ZE90.0000,SD10.500000",
+            "--SP,PN5,N 5001.0000,E 5001.0000,EL100.0000,--PP",
+        ]);
+        assert!(r.puntos.is_empty());
+        assert_eq!(r.errores.len(), 1);
+        assert!(r.errores[0].starts_with("Excéntrica"));
+    }
+}
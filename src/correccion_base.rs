@@ -0,0 +1,272 @@
+use crate::geodesia::{a_geocentricas, a_geodesicas, a_enu, rotar_a_enu};
+use crate::proyeccion::{TransversaMercator, GRS80};
+use crate::record_parser_gps::{parse_bp_record, parse_gps_record, parse_gs_record};
+use anyhow::anyhow;
+use serde::Serialize;
+use std::{fs, path::Path};
+
+// Posición postprocesada de una base. Se aplica a las sesiones de la base
+// con ese nombre cuya posición original cae a menos de la tolerancia.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CorreccionBase {
+    pub nombre: String,
+    pub latitud: f64,
+    pub longitud: f64,
+    pub elevacion: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Desplazamiento {
+    // Línea del archivo original, contando desde 1
+    pub linea: usize,
+    pub nombre: String,
+    pub base: String,
+    pub original: (f64, f64, f64),
+    pub corregido: (f64, f64, f64),
+    pub grilla_original: Option<(f64, f64)>,
+    pub grilla_corregida: Option<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ResultadoCorreccion {
+    pub lineas: Vec<String>,
+    pub desplazamientos: Vec<Desplazamiento>,
+}
+
+// "nombre,latitud,longitud,elevacion" por línea; se saltean las que no
+// tienen números, como el encabezado.
+pub fn leer_coordenadas(archivo: &Path) -> Result<Vec<CorreccionBase>, anyhow::Error> {
+    let contenido = fs::read_to_string(archivo)?;
+    let mut correcciones = vec![];
+    for linea in contenido.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let campos: Vec<&str> = linea.split(',').map(|c| c.trim()).collect();
+        if campos.len() != 4 {
+            return Err(anyhow!(format!("Línea de coordenadas inválida: {}", linea)));
+        }
+        let numeros: Result<Vec<f64>, _> = campos[1..].iter().map(|c| c.parse::<f64>()).collect();
+        if let Ok(v) = numeros {
+            correcciones.push(CorreccionBase { nombre: campos[0].to_string(), latitud: v[0], longitud: v[1], elevacion: v[2] });
+        }
+    }
+    Ok(correcciones)
+}
+
+struct Sesion {
+    base: String,
+    delta: (f64, f64, f64),
+}
+
+// Lo que hace falta para corregir el --GS que sigue a un GPS o BP movido
+struct Pendiente {
+    nombre: String,
+    indice: usize,
+    original: (f64, f64, f64),
+    corregido: (f64, f64, f64),
+}
+
+fn sumar(a: (f64, f64, f64), b: (f64, f64, f64)) -> (f64, f64, f64) {
+    (a.0 + b.0, a.1 + b.1, a.2 + b.2)
+}
+
+fn restar(a: (f64, f64, f64), b: (f64, f64, f64)) -> (f64, f64, f64) {
+    (a.0 - b.0, a.1 - b.1, a.2 - b.2)
+}
+
+fn reemplazar_campos(linea: &str, nuevos: &[(usize, String)]) -> String {
+    let mut campos: Vec<String> = linea.split(',').map(|c| c.to_string()).collect();
+    for (i, v) in nuevos {
+        campos[*i] = v.clone();
+    }
+    campos.join(",")
+}
+
+// Traslada cada sesión de base corregida, y todos los GPS medidos desde
+// ella, por la diferencia geocéntrica entre la posición original y la
+// corregida de la base. El --GS se corre lo mismo: con la proyección del
+// trabajo si se da, si no con el este/norte local. Cada línea modificada
+// queda precedida por la original como comentario "--Original".
+pub fn corregir_lineas(lineas: &[&str], correcciones: &[CorreccionBase], tolerancia: f64,
+                       proyeccion: Option<&TransversaMercator>) -> Result<ResultadoCorreccion, anyhow::Error> {
+    let mut resultado = ResultadoCorreccion::default();
+    let mut sesion: Option<Sesion> = None;
+    let mut pendiente: Option<Pendiente> = None;
+
+    for (i, linea) in lineas.iter().enumerate() {
+        let tipo = linea.split(',').next().unwrap_or("");
+        let movido = match tipo {
+            "BP" => {
+                let bp = parse_bp_record(linea)?;
+                let original = (bp.latitude, bp.longitude, bp.elevation);
+                let correccion = correcciones.iter().find(|c| {
+                    let (e, n, _) = a_enu(&GRS80, original, (c.latitud, c.longitud, c.elevacion));
+                    c.nombre == bp.occupy_point && e.hypot(n) <= tolerancia
+                });
+                sesion = correccion.map(|c| Sesion {
+                    base: c.nombre.clone(),
+                    delta: restar(a_geocentricas(&GRS80, c.latitud, c.longitud, c.elevacion),
+                                  a_geocentricas(&GRS80, original.0, original.1, original.2)),
+                });
+                correccion.map(|c| {
+                    let corregido = (c.latitud, c.longitud, c.elevacion);
+                    let nueva = reemplazar_campos(linea, &[
+                        (2, format!("LA{:.8}", corregido.0)),
+                        (3, format!("LN{:.8}", corregido.1)),
+                        (4, format!("ET{:.4}", corregido.2))]);
+                    (bp.occupy_point, original, corregido, nueva)
+                })
+            },
+            "GPS" => match &sesion {
+                None => None,
+                Some(s) => {
+                    let gps = parse_gps_record(linea)?;
+                    let original = (gps.latitude, gps.longitude, gps.elevation);
+                    let (x, y, z) = sumar(a_geocentricas(&GRS80, original.0, original.1, original.2), s.delta);
+                    let corregido = a_geodesicas(&GRS80, x, y, z);
+                    let nueva = reemplazar_campos(linea, &[
+                        (2, format!("LA{:.8}", corregido.0)),
+                        (3, format!("LN{:.8}", corregido.1)),
+                        (4, format!("EL{:.6}", corregido.2))]);
+                    Some((gps.occupy_point, original, corregido, nueva))
+                }
+            },
+            "--GS" => {
+                if let Some(p) = pendiente.take() {
+                    let gs = parse_gs_record(linea)?;
+                    if gs.occupy_point == p.nombre {
+                        let (dn, de) = match proyeccion {
+                            Some(proy) => {
+                                let (n0, e0) = proy.a_plano(p.original.0, p.original.1);
+                                let (n1, e1) = proy.a_plano(p.corregido.0, p.corregido.1);
+                                (n1 - n0, e1 - e0)
+                            },
+                            None => {
+                                let (e, n, _) = a_enu(&GRS80, p.original, p.corregido);
+                                (n, e)
+                            },
+                        };
+                        let du = p.corregido.2 - p.original.2;
+                        let corregida = (gs.north + dn, gs.east + de);
+                        resultado.lineas.push(format!("--Original: {}", linea));
+                        resultado.lineas.push(reemplazar_campos(linea, &[
+                            (2, format!("N {:.4}", corregida.0)),
+                            (3, format!("E {:.4}", corregida.1)),
+                            (4, format!("EL{:.4}", gs.elevation + du))]));
+                        let d = &mut resultado.desplazamientos[p.indice];
+                        d.grilla_original = Some((gs.north, gs.east));
+                        d.grilla_corregida = Some(corregida);
+                        continue;
+                    }
+                }
+                None
+            },
+            _ => None,
+        };
+
+        match movido {
+            Some((nombre, original, corregido, nueva)) => {
+                resultado.lineas.push(format!("--Original: {}", linea));
+                resultado.lineas.push(nueva);
+                pendiente = Some(Pendiente { nombre: nombre.clone(), indice: resultado.desplazamientos.len(), original, corregido });
+                resultado.desplazamientos.push(Desplazamiento {
+                    linea: i + 1,
+                    nombre,
+                    base: sesion.as_ref().map(|s| s.base.clone()).unwrap_or_default(),
+                    original,
+                    corregido,
+                    grilla_original: None,
+                    grilla_corregida: None,
+                });
+            },
+            None => {
+                if tipo != "--GS" {
+                    pendiente = None;
+                }
+                resultado.lineas.push(linea.to_string());
+            }
+        }
+    }
+    Ok(resultado)
+}
+
+// Este, norte y arriba de la corrección de una sesión, para informarla
+pub fn desplazamiento_enu(d: &Desplazamiento) -> (f64, f64, f64) {
+    let delta = restar(a_geocentricas(&GRS80, d.corregido.0, d.corregido.1, d.corregido.2),
+                       a_geocentricas(&GRS80, d.original.0, d.original.1, d.original.2));
+    rotar_a_enu(d.original.0, d.original.1, delta)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::geodesia::de_enu;
+    use std::io::Write;
+
+    const LINEAS: [&str; 9] = [
+        "BP,PN0,LA-35.02153129,LN-58.26579176,ET1.6280,AG1.5230,PA1.6278,ATAPC,SRBASE,--",
+        "--GS,PN0,N 6123201.7319,E 504615.1011,EL1.6280,--Base",
+        "GPS,PN1,LA-35.02154763,LN-58.26577623,EL1.244110,--esq",
+        "--GS,PN1,N 6123196.6946,E 504616.3950,EL1.2441,--esq",
+        "--GT,PN1,SW2205,ST242097000,EW2205,ET242107000",
+        "BP,PN0,LA-35.02255202,LN-58.26477676,ET22.0720,AG1.6890,PA1.7942,ATAPC,SRBASE,--",
+        "--GS,PN0,N 6122887.0388,E 504872.1981,EL22.0720,--Base",
+        "GPS,PN2,LA-35.02154763,LN-58.26577623,EL1.244110,--esq",
+        "--GS,PN2,N 6123196.6946,E 504616.3950,EL1.2441,--esq",
+    ];
+
+    #[test]
+    fn test_corregir_lineas() {
+        // La base se corre 0.5 m al este, 1.2 m al norte y 0.3 m hacia abajo
+        let (la, ln, el) = de_enu(&GRS80, (-35.02153129, -58.26579176, 1.628), (0.5, 1.2, -0.3));
+        let correccion = CorreccionBase { nombre: "0".to_string(), latitud: la, longitud: ln, elevacion: el };
+
+        let r = corregir_lineas(&LINEAS, &[correccion], 10., None).unwrap();
+        // Base y punto de la primera sesión, con sus GS y los originales
+        assert_eq!(r.lineas.len(), LINEAS.len() + 4);
+        assert_eq!(r.desplazamientos.len(), 2);
+        assert_eq!(r.lineas[0], format!("--Original: {}", LINEAS[0]));
+
+        let punto = &r.desplazamientos[1];
+        assert_eq!(punto.nombre, "1");
+        assert_eq!(punto.base, "0");
+        let (e, n, u) = desplazamiento_enu(punto);
+        assert!((e - 0.5).abs() < 1e-4 && (n - 1.2).abs() < 1e-4 && (u + 0.3).abs() < 1e-4);
+        let (n0, e0) = punto.grilla_original.unwrap();
+        let (n1, e1) = punto.grilla_corregida.unwrap();
+        assert!((n1 - n0 - 1.2).abs() < 1e-3 && (e1 - e0 - 0.5).abs() < 1e-3);
+
+        let gps = parse_gps_record(&r.lineas[5]).unwrap();
+        assert!((gps.latitude - punto.corregido.0).abs() < 1e-8);
+        let gs = parse_gs_record(&r.lineas[7]).unwrap();
+        assert!((gs.elevation - (1.2441 - 0.3)).abs() < 1e-4);
+
+        // La segunda base PN0 está a 100 m: no se toca, ni sus puntos
+        assert_eq!(&r.lineas[r.lineas.len() - 4..], &LINEAS[5..]);
+    }
+
+    #[test]
+    fn test_corregir_archivo() {
+        use crate::file_parser::{combinar_hasta_estabilizar, de_archivo_a_registros, lineas_a_registros};
+
+        let contenido = fs::read_to_string("tests/test.rw5").unwrap();
+        let lineas: Vec<&str> = contenido.lines().collect();
+        let (la, ln, el) = de_enu(&GRS80, (-35.02153129, -58.26579176, 1.628), (0.02, -0.03, 0.05));
+        let correccion = CorreccionBase { nombre: "0".to_string(), latitud: la, longitud: ln, elevacion: el };
+
+        let r = corregir_lineas(&lineas, &[correccion], 1., None).unwrap();
+        assert!(!r.desplazamientos.is_empty());
+        assert!(r.desplazamientos.iter().all(|d| d.grilla_corregida.is_some()));
+
+        // Las líneas "--Original" no son registros: mismos registros que antes
+        let corregidas: Vec<&str> = r.lineas.iter().map(|l| l.as_str()).collect();
+        let registros = combinar_hasta_estabilizar(lineas_a_registros(corregidas).unwrap().registros);
+        assert_eq!(registros.len(), de_archivo_a_registros(Path::new("tests/test.rw5")).len());
+    }
+
+    #[test]
+    fn test_leer_coordenadas() {
+        let mut archivo = tempfile::NamedTempFile::new().unwrap();
+        write!(archivo, "nombre,latitud,longitud,elevacion\n0,-35.0215,-58.2657,1.5\n").unwrap();
+        let c = leer_coordenadas(archivo.path()).unwrap();
+        assert_eq!(c, vec![CorreccionBase { nombre: "0".to_string(), latitud: -35.0215, longitud: -58.2657, elevacion: 1.5 }]);
+    }
+}
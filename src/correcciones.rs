@@ -0,0 +1,150 @@
+use crate::escala::factor_de_elevacion;
+use crate::proyeccion::TransversaMercator;
+use crate::record_parser::{parse_mode_setup_record, ModeSetupRecord};
+
+// El que usa SurvCE por omisión
+pub const COEFICIENTE_DE_REFRACCION: f64 = 0.14;
+const RADIO_TERRESTRE: f64 = 6_371_000.;
+
+// Correcciones a las distancias y desniveles de la estación total. Las
+// distancias y elevaciones van en las unidades del archivo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Correcciones {
+    // Curvatura y refracción en los desniveles trigonométricos (EC del MO)
+    pub curvatura: bool,
+    pub refraccion: f64,
+    // Se suma a cada distancia inclinada medida (EO del MO)
+    pub constante_edm: f64,
+    // Factor de las distancias horizontales (SF del MO)
+    pub escala: f64,
+    // Si está, las distancias horizontales se llevan al nivel del elipsoide
+    // y a la grilla en cada estación, y el SF no se usa: es el mismo factor
+    // de grilla calculado por la controladora
+    pub proyeccion: Option<TransversaMercator>,
+    pub metros_por_unidad: f64,
+}
+
+impl Default for Correcciones {
+    fn default() -> Self {
+        Correcciones {
+            curvatura: false,
+            refraccion: COEFICIENTE_DE_REFRACCION,
+            constante_edm: 0.,
+            escala: 1.,
+            proyeccion: None,
+            metros_por_unidad: 1.,
+        }
+    }
+}
+
+impl Correcciones {
+    // UN0 son pies, UN1 metros y UN2 pies topográficos de EEUU
+    pub fn de_modo(mo: &ModeSetupRecord) -> Self {
+        Correcciones {
+            curvatura: mo.ec == 1,
+            constante_edm: mo.eo,
+            escala: mo.sf,
+            metros_por_unidad: match mo.un {
+                0 => 0.3048,
+                2 => 1200. / 3937.,
+                _ => 1.,
+            },
+            ..Correcciones::default()
+        }
+    }
+
+    // Lo que cambia un MO a mitad del trabajo; la proyección y el
+    // coeficiente de refracción no vienen en el MO y se conservan
+    pub fn con_modo(&self, mo: &ModeSetupRecord) -> Self {
+        Correcciones { refraccion: self.refraccion, proyeccion: self.proyeccion, ..Correcciones::de_modo(mo) }
+    }
+
+    // Con el último MO del archivo, como `escala_mo_de_lineas`. Para todo
+    // el trabajo; cogo::reducir_con sigue además cada MO en su lugar.
+    pub fn de_lineas(lineas: &[&str]) -> Option<Self> {
+        lineas.iter().rev()
+            .filter(|l| l.starts_with("MO,"))
+            .find_map(|l| parse_mode_setup_record(l).ok())
+            .map(|mo| Correcciones::de_modo(&mo))
+    }
+
+    // (1 - k)·D² / 2R, a sumar al desnivel
+    pub fn curvatura_y_refraccion(&self, distancia_horizontal: f64) -> f64 {
+        if !self.curvatura {
+            return 0.;
+        }
+        let d = distancia_horizontal * self.metros_por_unidad;
+        (1. - self.refraccion) * d * d / (2. * RADIO_TERRESTRE) / self.metros_por_unidad
+    }
+
+    // Factor para una distancia horizontal medida desde (norte, este) a la
+    // elevación media de la visual: el de la proyección si la hay, si no el SF
+    pub fn factor_de_distancia(&self, norte: f64, este: f64, elevacion: f64) -> f64 {
+        match self.proyeccion {
+            Some(p) => {
+                let m = self.metros_por_unidad;
+                let (latitud, longitud) = p.a_geodesicas(norte * m, este * m);
+                p.factor_de_escala(latitud, longitud) * factor_de_elevacion(&p, latitud, elevacion * m)
+            },
+            None => self.escala,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cogo::{reducir_distancia, Estacion};
+    use crate::proyeccion::GRS80;
+    use crate::record_parser::{DistanceOption, ZenithOption};
+
+    fn estacion(norte: f64, este: f64, elevacion: f64) -> Estacion {
+        Estacion {
+            nombre: "1".to_string(), norte, este, elevacion,
+            altura_instrumento: 1.5, altura_prisma: 1.5,
+            atras: None, azimut_atras: None, circulo_atras: 0.,
+        }
+    }
+
+    #[test]
+    fn test_correcciones_del_mo() {
+        let c = Correcciones::de_lineas(&["MO,AD0,UN1,SF0.999600,EC1,EO0.030,AU0", "OC,OP1,N 0,E 0,EL0"]).unwrap();
+        assert!(c.curvatura);
+        assert_eq!((c.constante_edm, c.escala, c.metros_por_unidad), (0.03, 0.9996, 1.));
+        assert!(Correcciones::de_lineas(&["JB,NMSAMPLE"]).is_none());
+
+        let (hd, dh) = reducir_distancia(&estacion(0., 0., 0.), ZenithOption::Zenith(90.), DistanceOption::SlopeDistance(1000.), &c);
+        assert!((hd - 1000.03 * 0.9996).abs() < 1e-9);
+        // Unos 6.75 cm a un kilómetro
+        assert!((dh - 0.86 * 1000.03f64.powi(2) / (2. * RADIO_TERRESTRE)).abs() < 1e-9);
+
+        // Sin EC el desnivel queda como está
+        let c = Correcciones { curvatura: false, ..c };
+        let (_, dh) = reducir_distancia(&estacion(0., 0., 0.), ZenithOption::Zenith(90.), DistanceOption::SlopeDistance(1000.), &c);
+        assert!(dh.abs() < 1e-12);
+
+        // En pies la corrección es la misma longitud
+        let pies = Correcciones { curvatura: true, metros_por_unidad: 0.3048, ..Correcciones::default() };
+        let metros = Correcciones { curvatura: true, ..Correcciones::default() };
+        assert!((pies.curvatura_y_refraccion(1000. / 0.3048) * 0.3048 - metros.curvatura_y_refraccion(1000.)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_reduccion_a_grilla() {
+        let proy = TransversaMercator { latitud_origen: -90., ..TransversaMercator::new(GRS80, -58.5, 1., 500_000., 0.) };
+        let (n, e) = proy.a_plano(-35., -58.5);
+        let c = Correcciones { proyeccion: Some(proy), ..Correcciones::default() };
+        // Sobre el meridiano central con escala 1 sólo queda el nivel
+        let esperado = factor_de_elevacion(&proy, -35., 1000.);
+        assert!((c.factor_de_distancia(n, e, 1000.) - esperado).abs() < 1e-9);
+
+        // La elevación que cuenta es la media de la visual
+        let (hd, dh) = reducir_distancia(&estacion(n, e, 990.), ZenithOption::Zenith(90.), DistanceOption::HorizontalDistance(100.), &c);
+        assert!(dh.abs() < 1e-9);
+        assert!((hd - 100. * factor_de_elevacion(&proy, -35., 990.)).abs() < 1e-9);
+
+        // Con proyección el SF de la controladora no se aplica otra vez
+        let con_sf = Correcciones { escala: 0.9996, ..c };
+        assert_eq!(con_sf.factor_de_distancia(n, e, 1000.), c.factor_de_distancia(n, e, 1000.));
+    }
+}
@@ -0,0 +1,308 @@
+use crate::proyeccion::{Elipsoide, TransversaMercator, GRS80, INTERNACIONAL_1924, WGS84};
+use anyhow::anyhow;
+use serde::Serialize;
+use std::{fs, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum TipoDeProyeccion {
+    GaussKruger,
+    Utm,
+    TransversaMercator,
+}
+
+// Sistema de coordenadas del trabajo, tal como lo define la línea
+// "--User Defined: datum/elipsoide/proyección" del pie del archivo.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Crs {
+    pub datum: String,
+    pub tipo: TipoDeProyeccion,
+    pub proyeccion: TransversaMercator,
+    pub descripcion: String,
+}
+
+struct EntradaEpsg {
+    codigo: u32,
+    nombre: String,
+    datum: &'static str,
+    proyeccion: TransversaMercator,
+}
+
+// Familias de fajas argentinas: código EPSG de la faja 1, nombre, datum y elipsoide
+const FAJAS_ARGENTINAS: [(u32, &str, &str, Elipsoide); 4] = [
+    (5343, "POSGAR 2007", "POSGAR_2007", GRS80),
+    (22171, "POSGAR 98", "POSGAR_98", GRS80),
+    (22181, "POSGAR 94", "POSGAR_94", WGS84),
+    (22191, "Campo Inchauspe", "Campo_Inchauspe", INTERNACIONAL_1924),
+];
+
+fn tabla_epsg() -> Vec<EntradaEpsg> {
+    let mut tabla = vec![];
+    for (base, nombre, datum, elipsoide) in FAJAS_ARGENTINAS.iter() {
+        for faja in 1..=7u8 {
+            tabla.push(EntradaEpsg {
+                codigo: base + faja as u32 - 1,
+                nombre: format!("{} / Argentina {}", nombre, faja),
+                datum,
+                proyeccion: TransversaMercator::faja_argentina(faja, *elipsoide).expect("Faja válida"),
+            });
+        }
+    }
+    for zona in 1..=60u8 {
+        for (base, hemisferio, sur) in [(32600, "N", false), (32700, "S", true)] {
+            tabla.push(EntradaEpsg {
+                codigo: base + zona as u32,
+                nombre: format!("WGS 84 / UTM zone {}{}", zona, hemisferio),
+                datum: "WGS_1984",
+                proyeccion: TransversaMercator::utm(zona, sur).expect("Zona válida"),
+            });
+        }
+    }
+    tabla
+}
+
+fn iguales(a: &TransversaMercator, b: &TransversaMercator) -> bool {
+    let cerca = |x: f64, y: f64, tol: f64| (x - y).abs() < tol;
+    cerca(a.elipsoide.a, b.elipsoide.a, 1e-3)
+        && cerca(a.elipsoide.inv_f, b.elipsoide.inv_f, 1e-9)
+        && cerca(a.meridiano_central, b.meridiano_central, 1e-9)
+        && cerca(a.latitud_origen, b.latitud_origen, 1e-9)
+        && cerca(a.escala, b.escala, 1e-12)
+        && cerca(a.falso_este, b.falso_este, 1e-3)
+        && cerca(a.falso_norte, b.falso_norte, 1e-3)
+}
+
+// Nombre de la tabla para los datums que se escriben de varias maneras en
+// el pie: "POSGAR07", "Posgar 2007", "Inchauspe"
+fn datum_conocido(datum: &str) -> Option<&'static str> {
+    let clave: String = datum.to_uppercase().chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    match clave.as_str() {
+        "POSGAR07" | "POSGAR2007" => Some("POSGAR_2007"),
+        "POSGAR98" | "POSGAR1998" => Some("POSGAR_98"),
+        "POSGAR94" | "POSGAR1994" => Some("POSGAR_94"),
+        "INCHAUSPE" | "CAMPOINCHAUSPE" => Some("Campo_Inchauspe"),
+        "WGS84" | "WGS1984" => Some("WGS_1984"),
+        _ => None,
+    }
+}
+
+// Ángulo en grados con sufijo de hemisferio opcional: "58.5W" es -58.5
+fn parse_angulo(s: &str) -> Result<f64, anyhow::Error> {
+    let s = s.trim().to_uppercase();
+    let (numero, signo) = match s.chars().last() {
+        Some('W') | Some('O') | Some('S') => (&s[..s.len() - 1], -1.),
+        Some('E') | Some('N') => (&s[..s.len() - 1], 1.),
+        _ => (s.as_str(), 1.),
+    };
+    Ok(signo * numero.parse::<f64>()?)
+}
+
+// "Zone 21S", "21 South"
+fn parse_zona(s: &str) -> Result<(u8, bool), anyhow::Error> {
+    let s = s.to_uppercase().replace("ZONE", "").replace("ZONA", "").replace(' ', "");
+    let digitos: String = s.chars().take_while(|c| c.is_ascii_digit()).collect();
+    let hemisferio = &s[digitos.len()..];
+    let sur = hemisferio.starts_with('S');
+    Ok((digitos.parse::<u8>()?, sur))
+}
+
+fn parse_proyeccion(texto: &str, elipsoide: Elipsoide) -> Result<(TipoDeProyeccion, TransversaMercator), anyhow::Error> {
+    // El guión sólo se normaliza en el nombre: en los valores es el signo
+    let normalizado = texto.to_uppercase().replace('Ü', "U").replace("KRUEGER", "KRUGER").replace("GAUSS-KRUGER", "GAUSS KRUGER");
+    let tokens: Vec<&str> = normalizado.split_whitespace().collect();
+
+    let (tipo, resto) = if normalizado.starts_with("UTM") {
+        let (zona, sur) = parse_zona(normalizado.trim_start_matches("UTM"))?;
+        let mut utm = TransversaMercator::utm(zona, sur)?;
+        utm.elipsoide = elipsoide;
+        return Ok((TipoDeProyeccion::Utm, utm));
+    } else if normalizado.starts_with("GAUSS KRUGER") {
+        (TipoDeProyeccion::GaussKruger, &tokens[2..])
+    } else if normalizado.starts_with("TRANSVERSE MERCATOR") || normalizado.starts_with("TRANSVERSA MERCATOR") {
+        (TipoDeProyeccion::TransversaMercator, &tokens[2..])
+    } else {
+        return Err(anyhow!(format!("Proyección desconocida: {}", texto)));
+    };
+
+    let mut proy = match tipo {
+        TipoDeProyeccion::GaussKruger => TransversaMercator {
+            latitud_origen: -90.,
+            ..TransversaMercator::new(elipsoide, 0., 1., 500_000., 0.)
+        },
+        _ => TransversaMercator::new(elipsoide, 0., 1., 500_000., 0.),
+    };
+    let mut meridiano = false;
+    for par in resto.chunks(2) {
+        let (clave, valor) = match par {
+            [c, v] => (*c, *v),
+            _ => return Err(anyhow!(format!("Parámetro de proyección sin valor: {}", texto))),
+        };
+        match clave {
+            "FAJA" | "ZONE" | "ZONA" => {
+                proy = TransversaMercator::faja_argentina(valor.parse::<u8>()?, elipsoide)?;
+                meridiano = true;
+            },
+            "CM" => {
+                proy.meridiano_central = parse_angulo(valor)?;
+                meridiano = true;
+            },
+            "LAT0" | "LO" => proy.latitud_origen = parse_angulo(valor)?,
+            "SF" | "K0" => proy.escala = valor.parse::<f64>()?,
+            "FE" => proy.falso_este = valor.parse::<f64>()?,
+            "FN" => proy.falso_norte = valor.parse::<f64>()?,
+            _ => return Err(anyhow!(format!("Parámetro de proyección desconocido: {}", clave))),
+        }
+    }
+    if !meridiano {
+        return Err(anyhow!(format!("Proyección sin meridiano central: {}", texto)));
+    }
+    Ok((tipo, proy))
+}
+
+pub fn parse_user_defined(linea: &str) -> Result<Crs, anyhow::Error> {
+    let descripcion = match linea.split_once(':') {
+        Some((etiqueta, resto)) if etiqueta.trim() == "--User Defined" => resto.trim(),
+        _ => return Err(anyhow!("No es una línea User Defined")),
+    };
+    let partes: Vec<&str> = descripcion.split('/').map(|s| s.trim()).collect();
+    if partes.len() != 3 {
+        return Err(anyhow!(format!("Formato User Defined inválido: {}", descripcion)));
+    }
+    let elipsoide = Elipsoide::por_nombre(partes[1])
+        .ok_or_else(|| anyhow!(format!("Elipsoide desconocido: {}", partes[1])))?;
+    let (tipo, proyeccion) = parse_proyeccion(partes[2], elipsoide)?;
+
+    Ok(Crs { datum: partes[0].to_string(), tipo, proyeccion, descripcion: descripcion.to_string() })
+}
+
+// El pie se repite en cada trabajo del archivo; vale el último.
+pub fn crs_de_lineas(lineas: &[&str]) -> Result<Option<Crs>, anyhow::Error> {
+    match lineas.iter().rev().find(|l| l.starts_with("--User Defined")) {
+        Some(l) => Ok(Some(parse_user_defined(l)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn leer_crs(archivo: &Path) -> Result<Option<Crs>, anyhow::Error> {
+    let contenido = fs::read_to_string(archivo)?;
+    let lineas: Vec<&str> = contenido.lines().collect();
+    crs_de_lineas(&lineas)
+}
+
+impl Crs {
+    // Si el datum no es uno conocido ("default", "local") sólo se acepta
+    // cuando la proyección corresponde a un único datum de la tabla
+    fn entrada_epsg(&self) -> Option<EntradaEpsg> {
+        let mut candidatos: Vec<EntradaEpsg> = tabla_epsg().into_iter()
+            .filter(|e| iguales(&e.proyeccion, &self.proyeccion))
+            .collect();
+        match datum_conocido(&self.datum) {
+            Some(d) => candidatos.into_iter().find(|e| e.datum == d),
+            None if candidatos.len() == 1 => candidatos.pop(),
+            None => None,
+        }
+    }
+
+    pub fn epsg(&self) -> Option<u32> {
+        self.entrada_epsg().map(|e| e.codigo)
+    }
+
+    // WKT1 de OGC, que es lo que esperan los .prj
+    pub fn a_wkt(&self) -> String {
+        let conocido = self.entrada_epsg();
+        let (nombre, datum) = match &conocido {
+            Some(e) => (e.nombre.clone(), e.datum.to_string()),
+            None => (self.descripcion.clone(), self.datum.replace(' ', "_")),
+        };
+        let p = &self.proyeccion;
+        let e = &p.elipsoide;
+        let autoridad = match &conocido {
+            Some(c) => format!(",AUTHORITY[\"EPSG\",\"{}\"]", c.codigo),
+            None => "".to_string(),
+        };
+        format!(concat!(
+            "PROJCS[\"{}\",",
+            "GEOGCS[\"GCS_{}\",DATUM[\"D_{}\",SPHEROID[\"{}\",{},{}]],",
+            "PRIMEM[\"Greenwich\",0.0],UNIT[\"Degree\",0.0174532925199433]],",
+            "PROJECTION[\"Transverse_Mercator\"],",
+            "PARAMETER[\"False_Easting\",{}],PARAMETER[\"False_Northing\",{}],",
+            "PARAMETER[\"Central_Meridian\",{}],PARAMETER[\"Scale_Factor\",{}],",
+            "PARAMETER[\"Latitude_Of_Origin\",{}],UNIT[\"Meter\",1.0]{}]"),
+            nombre, datum, datum, e.nombre, e.a, e.inv_f,
+            p.falso_este, p.falso_norte, p.meridiano_central, p.escala, p.latitud_origen,
+            autoridad)
+    }
+
+    // Escribe el .prj al lado de una exportación: datos.csv -> datos.prj
+    pub fn escribir_prj(&self, exportacion: &Path) -> Result<(), anyhow::Error> {
+        fs::write(exportacion.with_extension("prj"), self.a_wkt())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_user_defined() {
+        let crs = parse_user_defined("--User Defined: default/GRS80/Gauss Kruger CM 58.5W").unwrap();
+        assert_eq!(crs.datum, "default");
+        assert_eq!(crs.tipo, TipoDeProyeccion::GaussKruger);
+        assert_eq!(crs.proyeccion.elipsoide, GRS80);
+        assert_eq!(crs.proyeccion.meridiano_central, -58.5);
+        assert_eq!(crs.proyeccion.latitud_origen, -90.);
+        assert_eq!(crs.proyeccion.falso_este, 500_000.);
+        assert_eq!(crs.proyeccion.escala, 1.);
+        assert_eq!(crs.epsg(), None);
+
+        let crs = parse_user_defined("--User Defined: POSGAR07/GRS80/Gauss Kruger Faja 5").unwrap();
+        assert_eq!(crs.epsg(), Some(5347));
+        let crs = parse_user_defined("--User Defined: Inchauspe/International 1924/Gauss-Kruger Faja 6").unwrap();
+        assert_eq!(crs.epsg(), Some(22196));
+        let crs = parse_user_defined("--User Defined: WGS84/WGS84/UTM Zone 21S").unwrap();
+        assert_eq!(crs.epsg(), Some(32721));
+        let crs = parse_user_defined("--User Defined: local/WGS84/Transverse Mercator CM 57W SF 0.9996 FE 500000 FN 10000000").unwrap();
+        assert_eq!(crs.epsg(), Some(32721));
+        let crs = parse_user_defined("--User Defined: local/GRS80/Transverse Mercator CM -57 LAT0 -90 FE 500000 FN -10000").unwrap();
+        assert_eq!(crs.proyeccion.meridiano_central, -57.);
+        assert_eq!(crs.proyeccion.latitud_origen, -90.);
+        assert_eq!(crs.proyeccion.falso_norte, -10_000.);
+        let crs = parse_user_defined("--User Defined: default/GRS80/Gauss-Krüger CM -58.5").unwrap();
+        assert_eq!((crs.tipo, crs.proyeccion.meridiano_central), (TipoDeProyeccion::GaussKruger, -58.5));
+
+        // Las fajas GRS80 son las mismas en POSGAR 98 y 2007: decide el datum
+        let crs = parse_user_defined("--User Defined: POSGAR 98/GRS80/Gauss Kruger Faja 5").unwrap();
+        assert_eq!(crs.epsg(), Some(22175));
+        assert!(crs.a_wkt().contains("DATUM[\"D_POSGAR_98\""));
+        let crs = parse_user_defined("--User Defined: default/GRS80/Gauss Kruger Faja 5").unwrap();
+        assert_eq!(crs.epsg(), None);
+        let crs = parse_user_defined("--User Defined: WGS84/GRS80/Gauss Kruger Faja 5").unwrap();
+        assert_eq!(crs.epsg(), None);
+
+        assert!(parse_user_defined("--User Defined: default/Bessel/Gauss Kruger CM 58.5W").is_err());
+        assert!(parse_user_defined("--User Defined: default/GRS80/Gauss Kruger").is_err());
+        assert!(parse_user_defined("--User Defined: default/GRS80/Lambert CM 58.5W").is_err());
+    }
+
+    #[test]
+    fn test_wkt_y_prj() {
+        let crs = parse_user_defined("--User Defined: POSGAR07/GRS80/Gauss Kruger Faja 5").unwrap();
+        let wkt = crs.a_wkt();
+        assert!(wkt.starts_with("PROJCS[\"POSGAR 2007 / Argentina 5\""));
+        assert!(wkt.contains("PARAMETER[\"Central_Meridian\",-60]"));
+        assert!(wkt.contains("PARAMETER[\"False_Easting\",5500000]"));
+        assert!(wkt.ends_with("AUTHORITY[\"EPSG\",\"5347\"]]"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("puntos.csv");
+        crs.escribir_prj(&csv).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("puntos.prj")).unwrap(), wkt);
+    }
+
+    #[test]
+    fn test_leer_crs() {
+        let crs = leer_crs(Path::new("tests/test.rw5")).unwrap().unwrap();
+        assert_eq!(crs.descripcion, "default/GRS80/Gauss Kruger CM 58.5W");
+        assert!(crs.a_wkt().contains("D_default"));
+    }
+}
@@ -0,0 +1,284 @@
+use crate::file_parser::Record;
+use crate::geodesia::{a_geocentricas, a_geodesicas};
+use crate::proyeccion::{Elipsoide, GRS80, INTERNACIONAL_1924, WGS84};
+use anyhow::anyhow;
+use rinex::prelude::Epoch;
+use serde::Serialize;
+
+const SEGUNDO_A_RADIAN: f64 = std::f64::consts::PI / (180. * 3600.);
+
+// Sentido de las rotaciones. IERS y EPSG 9606 usan "vector posición";
+// EPSG 9607 y buena parte de los parámetros viejos, "marco de coordenadas".
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Default)]
+pub enum Rotacion {
+    #[default]
+    VectorPosicion,
+    MarcoDeCoordenadas,
+}
+
+// Parámetros de Helmert: traslaciones en metros, rotaciones en segundos de
+// arco y escala en ppm. Con `tasas` (por año) son los 14 parámetros,
+// referidos a `epoca_referencia` en años decimales.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Default)]
+pub struct Helmert {
+    pub parametros: [f64; 7],
+    pub tasas: Option<[f64; 7]>,
+    pub epoca_referencia: f64,
+    pub rotacion: Rotacion,
+}
+
+impl Helmert {
+    pub fn siete(parametros: [f64; 7], rotacion: Rotacion) -> Self {
+        Helmert { parametros, tasas: None, epoca_referencia: 0., rotacion }
+    }
+
+    pub fn catorce(parametros: [f64; 7], tasas: [f64; 7], epoca_referencia: f64) -> Self {
+        Helmert { parametros, tasas: Some(tasas), epoca_referencia, rotacion: Rotacion::VectorPosicion }
+    }
+
+    // Parámetros dados por el usuario: "tx,ty,tz,rx,ry,rz,s" y, para 14
+    // parámetros, las siete tasas y la época de referencia a continuación.
+    pub fn de_texto(texto: &str, rotacion: Rotacion) -> Result<Self, anyhow::Error> {
+        let valores = texto.split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()?;
+        let siete = |v: &[f64]| -> [f64; 7] { [v[0], v[1], v[2], v[3], v[4], v[5], v[6]] };
+        match valores.len() {
+            7 => Ok(Helmert::siete(siete(&valores), rotacion)),
+            15 => Ok(Helmert { rotacion, ..Helmert::catorce(siete(&valores[..7]), siete(&valores[7..14]), valores[14]) }),
+            n => Err(anyhow!(format!("Se esperaban 7 o 15 valores de Helmert y hay {}", n))),
+        }
+    }
+
+    // Los siete parámetros propagados a la época `t`
+    pub fn en_epoca(&self, t: f64) -> [f64; 7] {
+        match self.tasas {
+            None => self.parametros,
+            Some(tasas) => {
+                let mut p = self.parametros;
+                for (v, r) in p.iter_mut().zip(tasas.iter()) {
+                    *v += r * (t - self.epoca_referencia);
+                }
+                p
+            }
+        }
+    }
+
+    // Aproximación de ángulos pequeños, como la definen IERS y EPSG
+    pub fn aplicar(&self, (x, y, z): (f64, f64, f64), t: f64) -> (f64, f64, f64) {
+        let [tx, ty, tz, rx, ry, rz, s] = self.en_epoca(t);
+        let signo = match self.rotacion {
+            Rotacion::VectorPosicion => 1.,
+            Rotacion::MarcoDeCoordenadas => -1.,
+        };
+        let (rx, ry, rz) = (signo * rx * SEGUNDO_A_RADIAN, signo * ry * SEGUNDO_A_RADIAN, signo * rz * SEGUNDO_A_RADIAN);
+        let k = 1. + s * 1e-6;
+        (tx + k * (x - rz * y + ry * z),
+         ty + k * (rz * x + y - rx * z),
+         tz + k * (-ry * x + rx * y + z))
+    }
+
+    // Inversa de primer orden, suficiente para parámetros chicos
+    pub fn inversa(&self) -> Self {
+        let negar = |p: [f64; 7]| p.map(|v| -v);
+        Helmert { parametros: negar(self.parametros), tasas: self.tasas.map(negar), ..*self }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TransformacionDatum {
+    pub nombre: String,
+    pub origen: Elipsoide,
+    pub destino: Elipsoide,
+    pub helmert: Helmert,
+}
+
+impl TransformacionDatum {
+    // (latitud, longitud, altura elipsoidal) en la época `t`, en años decimales
+    pub fn transformar(&self, latitud: f64, longitud: f64, altura: f64, t: f64) -> (f64, f64, f64) {
+        let xyz = a_geocentricas(&self.origen, latitud, longitud, altura);
+        let (x, y, z) = self.helmert.aplicar(xyz, t);
+        a_geodesicas(&self.destino, x, y, z)
+    }
+
+    pub fn inversa(&self) -> Self {
+        TransformacionDatum {
+            nombre: format!("{} (inversa)", self.nombre),
+            origen: self.destino,
+            destino: self.origen,
+            helmert: self.helmert.inversa(),
+        }
+    }
+}
+
+// Juegos de parámetros de uso común en Argentina
+pub fn transformaciones_conocidas() -> Vec<TransformacionDatum> {
+    let cero = [0.; 7];
+    vec![
+        TransformacionDatum {
+            // EPSG:1127
+            nombre: "Campo Inchauspe a WGS84".to_string(),
+            origen: INTERNACIONAL_1924,
+            destino: WGS84,
+            helmert: Helmert::siete([-148., 136., 90., 0., 0., 0., 0.], Rotacion::VectorPosicion),
+        },
+        TransformacionDatum {
+            // POSGAR 2007 materializa ITRF05; a nivel métrico coincide con WGS84
+            nombre: "POSGAR 2007 a WGS84".to_string(),
+            origen: GRS80,
+            destino: WGS84,
+            helmert: Helmert::siete(cero, Rotacion::VectorPosicion),
+        },
+        TransformacionDatum {
+            nombre: "POSGAR 94 a WGS84".to_string(),
+            origen: WGS84,
+            destino: WGS84,
+            helmert: Helmert::siete(cero, Rotacion::VectorPosicion),
+        },
+        TransformacionDatum {
+            // IERS, ITRF2014 a ITRF2008
+            nombre: "ITRF2014 a ITRF2008".to_string(),
+            origen: GRS80,
+            destino: GRS80,
+            helmert: Helmert::catorce(
+                [0.0016, 0.0019, 0.0024, 0., 0., 0., -0.00002],
+                [0., 0., -0.0001, 0., 0., 0., 0.00003],
+                2010.),
+        },
+        TransformacionDatum {
+            // IERS, ITRF2020 a ITRF2014
+            nombre: "ITRF2020 a ITRF2014".to_string(),
+            origen: GRS80,
+            destino: GRS80,
+            helmert: Helmert::catorce(
+                [-0.0014, -0.0009, 0.0014, 0., 0., 0., -0.00042],
+                [0., -0.0001, 0.0002, 0., 0., 0., 0.],
+                2015.),
+        },
+    ]
+}
+
+pub fn transformacion_por_nombre(nombre: &str) -> Option<TransformacionDatum> {
+    transformaciones_conocidas().into_iter().find(|t| t.nombre.eq_ignore_ascii_case(nombre))
+}
+
+// Año decimal de una época, para propagar los 14 parámetros
+pub fn anio_decimal(epoca: &Epoch) -> f64 {
+    let (y, m, d, hh, mm, ss, _) = epoca.to_gregorian_utc();
+    let inicio = Epoch::from_gregorian_utc_at_midnight(y, 1, 1);
+    let fin = Epoch::from_gregorian_utc_at_midnight(y + 1, 1, 1);
+    let ahora = Epoch::from_gregorian_utc(y, m, d, hh, mm, ss, 0);
+    y as f64 + (ahora - inicio).to_seconds() / (fin - inicio).to_seconds()
+}
+
+// Lleva LA/LN/EL de todos los GPS y BP a otro datum. EL tiene que ser
+// elipsoidal. Cada registro se transforma en la época de su GT; los que no
+// la tienen usan `epoca_por_defecto`. El N/E y la EL del --GS son del datum
+// anterior y se descartan: hay que volver a proyectar con el CRS nuevo.
+pub fn transformar_registros(registros: Vec<Record>, transformacion: &TransformacionDatum, epoca_por_defecto: f64) -> Vec<Record> {
+    let epoca = |e: &Option<Epoch>| e.as_ref().map_or(epoca_por_defecto, anio_decimal);
+    registros.into_iter().map(|r| match r {
+        Record::GPS(mut g) => {
+            let (la, ln, el) = transformacion.transformar(g.latitude, g.longitude, g.elevation, epoca(&g.start_time));
+            g.latitude = la;
+            g.longitude = ln;
+            g.elevation = el;
+            g.north = None;
+            g.east = None;
+            g.elevation_alt = None;
+            Record::GPS(g)
+        },
+        Record::BP(mut b) => {
+            let (la, ln, el) = transformacion.transformar(b.latitude, b.longitude, b.elevation, epoca(&b.start_time));
+            b.latitude = la;
+            b.longitude = ln;
+            b.elevation = el;
+            b.north = None;
+            b.east = None;
+            b.elevation_alt = None;
+            Record::BP(b)
+        },
+        otro => otro,
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file_parser::{combinar_hasta_estabilizar, lineas_a_registros};
+
+    #[test]
+    fn test_helmert_siete() {
+        // Ejemplo de la nota de guía 7-2 de IOGP (EPSG 9606)
+        let h = Helmert::siete([0., 0., 4.5, 0., 0., 0.554, 0.219], Rotacion::VectorPosicion);
+        let (x, y, z) = h.aplicar((3657660.66, 255768.55, 5201382.11), 0.);
+        assert!((x - 3657660.78).abs() < 0.01);
+        assert!((y - 255778.43).abs() < 0.01);
+        assert!((z - 5201387.75).abs() < 0.01);
+
+        // La misma transformación en la otra convención
+        let h = Helmert::siete([0., 0., 4.5, 0., 0., -0.554, 0.219], Rotacion::MarcoDeCoordenadas);
+        let (x2, y2, _) = h.aplicar((3657660.66, 255768.55, 5201382.11), 0.);
+        assert!((x2 - x).abs() < 1e-9 && (y2 - y).abs() < 1e-9);
+
+        let (xi, yi, zi) = h.inversa().aplicar((x2, y2, z), 0.);
+        assert!((xi - 3657660.66).abs() < 0.001);
+        assert!((yi - 255768.55).abs() < 0.001);
+        assert!((zi - 5201382.11).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_helmert_catorce() {
+        let h = transformacion_por_nombre("ITRF2014 a ITRF2008").unwrap().helmert;
+        assert_eq!(h.en_epoca(2010.), h.parametros);
+        let p = h.en_epoca(2020.);
+        assert!((p[2] - 0.0014).abs() < 1e-12);
+        assert!((p[6] - 0.00028).abs() < 1e-12);
+
+        let texto = "0.0016,0.0019,0.0024,0,0,0,-0.00002,0,0,-0.0001,0,0,0,0.00003,2010";
+        assert_eq!(Helmert::de_texto(texto, Rotacion::VectorPosicion).unwrap(), h);
+        assert!(Helmert::de_texto("1,2,3", Rotacion::VectorPosicion).is_err());
+    }
+
+    #[test]
+    fn test_transformar_registros() {
+        let t = transformacion_por_nombre("Campo Inchauspe a WGS84").unwrap();
+        let registros = combinar_hasta_estabilizar(lineas_a_registros(vec![
+            "GPS,PN1,LA-35.02154763,LN-58.26577623,EL1.244110,--esq",
+            "--GS,PN1,N 6124900.0000,E 521000.0000,EL1.2441,--",
+            "BP,PN0,LA-35.02153129,LN-58.26579176,ET1.6280,AG1.5230,PA1.6278,ATAPC,SRBASE,--",
+            "--DT04-12-2022",
+        ]).unwrap().registros);
+        match &registros[0] {
+            Record::GPS(g) => assert!(g.north.is_some()),
+            _ => panic!("Se esperaba un GPS"),
+        }
+        let transformados = transformar_registros(registros, &t, 2022.);
+        assert_eq!(transformados.len(), 3);
+
+        let (la, ln, el) = t.transformar(-35.02154763, -58.26577623, 1.244110, 2022.);
+        match &transformados[0] {
+            Record::GPS(g) => {
+                assert_eq!((g.latitude, g.longitude, g.elevation), (la, ln, el));
+                // El GS quedó en el datum anterior
+                assert_eq!((g.north, g.east, g.elevation_alt), (None, None, None));
+            },
+            _ => panic!("Se esperaba un GPS"),
+        }
+        // Entre Inchauspe y WGS84 hay del orden de cien metros
+        let dn = (la - -35.02154763) * 111_000.;
+        let de = (ln - -58.26577623) * 111_000. * la.to_radians().cos();
+        assert!(dn.hypot(de) > 50.);
+
+        let (la2, ln2, el2) = t.inversa().transformar(la, ln, el, 2022.);
+        assert!((la2 - -35.02154763).abs() < 1e-8);
+        assert!((ln2 - -58.26577623).abs() < 1e-8);
+        assert!((el2 - 1.244110).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_anio_decimal() {
+        let e = Epoch::from_gregorian_utc_at_midnight(2022, 7, 2);
+        assert!((anio_decimal(&e) - (2022. + 182. / 365.)).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::promedios::PuntoPromediado;
+use crate::proyeccion::{Geodesicas, TransversaMercator};
+use crate::record_parser::parse_mode_setup_record;
+use serde::Serialize;
+
+// Factores de un punto. El combinado lleva distancias del terreno a la
+// grilla: grilla = terreno * combinado.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FactoresDeEscala {
+    pub nombre: String,
+    pub factor_de_escala: f64,
+    pub factor_de_elevacion: f64,
+    pub factor_combinado: f64,
+    pub convergencia: f64,
+    // Lo que aplicó la controladora: GPS Scale del pie y SF del MO
+    pub escala_gps: Option<f64>,
+    pub escala_mo: Option<f64>,
+}
+
+impl FactoresDeEscala {
+    pub fn con_controladora(&mut self, escala_gps: Option<f64>, escala_mo: Option<f64>) -> &Self {
+        self.escala_gps = escala_gps;
+        self.escala_mo = escala_mo;
+        self
+    }
+
+    pub fn discrepancia_gps(&self) -> Option<f64> {
+        self.escala_gps.map(|s| discrepancia_gps(s, self.factor_combinado))
+    }
+
+    pub fn discrepancia_mo(&self) -> Option<f64> {
+        self.escala_mo.map(|s| discrepancia_mo(s, self.factor_combinado))
+    }
+}
+
+// La controladora pasa de grilla a terreno multiplicando por el GPS Scale,
+// que debería ser la inversa del combinado. En ppm.
+fn discrepancia_gps(escala: f64, combinado: f64) -> f64 {
+    (escala * combinado - 1.) * 1e6
+}
+
+// El SF lleva las distancias de la estación total a la grilla, como el
+// combinado. En ppm.
+fn discrepancia_mo(escala: f64, combinado: f64) -> f64 {
+    (escala / combinado - 1.) * 1e6
+}
+
+// Reducción de una distancia horizontal a altura elipsoidal `altura` al
+// elipsoide
+pub fn factor_de_elevacion(proyeccion: &TransversaMercator, latitud: f64, altura: f64) -> f64 {
+    let r = proyeccion.elipsoide.radio_medio(latitud);
+    r / (r + altura)
+}
+
+// `altura` tiene que ser elipsoidal; la del registro se usa sólo para la
+// posición
+pub fn factores<G: Geodesicas>(proyeccion: &TransversaMercator, registro: &G, altura: f64) -> FactoresDeEscala {
+    let (latitud, longitud, _) = registro.geodesicas();
+    let factor_de_escala = proyeccion.factor_de_escala(latitud, longitud);
+    let factor_de_elevacion = factor_de_elevacion(proyeccion, latitud, altura);
+    FactoresDeEscala {
+        nombre: registro.nombre().to_string(),
+        factor_de_escala,
+        factor_de_elevacion,
+        factor_combinado: factor_de_escala * factor_de_elevacion,
+        convergencia: proyeccion.convergencia(latitud, longitud),
+        escala_gps: None,
+        escala_mo: None,
+    }
+}
+
+// Un solo factor para todo el trabajo: la media de los combinados
+pub fn factor_del_trabajo(factores: &[FactoresDeEscala]) -> Option<f64> {
+    if factores.is_empty() {
+        return None;
+    }
+    Some(factores.iter().map(|f| f.factor_combinado).sum::<f64>() / factores.len() as f64)
+}
+
+// El factor del trabajo junto con lo que usó la controladora
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EscalaDelTrabajo {
+    pub factor_combinado: f64,
+    pub escala_gps: Option<f64>,
+    pub escala_mo: Option<f64>,
+    pub discrepancia_gps: Option<f64>,
+    pub discrepancia_mo: Option<f64>,
+}
+
+pub fn escala_del_trabajo(factores: &[FactoresDeEscala]) -> Option<EscalaDelTrabajo> {
+    let combinado = factor_del_trabajo(factores)?;
+    let (escala_gps, escala_mo) = (factores[0].escala_gps, factores[0].escala_mo);
+    Some(EscalaDelTrabajo {
+        factor_combinado: combinado,
+        escala_gps,
+        escala_mo,
+        discrepancia_gps: escala_gps.map(|s| discrepancia_gps(s, combinado)),
+        discrepancia_mo: escala_mo.map(|s| discrepancia_mo(s, combinado)),
+    })
+}
+
+// Carga en cada factor las escalas de la controladora que haya en el archivo
+pub fn con_escalas_de_lineas(factores: &mut [FactoresDeEscala], lineas: &[&str]) {
+    let (gps, mo) = (escala_gps_de_lineas(lineas), escala_mo_de_lineas(lineas));
+    for f in factores.iter_mut() {
+        f.con_controladora(gps, mo);
+    }
+}
+
+// "--GPS Scale: 1.00000000" del pie del archivo
+pub fn escala_gps_de_lineas(lineas: &[&str]) -> Option<f64> {
+    lineas.iter().rev()
+        .find_map(|l| l.strip_prefix("--GPS Scale:"))
+        .and_then(|v| v.trim().parse::<f64>().ok())
+}
+
+// SF del registro MO, el factor que la controladora aplica a las
+// distancias de la estación total
+pub fn escala_mo_de_lineas(lineas: &[&str]) -> Option<f64> {
+    lineas.iter().rev()
+        .filter(|l| l.starts_with("MO,"))
+        .find_map(|l| parse_mode_setup_record(l).ok())
+        .map(|mo| mo.sf)
+}
+
+// Coordenadas de terreno: la grilla escalada alrededor de un origen, para
+// que las distancias coincidan con las medidas con estación total.
+// terreno = origen + (grilla - origen) / factor
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Terreno {
+    pub origen_norte: f64,
+    pub origen_este: f64,
+    pub factor: f64,
+}
+
+impl Terreno {
+    pub fn new(origen_norte: f64, origen_este: f64, factor: f64) -> Self {
+        Terreno { origen_norte, origen_este, factor }
+    }
+
+    // La controladora multiplica las distancias de grilla por el GPS Scale,
+    // así que el factor equivalente es su inversa
+    pub fn de_escala_gps(origen_norte: f64, origen_este: f64, escala_gps: f64) -> Self {
+        Terreno::new(origen_norte, origen_este, 1. / escala_gps)
+    }
+
+    pub fn a_terreno(&self, norte: f64, este: f64) -> (f64, f64) {
+        (self.origen_norte + (norte - self.origen_norte) / self.factor,
+         self.origen_este + (este - self.origen_este) / self.factor)
+    }
+
+    pub fn a_grilla(&self, norte: f64, este: f64) -> (f64, f64) {
+        (self.origen_norte + (norte - self.origen_norte) * self.factor,
+         self.origen_este + (este - self.origen_este) * self.factor)
+    }
+
+    // Pasa norte y este de los promedios a terreno; los desvíos también
+    pub fn aplicar(&self, promedios: &mut [PuntoPromediado]) {
+        for p in promedios.iter_mut() {
+            if let (Some(n), Some(e)) = (p.norte, p.este) {
+                let (n, e) = self.a_terreno(n, e);
+                p.norte = Some(n);
+                p.este = Some(e);
+                p.desvio_norte /= self.factor;
+                p.desvio_este /= self.factor;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proyeccion::GRS80;
+    use crate::record_parser_gps::parse_gps_record;
+
+    #[test]
+    fn test_factores() {
+        let proy = TransversaMercator { latitud_origen: -90., ..TransversaMercator::new(GRS80, -58.5, 1., 500_000., 0.) };
+        let gps = parse_gps_record("GPS,PN7,LA-35.02153129,LN-58.26579176,EL1.6280,--").unwrap();
+
+        let f = factores(&proy, &gps, 1000.);
+        // A mil metros una distancia se achica unos 157 ppm al llevarla al elipsoide
+        assert!((f.factor_de_elevacion - (1. - 157e-6)).abs() < 1e-6);
+        assert!((f.factor_combinado - f.factor_de_escala * f.factor_de_elevacion).abs() < 1e-15);
+        assert_eq!(f.convergencia, proy.convergencia(gps.latitude, gps.longitude));
+
+        assert_eq!(factor_del_trabajo(&[]), None);
+        let g = factores(&proy, &gps, 0.);
+        let medio = factor_del_trabajo(&[f.clone(), g.clone()]).unwrap();
+        assert!((medio - (f.factor_combinado + g.factor_combinado) / 2.).abs() < 1e-15);
+    }
+
+    #[test]
+    fn test_escalas_del_archivo() {
+        let contenido = std::fs::read_to_string("tests/test.rw5").unwrap();
+        let lineas: Vec<&str> = contenido.lines().collect();
+        assert_eq!(escala_gps_de_lineas(&lineas), Some(1.));
+        assert_eq!(escala_mo_de_lineas(&lineas), Some(1.));
+        assert_eq!(escala_gps_de_lineas(&["--GPS Scale: 0.99960000"]), Some(0.9996));
+    }
+
+    #[test]
+    fn test_escalas_de_la_controladora() {
+        let proy = TransversaMercator { latitud_origen: -90., ..TransversaMercator::new(GRS80, -58.5, 1., 500_000., 0.) };
+        let gps = parse_gps_record("GPS,PN7,LA-35.02153129,LN-58.26579176,EL1.6280,--").unwrap();
+        let mut factores = vec![factores(&proy, &gps, 1000.)];
+        assert_eq!(factores[0].discrepancia_gps(), None);
+
+        // Una controladora bien configurada usa la inversa del combinado
+        // como GPS Scale y el combinado como SF
+        let combinado = factores[0].factor_combinado;
+        let lineas = [format!("MO,AD0,UN1,SF{:.8},EC0,EO0.0,AU0", combinado), format!("--GPS Scale: {:.8}", 1. / combinado)];
+        let lineas: Vec<&str> = lineas.iter().map(|l| l.as_str()).collect();
+        con_escalas_de_lineas(&mut factores, &lineas);
+        assert!(factores[0].discrepancia_gps().unwrap().abs() < 0.01);
+        assert!(factores[0].discrepancia_mo().unwrap().abs() < 0.01);
+
+        // Con escala 1 la discrepancia es lo que falta reducir, unos -157 ppm
+        con_escalas_de_lineas(&mut factores, &["MO,AD0,UN1,SF1.00000000,EC0,EO0.0,AU0", "--GPS Scale: 1.00000000"]);
+        let trabajo = escala_del_trabajo(&factores).unwrap();
+        assert_eq!((trabajo.escala_gps, trabajo.escala_mo), (Some(1.), Some(1.)));
+        assert!((trabajo.discrepancia_gps.unwrap() - (combinado - 1.) * 1e6).abs() < 1e-6);
+        assert!((trabajo.discrepancia_mo.unwrap() - (1. / combinado - 1.) * 1e6).abs() < 1e-6);
+        assert_eq!(escala_del_trabajo(&[]), None);
+
+        let mut csv = vec![];
+        crate::exportar::factores_a_csv(&factores, &mut csv).unwrap();
+        assert!(String::from_utf8(csv).unwrap().lines().nth(1).unwrap().contains(",1.00000000,1.00000000,"));
+    }
+
+    #[test]
+    fn test_terreno() {
+        let t = Terreno::new(6_123_000., 504_000., 0.9999);
+        let (n, e) = t.a_terreno(6_124_000., 504_000.);
+        assert!((n - 6_123_000. - 1000. / 0.9999).abs() < 1e-6);
+        assert_eq!(e, 504_000.);
+        let (n2, e2) = t.a_grilla(n, e);
+        assert!((n2 - 6_124_000.).abs() < 1e-6 && (e2 - 504_000.).abs() < 1e-9);
+
+        let t = Terreno::de_escala_gps(0., 0., 1.0001);
+        let (n, _) = t.a_terreno(1000., 0.);
+        assert!((n - 1000.1).abs() < 1e-9);
+    }
+}
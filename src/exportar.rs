@@ -0,0 +1,210 @@
+use crate::promedios::PuntoPromediado;
+use crate::verificacion::ResiduoGrilla;
+use crate::escala::FactoresDeEscala;
+use crate::calibracion::{CoordenadaLocal, ResiduoCalibracion};
+use crate::correccion_base::{desplazamiento_enu, Desplazamiento};
+use crate::cogo::ChequeoAtras;
+use crate::puestas::ResumenDePuesta;
+use crate::nivelacion::{CierreDeNivelacion, CotaDeReferencia, DesnivelPromedio};
+use crate::red::{PuntoAjustado, ResiduoRed};
+use std::io::Write;
+
+fn opcional(v: Option<f64>, decimales: usize) -> String {
+    match v {
+        Some(x) => format!("{:.*}", decimales, x),
+        None => "".into()
+    }
+}
+
+// Una fila por punto, con la media pesada de sus tomas.
+pub fn promedios_a_csv<W: Write>(promedios: &[PuntoPromediado], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "nombre,latitud,longitud,elevacion,tipo_altura,norte,este,desvio_norte,desvio_este,desvio_elevacion,tomas,atipicas")?;
+    for p in promedios {
+        writeln!(w, "{},{:.9},{:.9},{:.4},{:?},{},{},{:.4},{:.4},{:.4},{},{}",
+                 p.nombre, p.latitud, p.longitud, p.elevacion, p.tipo_altura,
+                 opcional(p.norte, 4), opcional(p.este, 4),
+                 p.desvio_norte, p.desvio_este, p.desvio_elevacion,
+                 p.tomas.len(), p.atipicas())?;
+    }
+    Ok(())
+}
+
+// Una fila por toma individual, para auditar el promedio.
+pub fn tomas_a_csv<W: Write>(promedios: &[PuntoPromediado], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "nombre,punto,epoca,latitud,longitud,elevacion,norte,este,residuo_norte,residuo_este,residuo_elevacion,atipica")?;
+    for p in promedios {
+        for t in p.tomas.iter() {
+            let r = t.registro;
+            writeln!(w, "{},{},{},{:.9},{:.9},{:.4},{},{},{:.4},{:.4},{:.4},{}",
+                     p.nombre, r.occupy_point, t.epoca,
+                     r.latitude, r.longitude, r.elevation,
+                     opcional(r.north, 4), opcional(r.east, 4),
+                     t.residuos.0, t.residuos.1, t.residuos.2,
+                     t.atipica)?;
+        }
+    }
+    Ok(())
+}
+
+// Residuos de la verificación de grilla, uno por base o punto
+pub fn residuos_grilla_a_csv<W: Write>(residuos: &[ResiduoGrilla], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "nombre,norte_gs,este_gs,norte_calculado,este_calculado,residuo_norte,residuo_este,residuo_horizontal,residuo_elevacion")?;
+    for r in residuos {
+        writeln!(w, "{},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4},{}",
+                 r.nombre, r.norte_gs, r.este_gs, r.norte_calculado, r.este_calculado,
+                 r.residuo_norte, r.residuo_este, r.horizontal(), opcional(r.residuo_elevacion, 4))?;
+    }
+    Ok(())
+}
+
+// Registros movidos por la corrección de base, con el corrimiento local
+pub fn desplazamientos_a_csv<W: Write>(desplazamientos: &[Desplazamiento], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "linea,nombre,base,latitud,longitud,elevacion,latitud_corregida,longitud_corregida,elevacion_corregida,norte,este,norte_corregido,este_corregido,d_este,d_norte,d_arriba")?;
+    for d in desplazamientos {
+        let (de, dn, du) = desplazamiento_enu(d);
+        writeln!(w, "{},{},{},{:.9},{:.9},{:.4},{:.9},{:.9},{:.4},{},{},{},{},{:.4},{:.4},{:.4}",
+                 d.linea, d.nombre, d.base,
+                 d.original.0, d.original.1, d.original.2,
+                 d.corregido.0, d.corregido.1, d.corregido.2,
+                 opcional(d.grilla_original.map(|g| g.0), 4), opcional(d.grilla_original.map(|g| g.1), 4),
+                 opcional(d.grilla_corregida.map(|g| g.0), 4), opcional(d.grilla_corregida.map(|g| g.1), 4),
+                 de, dn, du)?;
+    }
+    Ok(())
+}
+
+// Factores de escala y convergencia por punto
+pub fn factores_a_csv<W: Write>(factores: &[FactoresDeEscala], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "nombre,factor_de_escala,factor_de_elevacion,factor_combinado,convergencia,escala_gps,escala_mo,discrepancia_gps_ppm,discrepancia_mo_ppm")?;
+    for f in factores {
+        writeln!(w, "{},{:.9},{:.9},{:.9},{:.6},{},{},{},{}",
+                 f.nombre, f.factor_de_escala, f.factor_de_elevacion, f.factor_combinado, f.convergencia,
+                 opcional(f.escala_gps, 8), opcional(f.escala_mo, 8),
+                 opcional(f.discrepancia_gps(), 2), opcional(f.discrepancia_mo(), 2))?;
+    }
+    Ok(())
+}
+
+pub fn residuos_calibracion_a_csv<W: Write>(residuos: &[ResiduoCalibracion], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "nombre,residuo_norte,residuo_este,residuo_elevacion,residuo_horizontal")?;
+    for r in residuos {
+        writeln!(w, "{},{:.4},{:.4},{:.4},{:.4}",
+                 r.nombre, r.residuo_norte, r.residuo_este, r.residuo_elevacion, r.horizontal())?;
+    }
+    Ok(())
+}
+
+pub fn locales_a_csv<W: Write>(locales: &[CoordenadaLocal], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "nombre,norte,este,elevacion")?;
+    for l in locales {
+        writeln!(w, "{},{:.4},{:.4},{:.4}", l.nombre, l.norte, l.este, l.elevacion)?;
+    }
+    Ok(())
+}
+
+// Residuos angulares en segundos, distancias en metros
+pub fn chequeos_atras_a_csv<W: Write>(chequeos: &[ChequeoAtras], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "puesta,estacion,atras,azimut_atras,azimut_calculado,residuo_azimut,azimut_medido,residuo_angular,distancia_calculada,distancia_medida,residuo_distancia")?;
+    for c in chequeos {
+        writeln!(w, "{},{},{},{:.6},{},{},{},{},{},{},{}",
+                 c.puesta, c.estacion, c.atras, c.azimut_atras,
+                 opcional(c.azimut_calculado, 6), opcional(c.residuo_azimut, 1),
+                 opcional(c.azimut_medido, 6), opcional(c.residuo_angular, 1),
+                 opcional(c.distancia_calculada, 4), opcional(c.distancia_medida, 4),
+                 opcional(c.residuo_distancia, 4))?;
+    }
+    Ok(())
+}
+
+// Una fila por puesta; las alturas de prisma van separadas por ';'
+pub fn puestas_a_csv<W: Write>(puestas: &[ResumenDePuesta], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "puesta,estacion,atras,epoca,altura_instrumento,alturas_de_prisma,visuales,residuo_azimut,residuo_angular,residuo_distancia")?;
+    for p in puestas {
+        let alturas: Vec<String> = p.alturas_de_prisma.iter().map(|h| format!("{:.3}", h)).collect();
+        writeln!(w, "{},{},{},{},{:.3},{},{},{},{},{}",
+                 p.indice, p.estacion, p.atras.as_deref().unwrap_or(""),
+                 p.epoca.map(|e| e.to_string()).unwrap_or_default(),
+                 p.altura_instrumento, alturas.join(";"), p.visuales,
+                 opcional(p.residuo_azimut, 1), opcional(p.residuo_angular, 1),
+                 opcional(p.residuo_distancia, 4))?;
+    }
+    Ok(())
+}
+
+pub fn desniveles_a_csv<W: Write>(desniveles: &[DesnivelPromedio], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "desde,hasta,desnivel,distancia,idas,vueltas,diferencia_reciproca")?;
+    for d in desniveles {
+        writeln!(w, "{},{},{:.4},{:.3},{},{},{}",
+                 d.desde, d.hasta, d.desnivel, d.distancia, d.idas, d.vueltas, opcional(d.diferencia_reciproca, 4))?;
+    }
+    Ok(())
+}
+
+pub fn lazos_a_csv<W: Write>(lazos: &[CierreDeNivelacion], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "lazo,cierre,longitud")?;
+    for l in lazos {
+        writeln!(w, "{},{:.4},{:.3}", l.puntos.join("-"), l.cierre, l.longitud)?;
+    }
+    Ok(())
+}
+
+pub fn cotas_a_csv<W: Write>(cotas: &[CotaDeReferencia], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "punto,conocida,calculada,desde,diferencia")?;
+    for c in cotas {
+        writeln!(w, "{},{},{},{},{}", c.punto, opcional(c.conocida, 4), opcional(c.calculada, 4),
+                 c.desde.as_deref().unwrap_or(""), opcional(c.diferencia, 4))?;
+    }
+    Ok(())
+}
+
+// Semiejes en metros, orientación como azimut en grados
+pub fn puntos_ajustados_a_csv<W: Write>(puntos: &[PuntoAjustado], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "punto,norte,este,elevacion,sigma_norte,sigma_este,sigma_elevacion,semieje_mayor,semieje_menor,orientacion,fijo")?;
+    for p in puntos {
+        writeln!(w, "{},{:.4},{:.4},{},{:.4},{:.4},{},{},{},{},{}", p.nombre, p.norte, p.este,
+                 opcional(p.elevacion, 4), p.sigma_norte, p.sigma_este, opcional(p.sigma_elevacion, 4),
+                 opcional(p.elipse.map(|e| e.semieje_mayor), 4), opcional(p.elipse.map(|e| e.semieje_menor), 4),
+                 opcional(p.elipse.map(|e| e.orientacion), 2), p.fijo)?;
+    }
+    Ok(())
+}
+
+pub fn residuos_red_a_csv<W: Write>(residuos: &[ResiduoRed], w: &mut W) -> Result<(), anyhow::Error> {
+    writeln!(w, "tipo,desde,hasta,residuo,estandarizado")?;
+    for r in residuos {
+        writeln!(w, "{:?},{},{},{:.4},{}", r.tipo, r.desde, r.hasta, r.residuo, opcional(r.estandarizado, 2))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::file_parser::de_archivo_a_registros;
+    use crate::post_parse_gps::RelevamientoGNSS;
+    use super::*;
+
+    #[test]
+    fn test_promedios_a_csv() {
+        let registros = de_archivo_a_registros(std::path::Path::new("tests/test.rw5"));
+        let mut rel = RelevamientoGNSS::new(&registros);
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+
+        let promedios = rel.promediar_puntos(3.);
+        let mut salida: Vec<u8> = vec![];
+        promedios_a_csv(&promedios, &mut salida).unwrap();
+        let texto = String::from_utf8(salida).unwrap();
+        assert_eq!(texto.lines().count(), promedios.len() + 1);
+
+        // PN58 se midió dos veces
+        let p58 = promedios.iter().find(|p| p.nombre == "58").unwrap();
+        assert_eq!(p58.tomas.len(), 2);
+
+        let mut salida: Vec<u8> = vec![];
+        tomas_a_csv(&promedios, &mut salida).unwrap();
+        let texto = String::from_utf8(salida).unwrap();
+        let n_tomas: usize = promedios.iter().map(|p| p.tomas.len()).sum();
+        assert_eq!(texto.lines().count(), n_tomas + 1);
+    }
+}
@@ -16,7 +16,16 @@ pub enum Record {
     AT (gps::ATRecord),
     EH (gps::EHRecord),
     LS (gps::LSRecord),
-    T  (rec::TRecord)
+    Q  (gps::QRecord),
+    T  (rec::TRecord),
+    // Estación total
+    MO (rec::ModeSetupRecord),
+    OC (rec::OccupyRecord),
+    BK (rec::BacksightRecord),
+    HI (rec::LineOfSightRecord),
+    SP (rec::StorePointRecord),
+    SS (rec::TraverseRecord),
+    OF (rec::OffCenterShotRecord)
 }
 
 
@@ -39,7 +48,25 @@ impl Record {
         "--Entered Rover HR" => Ok(Record::EH(gps::parse_entered_height_record(line)?)),
         "--Entered Base HR" => Ok(Record::EH(gps::parse_entered_height_record(line)?)),
         "--Antenna Type" => Ok(Record::AT(gps::parse_antenna_type_record(line)?)),
-        "LS" => Ok(Record::LS(gps::parse_ls_record(line)?)),
+        // El LS de la estación total trae la altura de instrumento
+        "LS" => if line.contains(",HI") {
+            Ok(Record::HI(rec::parse_line_of_sight_record(line).map_err(|e| anyhow!(e.to_string()))?))
+        } else {
+            Ok(Record::LS(gps::parse_ls_record(line)?))
+        },
+        "MO" => Ok(Record::MO(rec::parse_mode_setup_record(line).map_err(|e| anyhow!(e.to_string()))?)),
+        "OC" => Ok(Record::OC(rec::parse_occupy_record(line).map_err(|e| anyhow!(e.to_string()))?)),
+        "BK" => Ok(Record::BK(rec::parse_backsight_record(line).map_err(|e| anyhow!(e.to_string()))?)),
+        "OF" => Ok(Record::OF(rec::parse_off_center_shot_record(line).map_err(|e| anyhow!(e.to_string()))?)),
+        "--SP" => Ok(Record::SP(rec::parse_store_point_record(line).map_err(|e| anyhow!(e.to_string()))?)),
+        "SS" | "TR" | "BD" | "BR" | "FD" | "FR" =>
+            Ok(Record::SS(rec::parse_traverse_record(line).map_err(|e| anyhow!(e.to_string()))?)),
+        "--Valid Readings" | "--Fixed Readings" |
+        "--Nor Min" | "--Eas Min" | "--Elv Min" |
+        "--Nor Avg" | "--Eas Avg" | "--Elv Avg" |
+        "--NRMS Avg" | "--ERMS Avg" | "--HSDV Avg" | "--VSDV Avg" |
+        "--HDOP Avg" | "--VDOP Avg" | "--PDOP Avg" | "--AGE Avg" |
+        "--Number of Satellites Avg" | "--HSDV" => Ok(Record::Q(gps::parse_quality_record(line)?)),
         &_ => if record_type.len() >= 4 
         { 
             match record_type.split_at(4) 
@@ -86,11 +113,14 @@ pub fn leer_archivo_y_parsear(archivo: &std::path::Path) -> ResultadoDeParseo
 pub fn de_archivo_a_registros(archivo: &std::path::Path) -> Vec<Record>
 {
     let result = leer_archivo_y_parsear(archivo);
-    let largo_total = result.registros.len();
+    combinar_hasta_estabilizar(result.registros)
+}
 
-    let mut largo_n = largo_total;
+pub fn combinar_hasta_estabilizar(registros: Vec<Record>) -> Vec<Record>
+{
+    let mut largo_n = registros.len();
     let mut largo_p = 0;
-    let mut registros_gps  = result.registros;
+    let mut registros_gps  = registros;
     
     while largo_n != largo_p
     {
@@ -102,6 +132,15 @@ pub fn de_archivo_a_registros(archivo: &std::path::Path) -> Vec<Record>
     registros_gps
 }
 
+// Para las pruebas: los registros combinados de unas líneas, que tienen
+// que leerse todas salvo el JB, que no es un registro del modelo
+#[cfg(test)]
+pub(crate) fn registros_de_lineas(lineas: Vec<&str>) -> Vec<Record> {
+    let parseo = lineas_a_registros(lineas).unwrap();
+    assert!(parseo.errores.iter().all(|(l, _)| l.starts_with("JB")), "{:?}", parseo.errores);
+    combinar_hasta_estabilizar(parseo.registros)
+}
+
 // pub fn vec_a_rel<'a> (registros_gps: &'a Vec<Record>) -> &'a RelevamientoGNSS
 // {
 //     let mut rel = RelevamientoGNSS::new(registros_gps);
@@ -226,7 +265,8 @@ GPS,PN3,LA-35.02146494,LN-58.27023241,EL1.280220,--esq
     {
         let result = leer_archivo_y_parsear(std::path::Path::new("tests/test.rw5"));
         //pprintln!("{:?}",result.registros);
-        assert_eq!(result.registros.len(), 4596);
+        // 4596 registros GPS más 8331 lineas de calidad y el MO
+        assert_eq!(result.registros.len(), 12928);
     }
 
 
@@ -0,0 +1,89 @@
+use crate::record_parser_gps::{EstadoSolucion, Estadistico, QRecord};
+use serde::Serialize;
+
+// Criterios de calidad para los puntos consolidados. Los que quedan en None
+// no se controlan; si un criterio está puesto y el punto no trae el dato
+// correspondiente, el punto no pasa.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct FiltroCalidad {
+    pub estados: Option<Vec<EstadoSolucion>>,
+    pub hsdv_maximo: Option<f64>,
+    pub vsdv_maximo: Option<f64>,
+    pub pdop_maximo: Option<f64>,
+    pub satelites_minimo: Option<f64>,
+    pub edad_maxima: Option<f64>,
+    // Lecturas fijas / lecturas válidas
+    pub proporcion_fijas_minima: Option<f64>,
+}
+
+fn promedio(e: &Option<Estadistico>) -> Option<f64> {
+    e.and_then(|v| v.promedio)
+}
+
+fn maximo(limite: Option<f64>, valor: Option<f64>) -> bool {
+    match limite {
+        None => true,
+        Some(l) => valor.is_some_and(|v| v <= l),
+    }
+}
+
+fn minimo(limite: Option<f64>, valor: Option<f64>) -> bool {
+    match limite {
+        None => true,
+        Some(l) => valor.is_some_and(|v| v >= l),
+    }
+}
+
+impl FiltroCalidad {
+    // Sólo soluciones fijas, lo mínimo para marcar un sitio en el RINEX
+    pub fn fijas() -> Self {
+        FiltroCalidad { estados: Some(vec![EstadoSolucion::Fija]), ..FiltroCalidad::default() }
+    }
+
+    pub fn acepta(&self, calidad: Option<&QRecord>) -> bool {
+        let q = match calidad {
+            Some(q) => q,
+            None => return *self == FiltroCalidad::default(),
+        };
+        let estado = match &self.estados {
+            None => true,
+            Some(estados) => q.estado_solucion().is_some_and(|e| estados.contains(&e)),
+        };
+
+        estado
+            && maximo(self.hsdv_maximo, promedio(&q.hsdv))
+            && maximo(self.vsdv_maximo, promedio(&q.vsdv))
+            && maximo(self.pdop_maximo, promedio(&q.pdop))
+            && minimo(self.satelites_minimo, promedio(&q.satelites))
+            && maximo(self.edad_maxima, promedio(&q.edad))
+            && minimo(self.proporcion_fijas_minima, q.proporcion_fijas())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::record_parser_gps::parse_quality_record;
+
+    #[test]
+    fn test_filtro_calidad() {
+        let fija = parse_quality_record("--HSDV:0.0100, VSDV:0.0200, STATUS:FIJO, SATS:18, PDOP:1.2, AGE:1.0").unwrap();
+        let flotante = parse_quality_record("--HSDV:0.2100, VSDV:0.3000, STATUS:FLOTANTE, SATS:9, PDOP:3.1, AGE:1.0").unwrap();
+
+        assert!(FiltroCalidad::default().acepta(None));
+        assert!(FiltroCalidad::default().acepta(Some(&flotante)));
+
+        let f = FiltroCalidad::fijas();
+        assert!(f.acepta(Some(&fija)));
+        assert!(!f.acepta(Some(&flotante)));
+        assert!(!f.acepta(None));
+
+        let f = FiltroCalidad { hsdv_maximo: Some(0.05), satelites_minimo: Some(10.), ..FiltroCalidad::default() };
+        assert!(f.acepta(Some(&fija)));
+        assert!(!f.acepta(Some(&flotante)));
+
+        // Sin lecturas no hay proporción de fijas
+        let f = FiltroCalidad { proporcion_fijas_minima: Some(0.9), ..FiltroCalidad::default() };
+        assert!(!f.acepta(Some(&fija)));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::proyeccion::{Elipsoide, Geodesicas};
+use serde::Serialize;
+
+// Geodésicas (grados, metros) a geocéntricas cartesianas
+pub fn a_geocentricas(elipsoide: &Elipsoide, latitud: f64, longitud: f64, altura: f64) -> (f64, f64, f64) {
+    let (phi, lambda) = (latitud.to_radians(), longitud.to_radians());
+    let e2 = elipsoide.e2();
+    let n = elipsoide.a / (1. - e2 * phi.sin().powi(2)).sqrt();
+    ((n + altura) * phi.cos() * lambda.cos(),
+     (n + altura) * phi.cos() * lambda.sin(),
+     (n * (1. - e2) + altura) * phi.sin())
+}
+
+// Geocéntricas a geodésicas, iterando la latitud. Converge al micrómetro
+// en pocas vueltas para puntos cerca de la superficie.
+pub fn a_geodesicas(elipsoide: &Elipsoide, x: f64, y: f64, z: f64) -> (f64, f64, f64) {
+    let e2 = elipsoide.e2();
+    let p = x.hypot(y);
+    let lambda = y.atan2(x);
+    let mut phi = z.atan2(p * (1. - e2));
+    let mut altura = 0.;
+    for _ in 0..10 {
+        let n = elipsoide.a / (1. - e2 * phi.sin().powi(2)).sqrt();
+        altura = if phi.cos().abs() > 1e-9 { p / phi.cos() - n } else { z.abs() - elipsoide.b() };
+        let anterior = phi;
+        phi = z.atan2(p * (1. - e2 * n / (n + altura)));
+        if (phi - anterior).abs() < 1e-14 {
+            break;
+        }
+    }
+    (phi.to_degrees(), lambda.to_degrees(), altura)
+}
+
+pub fn ecef<G: Geodesicas>(elipsoide: &Elipsoide, registro: &G) -> (f64, f64, f64) {
+    let (latitud, longitud, altura) = registro.geodesicas();
+    a_geocentricas(elipsoide, latitud, longitud, altura)
+}
+
+// Diferencia geocéntrica rotada al horizonte local de (latitud, longitud)
+pub fn rotar_a_enu(latitud: f64, longitud: f64, (dx, dy, dz): (f64, f64, f64)) -> (f64, f64, f64) {
+    let (sf, cf) = latitud.to_radians().sin_cos();
+    let (sl, cl) = longitud.to_radians().sin_cos();
+    (-sl * dx + cl * dy,
+     -sf * cl * dx - sf * sl * dy + cf * dz,
+     cf * cl * dx + cf * sl * dy + sf * dz)
+}
+
+pub fn rotar_de_enu(latitud: f64, longitud: f64, (e, n, u): (f64, f64, f64)) -> (f64, f64, f64) {
+    let (sf, cf) = latitud.to_radians().sin_cos();
+    let (sl, cl) = longitud.to_radians().sin_cos();
+    (-sl * e - sf * cl * n + cf * cl * u,
+     cl * e - sf * sl * n + cf * sl * u,
+     cf * n + sf * u)
+}
+
+// Coordenadas topocéntricas (este, norte, arriba) de `punto` en el
+// horizonte de `origen`, ambos en (latitud, longitud, altura elipsoidal)
+pub fn a_enu(elipsoide: &Elipsoide, origen: (f64, f64, f64), punto: (f64, f64, f64)) -> (f64, f64, f64) {
+    let (x0, y0, z0) = a_geocentricas(elipsoide, origen.0, origen.1, origen.2);
+    let (x, y, z) = a_geocentricas(elipsoide, punto.0, punto.1, punto.2);
+    rotar_a_enu(origen.0, origen.1, (x - x0, y - y0, z - z0))
+}
+
+pub fn de_enu(elipsoide: &Elipsoide, origen: (f64, f64, f64), enu: (f64, f64, f64)) -> (f64, f64, f64) {
+    let (x0, y0, z0) = a_geocentricas(elipsoide, origen.0, origen.1, origen.2);
+    let (dx, dy, dz) = rotar_de_enu(origen.0, origen.1, enu);
+    a_geodesicas(elipsoide, x0 + dx, y0 + dy, z0 + dz)
+}
+
+// Vector entre dos registros. Azimut geodésico desde el norte en sentido
+// horario y ángulo de elevación sobre el horizonte del primero, en grados.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LineaBase {
+    pub dx: f64,
+    pub dy: f64,
+    pub dz: f64,
+    pub este: f64,
+    pub norte: f64,
+    pub arriba: f64,
+    pub longitud: f64,
+    pub distancia_horizontal: f64,
+    pub azimut: f64,
+    pub elevacion: f64,
+}
+
+pub fn linea_base<A: Geodesicas, B: Geodesicas>(elipsoide: &Elipsoide, desde: &A, hasta: &B) -> LineaBase {
+    let (lat, lon, _) = desde.geodesicas();
+    let (x0, y0, z0) = ecef(elipsoide, desde);
+    let (x1, y1, z1) = ecef(elipsoide, hasta);
+    let (dx, dy, dz) = (x1 - x0, y1 - y0, z1 - z0);
+    let (este, norte, arriba) = rotar_a_enu(lat, lon, (dx, dy, dz));
+    let distancia_horizontal = este.hypot(norte);
+    LineaBase {
+        dx, dy, dz,
+        este, norte, arriba,
+        longitud: (dx * dx + dy * dy + dz * dz).sqrt(),
+        distancia_horizontal,
+        azimut: este.atan2(norte).to_degrees().rem_euclid(360.),
+        elevacion: arriba.atan2(distancia_horizontal).to_degrees(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proyeccion::{GRS80, INTERNACIONAL_1924};
+    use crate::record_parser_gps::{parse_bp_record, parse_gps_record};
+
+    #[test]
+    fn test_ida_y_vuelta_geocentricas() {
+        for (lat, lon, h) in [(-35.02153129, -58.26579176, 1.628), (0., 0., 0.), (89.9, 120., 3000.), (-54.8, -68.3, -30.)] {
+            for e in [GRS80, INTERNACIONAL_1924] {
+                let (x, y, z) = a_geocentricas(&e, lat, lon, h);
+                let (lat2, lon2, h2) = a_geodesicas(&e, x, y, z);
+                assert!((lat - lat2).abs() < 1e-10);
+                assert!((lon - lon2).abs() < 1e-10);
+                assert!((h - h2).abs() < 1e-5);
+            }
+        }
+        // En el ecuador y Greenwich X es el semieje mayor
+        let (x, y, z) = a_geocentricas(&GRS80, 0., 0., 0.);
+        assert_eq!((x, y, z), (GRS80.a, 0., 0.));
+    }
+
+    #[test]
+    fn test_enu() {
+        let origen = (-35.02153129, -58.26579176, 1.628);
+
+        let (e, n, u) = a_enu(&GRS80, origen, (origen.0, origen.1, 101.628));
+        assert!(e.abs() < 1e-6 && n.abs() < 1e-6);
+        assert!((u - 100.).abs() < 1e-6);
+
+        // Un segundo de latitud son unos 30.8 m a esta latitud
+        let (e, n, _) = a_enu(&GRS80, origen, (origen.0 + 1. / 3600., origen.1, origen.2));
+        assert!(e.abs() < 1e-6);
+        assert!((n - 30.82).abs() < 0.01);
+
+        let punto = de_enu(&GRS80, origen, (120., -45., 3.));
+        let (e, n, u) = a_enu(&GRS80, origen, punto);
+        assert!((e - 120.).abs() < 1e-6 && (n + 45.).abs() < 1e-6 && (u - 3.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_linea_base() {
+        let base = parse_bp_record("BP,PN0,LA-35.02153129,LN-58.26579176,ET1.6280,AG1.5230,PA1.6278,ATAPC,SRBASE,--").unwrap();
+        let (lat, lon, h) = de_enu(&GRS80, (base.latitude, base.longitude, base.elevation), (100., 100., 10.));
+        let punto = parse_gps_record(&format!("GPS,PN1,LA{:.10},LN{:.10},EL{:.5},--", lat, lon, h)).unwrap();
+
+        let l = linea_base(&GRS80, &base, &punto);
+        assert!((l.azimut - 45.).abs() < 1e-5);
+        assert!((l.distancia_horizontal - 100. * 2f64.sqrt()).abs() < 1e-4);
+        assert!((l.longitud - (20000f64 + 100.).sqrt()).abs() < 1e-4);
+        assert!((l.elevacion - (10. / (100. * 2f64.sqrt())).atan().to_degrees()).abs() < 1e-4);
+
+        let vuelta = linea_base(&GRS80, &punto, &base);
+        assert!((vuelta.longitud - l.longitud).abs() < 1e-9);
+        assert!((vuelta.azimut - 225.).abs() < 0.01);
+    }
+}
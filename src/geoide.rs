@@ -0,0 +1,275 @@
+use anyhow::anyhow;
+use serde::Serialize;
+use std::{collections::HashMap, convert::TryInto, fs, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Default)]
+pub enum TipoDeAltura {
+    #[default]
+    Elipsoidal,
+    Ortometrica,
+}
+
+// Grilla regular de ondulaciones N en metros. Las filas van de sur a norte
+// y las columnas de oeste a este; los valores caen sobre los nodos.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Geoide {
+    pub latitud_minima: f64,
+    pub longitud_minima: f64,
+    pub paso_latitud: f64,
+    pub paso_longitud: f64,
+    pub filas: usize,
+    pub columnas: usize,
+    valores: Vec<Option<f64>>,
+}
+
+impl Geoide {
+    fn nuevo(latitud_minima: f64, longitud_minima: f64, paso_latitud: f64, paso_longitud: f64,
+             filas: usize, columnas: usize, valores: Vec<Option<f64>>) -> Result<Self, anyhow::Error> {
+        if valores.len() != filas * columnas {
+            return Err(anyhow!(format!("La grilla tiene {} valores y se esperaban {}", valores.len(), filas * columnas)));
+        }
+        if filas < 2 || columnas < 2 || paso_latitud <= 0. || paso_longitud <= 0. {
+            return Err(anyhow!("Grilla de geoide degenerada"));
+        }
+        Ok(Geoide { latitud_minima, longitud_minima, paso_latitud, paso_longitud, filas, columnas, valores })
+    }
+
+    fn valor(&self, fila: usize, columna: usize) -> Option<f64> {
+        self.valores[fila * self.columnas + columna]
+    }
+
+    // Ondulación interpolada bilinealmente. None fuera de la grilla o si
+    // alguno de los cuatro nodos no tiene dato.
+    pub fn ondulacion(&self, latitud: f64, longitud: f64) -> Option<f64> {
+        let y = (latitud - self.latitud_minima) / self.paso_latitud;
+        // Las grillas globales suelen venir en 0..360
+        let x = [longitud, longitud + 360., longitud - 360.].iter()
+            .map(|l| (l - self.longitud_minima) / self.paso_longitud)
+            .find(|x| *x >= 0. && *x <= (self.columnas - 1) as f64)?;
+        if y < 0. || y > (self.filas - 1) as f64 {
+            return None;
+        }
+
+        let i = (y.floor() as usize).min(self.filas - 2);
+        let j = (x.floor() as usize).min(self.columnas - 2);
+        let (ty, tx) = (y - i as f64, x - j as f64);
+
+        let sur = self.valor(i, j)? * (1. - tx) + self.valor(i, j + 1)? * tx;
+        let norte = self.valor(i + 1, j)? * (1. - tx) + self.valor(i + 1, j + 1)? * tx;
+        Some(sur * (1. - ty) + norte * ty)
+    }
+
+    // h = H + N
+    pub fn convertir(&self, latitud: f64, longitud: f64, altura: f64, de: TipoDeAltura, a: TipoDeAltura) -> Option<f64> {
+        match (de, a) {
+            (TipoDeAltura::Elipsoidal, TipoDeAltura::Ortometrica) => Some(altura - self.ondulacion(latitud, longitud)?),
+            (TipoDeAltura::Ortometrica, TipoDeAltura::Elipsoidal) => Some(altura + self.ondulacion(latitud, longitud)?),
+            _ => Some(altura),
+        }
+    }
+}
+
+// NOAA/PROJ .gtx: cabecera big-endian con latitud y longitud del nodo
+// suroeste, pasos, filas y columnas, y luego f32 de sur a norte.
+pub fn leer_gtx(archivo: &Path) -> Result<Geoide, anyhow::Error> {
+    let bytes = fs::read(archivo)?;
+    if bytes.len() < 40 {
+        return Err(anyhow!("Archivo GTX demasiado corto"));
+    }
+    let f64_en = |i: usize| f64::from_be_bytes(bytes[i..i + 8].try_into().expect("8 bytes"));
+    let i32_en = |i: usize| i32::from_be_bytes(bytes[i..i + 4].try_into().expect("4 bytes"));
+    let (filas, columnas) = (i32_en(32) as usize, i32_en(36) as usize);
+
+    let datos = &bytes[40..];
+    if datos.len() != filas * columnas * 4 {
+        return Err(anyhow!("El tamaño del GTX no coincide con su cabecera"));
+    }
+    let valores = datos.chunks_exact(4)
+        .map(|b| f32::from_be_bytes(b.try_into().expect("4 bytes")) as f64)
+        .map(|v| if (v + 88.8888).abs() < 1e-3 { None } else { Some(v) })
+        .collect();
+    Geoide::nuevo(f64_en(0), f64_en(8), f64_en(16), f64_en(24), filas, columnas, valores)
+}
+
+// Encabezado "clave valor" o "clave = valor", y los datos de norte a sur
+fn encabezado_y_datos<'a>(lineas: &[&'a str], separador: Option<char>) -> (HashMap<String, String>, Vec<&'a str>) {
+    let mut encabezado = HashMap::new();
+    let mut datos = vec![];
+    for l in lineas {
+        let par = match separador {
+            Some(c) => l.split_once(c),
+            None => l.trim().split_once(char::is_whitespace),
+        };
+        match par {
+            Some((k, v)) if k.trim().chars().next().is_some_and(|c| c.is_alphabetic()) =>
+                { encabezado.insert(k.trim().to_lowercase(), v.trim().to_string()); },
+            _ => datos.push(*l),
+        }
+    }
+    (encabezado, datos)
+}
+
+fn campo<T: std::str::FromStr>(encabezado: &HashMap<String, String>, clave: &str) -> Result<T, anyhow::Error> {
+    encabezado.get(clave)
+        .ok_or_else(|| anyhow!(format!("Falta {} en el encabezado", clave)))?
+        .parse::<T>()
+        .map_err(|_| anyhow!(format!("Valor inválido para {}", clave)))
+}
+
+// Filas de norte a sur pasadas al orden de la grilla
+fn valores_de_norte_a_sur(datos: &[&str], columnas: usize, nulo: Option<f64>) -> Result<Vec<Option<f64>>, anyhow::Error> {
+    let mut filas: Vec<Vec<Option<f64>>> = vec![];
+    let numeros = datos.iter().flat_map(|l| l.split_whitespace())
+        .map(|v| v.parse::<f64>().map(|v| if nulo.is_some_and(|n| (v - n).abs() < 1e-6) { None } else { Some(v) }))
+        .collect::<Result<Vec<_>, _>>()?;
+    for fila in numeros.chunks(columnas) {
+        filas.push(fila.to_vec());
+    }
+    Ok(filas.into_iter().rev().flatten().collect())
+}
+
+// Grilla ASCII de ESRI. Con xllcorner las celdas se toman por su centro.
+pub fn leer_ascii(archivo: &Path) -> Result<Geoide, anyhow::Error> {
+    let contenido = fs::read_to_string(archivo)?;
+    let lineas: Vec<&str> = contenido.lines().filter(|l| !l.trim().is_empty()).collect();
+    let (enc, datos) = encabezado_y_datos(&lineas, None);
+
+    let columnas: usize = campo(&enc, "ncols")?;
+    let filas: usize = campo(&enc, "nrows")?;
+    let paso: f64 = campo(&enc, "cellsize")?;
+    let (x, y) = match (enc.contains_key("xllcorner"), enc.contains_key("xllcenter")) {
+        (true, _) => (campo::<f64>(&enc, "xllcorner")? + paso / 2., campo::<f64>(&enc, "yllcorner")? + paso / 2.),
+        (_, true) => (campo(&enc, "xllcenter")?, campo(&enc, "yllcenter")?),
+        _ => return Err(anyhow!("Falta el origen de la grilla ASCII")),
+    };
+    let nulo = campo::<f64>(&enc, "nodata_value").ok();
+
+    Geoide::nuevo(y, x, paso, paso, filas, columnas, valores_de_norte_a_sur(&datos, columnas, nulo)?)
+}
+
+// Formato ISG del International Service for the Geoid, sólo con
+// coordenadas en grados decimales.
+pub fn leer_isg(archivo: &Path) -> Result<Geoide, anyhow::Error> {
+    let contenido = fs::read_to_string(archivo)?;
+    let (cabecera, cuerpo) = contenido.split_once("end_of_head")
+        .ok_or_else(|| anyhow!("ISG sin end_of_head"))?;
+    let cabecera: Vec<&str> = cabecera.lines()
+        .skip_while(|l| !l.starts_with("begin_of_head"))
+        .skip(1)
+        .collect();
+    let (enc, _) = encabezado_y_datos(&cabecera, Some('='));
+    let cuerpo: Vec<&str> = cuerpo.lines().skip(1).filter(|l| !l.trim().is_empty()).collect();
+
+    let filas: usize = campo(&enc, "nrows")?;
+    let columnas: usize = campo(&enc, "ncols")?;
+    let (dlat, dlon): (f64, f64) = (campo(&enc, "delta_lat")?, campo(&enc, "delta_lon")?);
+    let (mut lat, mut lon): (f64, f64) = (campo(&enc, "lat_min")?, campo(&enc, "lon_min")?);
+    // En ISG 2.0 las grillas pueden ser de celdas
+    if enc.get("node_offset").is_some_and(|v| v.trim_start_matches('-').starts_with("half")) {
+        lat += dlat / 2.;
+        lon += dlon / 2.;
+    }
+    let nulo = campo::<f64>(&enc, "nodata").ok();
+
+    Geoide::nuevo(lat, lon, dlat, dlon, filas, columnas, valores_de_norte_a_sur(&cuerpo, columnas, nulo)?)
+}
+
+pub fn leer_geoide(archivo: &Path) -> Result<Geoide, anyhow::Error> {
+    match archivo.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_deref() {
+        Some("gtx") => leer_gtx(archivo),
+        Some("isg") => leer_isg(archivo),
+        Some("asc") | Some("grd") | Some("txt") => leer_ascii(archivo),
+        _ => Err(anyhow!(format!("Formato de geoide desconocido: {}", archivo.display()))),
+    }
+}
+
+// "--Geoid Separation File: None" en el pie del trabajo. Con un archivo de
+// geoide la controladora guarda alturas ortométricas en EL.
+pub fn geoide_de_lineas(lineas: &[&str]) -> Option<String> {
+    lineas.iter().rev()
+        .find_map(|l| l.strip_prefix("--Geoid Separation File:"))
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty() && !v.eq_ignore_ascii_case("none"))
+        .map(|v| v.to_string())
+}
+
+pub fn tipo_de_altura_de_lineas(lineas: &[&str]) -> TipoDeAltura {
+    match geoide_de_lineas(lineas) {
+        Some(_) => TipoDeAltura::Ortometrica,
+        None => TipoDeAltura::Elipsoidal,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    // 3 x 3 nodos de 1°, desde -36,-60. N = 10 + fila + 0.1 columna
+    fn valor_esperado(lat: f64, lon: f64) -> f64 {
+        10. + (lat + 36.) + 0.1 * (lon + 60.)
+    }
+
+    #[test]
+    fn test_leer_gtx() {
+        let mut archivo = tempfile::Builder::new().suffix(".gtx").tempfile().unwrap();
+        for v in [-36f64, -60., 1., 1.] {
+            archivo.write_all(&v.to_be_bytes()).unwrap();
+        }
+        for v in [3i32, 3] {
+            archivo.write_all(&v.to_be_bytes()).unwrap();
+        }
+        for fila in 0..3 {
+            for col in 0..3 {
+                let v = if fila == 2 && col == 2 { -88.8888f32 } else { 10. + fila as f32 + 0.1 * col as f32 };
+                archivo.write_all(&v.to_be_bytes()).unwrap();
+            }
+        }
+        let g = leer_geoide(archivo.path()).unwrap();
+        assert!((g.ondulacion(-35.5, -59.5).unwrap() - valor_esperado(-35.5, -59.5)).abs() < 1e-6);
+        // Longitud en 0..360
+        let g360 = Geoide { longitud_minima: 300., ..g.clone() };
+        assert!((g360.ondulacion(-35.5, -59.5).unwrap() - valor_esperado(-35.5, -59.5)).abs() < 1e-6);
+        // El nodo nulo contamina su celda
+        assert_eq!(g.ondulacion(-34.5, -58.5), None);
+        assert_eq!(g.ondulacion(-37., -59.), None);
+    }
+
+    #[test]
+    fn test_leer_ascii() {
+        let mut archivo = tempfile::Builder::new().suffix(".asc").tempfile().unwrap();
+        write!(archivo, "ncols 3\nnrows 3\nxllcenter -60\nyllcenter -36\ncellsize 1\nNODATA_value -9999\n\
+                         12 12.1 12.2\n11 11.1 11.2\n10 10.1 10.2\n").unwrap();
+        let g = leer_geoide(archivo.path()).unwrap();
+        for (lat, lon) in [(-35.25, -59.75), (-34., -58.), (-36., -60.)] {
+            assert!((g.ondulacion(lat, lon).unwrap() - valor_esperado(lat, lon)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_leer_isg() {
+        let mut archivo = tempfile::Builder::new().suffix(".isg").tempfile().unwrap();
+        write!(archivo, "comentario\nbegin_of_head ================\n\
+                         lat_min = -36.0\nlat_max = -34.0\nlon_min = -60.0\nlon_max = -58.0\n\
+                         delta_lat = 1.0\ndelta_lon = 1.0\nnrows = 3\nncols = 3\nnodata = -9999.0\n\
+                         end_of_head ==================\n\
+                         12 12.1 12.2\n11 11.1 -9999.0\n10 10.1 10.2\n").unwrap();
+        let g = leer_geoide(archivo.path()).unwrap();
+        assert!((g.ondulacion(-35.5, -59.5).unwrap() - valor_esperado(-35.5, -59.5)).abs() < 1e-9);
+        assert_eq!(g.ondulacion(-35.5, -58.5), None);
+
+        let h = g.convertir(-35.5, -59.5, 20., TipoDeAltura::Elipsoidal, TipoDeAltura::Ortometrica).unwrap();
+        assert!((h - (20. - valor_esperado(-35.5, -59.5))).abs() < 1e-9);
+        let v = g.convertir(-35.5, -59.5, h, TipoDeAltura::Ortometrica, TipoDeAltura::Elipsoidal).unwrap();
+        assert!((v - 20.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_geoide_del_trabajo() {
+        assert_eq!(geoide_de_lineas(&["--Geoid Separation File: None"]), None);
+        assert_eq!(tipo_de_altura_de_lineas(&["--Geoid Separation File: None"]), TipoDeAltura::Elipsoidal);
+        let lineas = ["--Geoid Separation File: GEOIDE-Ar16.gtx"];
+        assert_eq!(geoide_de_lineas(&lineas), Some("GEOIDE-Ar16.gtx".to_string()));
+        assert_eq!(tipo_de_altura_de_lineas(&lineas), TipoDeAltura::Ortometrica);
+    }
+}
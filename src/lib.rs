@@ -2,6 +2,29 @@ mod record_parser;
 mod record_parser_gps;
 pub mod file_parser;
 pub mod post_parse_gps;
+pub mod promedios;
+pub mod exportar;
+pub mod tiempo;
+pub mod ocupacion;
+pub mod filtros;
+pub mod proyeccion;
+pub mod crs;
+pub mod verificacion;
+pub mod geoide;
+pub mod geodesia;
+pub mod datum;
+pub mod correccion_base;
+pub mod escala;
+pub mod correcciones;
+pub mod algebra;
+pub mod calibracion;
+pub mod cogo;
+pub mod poligonal;
+pub mod caras;
+pub mod reseccion;
+pub mod puestas;
+pub mod nivelacion;
+pub mod red;
 
 use std::error::Error;
 use std::fs::File;
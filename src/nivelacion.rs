@@ -0,0 +1,322 @@
+use crate::cogo::ReduccionTopografica;
+use anyhow::anyhow;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+// Desnivel de terreno a terreno entre dos puntos, promediando las idas y
+// las vueltas. `desde` < `hasta` para que cada tramo aparezca una vez.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DesnivelPromedio {
+    pub desde: String,
+    pub hasta: String,
+    pub desnivel: f64,
+    pub distancia: f64,
+    pub idas: usize,
+    pub vueltas: usize,
+    // Ida más vuelta: con recíprocas debería ser cero
+    pub diferencia_reciproca: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CierreDeNivelacion {
+    pub puntos: Vec<String>,
+    pub cierre: f64,
+    pub longitud: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CotaDeReferencia {
+    pub punto: String,
+    pub conocida: Option<f64>,
+    // Llevada por la red desde la referencia más cercana, sin usar la propia
+    pub calculada: Option<f64>,
+    pub desde: Option<String>,
+    pub diferencia: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Nivelacion {
+    pub desniveles: Vec<DesnivelPromedio>,
+    pub referencias: BTreeMap<String, f64>,
+}
+
+#[derive(Default)]
+struct Observaciones {
+    idas: Vec<f64>,
+    vueltas: Vec<f64>,
+    distancias: Vec<f64>,
+}
+
+// De un punto a la raíz de su árbol
+fn camino<'a>(padre: &BTreeMap<&'a str, Option<&'a str>>, mut p: &'a str) -> Vec<&'a str> {
+    let mut c = vec![p];
+    while let Some(Some(q)) = padre.get(p) {
+        c.push(q);
+        p = q;
+    }
+    c
+}
+
+impl Nivelacion {
+    // Sólo visuales entre puestas o a puntos de control (SP), que son las
+    // cotas de referencia. Los chequeos atrás cuentan como vuelta cuando la
+    // visual no quedó como punto.
+    pub fn de_reduccion(r: &ReduccionTopografica) -> Self {
+        let estaciones: BTreeSet<&str> = r.estaciones.iter().map(|e| e.nombre.as_str()).collect();
+        let mut observados: Vec<(&str, &str, f64, f64)> = r.puntos.iter()
+            .filter(|p| estaciones.contains(p.nombre.as_str()) || r.control.contains_key(&p.nombre))
+            .map(|p| (p.estacion.as_str(), p.nombre.as_str(), p.desnivel, p.distancia_horizontal))
+            .collect();
+        for c in r.chequeos.iter() {
+            let visto = r.puntos.iter().any(|p| p.puesta == c.puesta && p.nombre == c.atras);
+            if let (false, Some(dh), Some(d)) = (visto, c.desnivel_medido, c.distancia_medida) {
+                observados.push((c.estacion.as_str(), c.atras.as_str(), dh, d));
+            }
+        }
+
+        let mut tramos: BTreeMap<(&str, &str), Observaciones> = BTreeMap::new();
+        for (desde, hasta, dh, d) in observados {
+            if desde == hasta {
+                continue;
+            }
+            let (clave, dh) = if desde < hasta { ((desde, hasta), dh) } else { ((hasta, desde), -dh) };
+            let t = tramos.entry(clave).or_default();
+            if desde < hasta { t.idas.push(dh) } else { t.vueltas.push(dh) }
+            t.distancias.push(d);
+        }
+        let media = |v: &[f64]| v.iter().sum::<f64>() / v.len() as f64;
+        let desniveles = tramos.into_iter().map(|((desde, hasta), Observaciones { idas, vueltas, distancias })| {
+            let todos: Vec<f64> = idas.iter().chain(vueltas.iter()).copied().collect();
+            DesnivelPromedio {
+                desde: desde.to_string(),
+                hasta: hasta.to_string(),
+                // Ida y vuelta pesan lo mismo aunque haya más de una de cada
+                desnivel: match (idas.is_empty(), vueltas.is_empty()) {
+                    (false, false) => (media(&idas) + media(&vueltas)) / 2.,
+                    _ => media(&todos),
+                },
+                distancia: media(&distancias),
+                idas: idas.len(),
+                vueltas: vueltas.len(),
+                // Las vueltas ya están cambiadas de signo
+                diferencia_reciproca: if idas.is_empty() || vueltas.is_empty() { None } else { Some(media(&idas) - media(&vueltas)) },
+            }
+        }).collect();
+        Nivelacion {
+            desniveles,
+            referencias: r.control.iter().map(|(k, v)| (k.clone(), v.2)).collect(),
+        }
+    }
+
+    fn tramo(&self, desde: &str, hasta: &str) -> Option<(f64, f64)> {
+        self.desniveles.iter().find_map(|d| {
+            if d.desde == desde && d.hasta == hasta {
+                Some((d.desnivel, d.distancia))
+            } else if d.desde == hasta && d.hasta == desde {
+                Some((-d.desnivel, d.distancia))
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn desnivel(&self, desde: &str, hasta: &str) -> Option<f64> {
+        self.tramo(desde, hasta).map(|t| t.0)
+    }
+
+    // Suma de desniveles a lo largo de puntos que empiezan y terminan en el
+    // mismo
+    pub fn cierre(&self, puntos: &[&str]) -> Result<CierreDeNivelacion, anyhow::Error> {
+        if puntos.len() < 3 || puntos.first() != puntos.last() {
+            return Err(anyhow!(format!("El lazo {} no cierra", puntos.join("-"))));
+        }
+        let (mut cierre, mut longitud) = (0., 0.);
+        for par in puntos.windows(2) {
+            let (dh, d) = self.tramo(par[0], par[1])
+                .ok_or_else(|| anyhow!(format!("No hay desnivel entre {} y {}", par[0], par[1])))?;
+            cierre += dh;
+            longitud += d;
+        }
+        Ok(CierreDeNivelacion { puntos: puntos.iter().map(|p| p.to_string()).collect(), cierre, longitud })
+    }
+
+    fn vecinos(&self) -> BTreeMap<&str, Vec<&str>> {
+        let mut vecinos: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for d in self.desniveles.iter() {
+            vecinos.entry(&d.desde).or_default().push(&d.hasta);
+            vecinos.entry(&d.hasta).or_default().push(&d.desde);
+        }
+        vecinos
+    }
+
+    // Un lazo por cada tramo que sobra sobre un árbol de la red
+    pub fn lazos(&self) -> Vec<CierreDeNivelacion> {
+        let vecinos = self.vecinos();
+        let mut padre: BTreeMap<&str, Option<&str>> = BTreeMap::new();
+        for raiz in vecinos.keys() {
+            if padre.contains_key(raiz) {
+                continue;
+            }
+            padre.insert(raiz, None);
+            let mut cola = VecDeque::from(vec![*raiz]);
+            while let Some(p) = cola.pop_front() {
+                for v in vecinos[p].iter() {
+                    if !padre.contains_key(v) {
+                        padre.insert(v, Some(p));
+                        cola.push_back(v);
+                    }
+                }
+            }
+        }
+        self.desniveles.iter()
+            .filter(|d| padre[d.desde.as_str()] != Some(d.hasta.as_str()) && padre[d.hasta.as_str()] != Some(d.desde.as_str()))
+            .filter_map(|d| {
+                let (a, b) = (camino(&padre, &d.desde), camino(&padre, &d.hasta));
+                let comun = a.iter().find(|p| b.contains(p))?;
+                let mut lazo: Vec<&str> = a.iter().take_while(|p| *p != comun).copied().collect();
+                lazo.push(comun);
+                let vuelta: Vec<&str> = b.iter().take_while(|p| *p != comun).copied().collect();
+                lazo.extend(vuelta.into_iter().rev());
+                lazo.push(&d.desde);
+                self.cierre(&lazo).ok()
+            })
+            .collect()
+    }
+
+    // Cotas desde las referencias, por el camino con menos tramos
+    fn propagar(&self, fuentes: &[&str]) -> BTreeMap<String, (f64, String)> {
+        let vecinos = self.vecinos();
+        let mut cotas: BTreeMap<String, (f64, String)> = BTreeMap::new();
+        let mut cola = VecDeque::new();
+        for f in fuentes {
+            cotas.insert(f.to_string(), (self.referencias[*f], f.to_string()));
+            cola.push_back(f.to_string());
+        }
+        while let Some(p) = cola.pop_front() {
+            let (cota, origen) = cotas[&p].clone();
+            for v in vecinos.get(p.as_str()).into_iter().flatten() {
+                if !cotas.contains_key(*v) {
+                    cotas.insert(v.to_string(), (cota + self.desnivel(&p, v).unwrap(), origen.clone()));
+                    cola.push_back(v.to_string());
+                }
+            }
+        }
+        cotas
+    }
+
+    // Todos los puntos de la red y las referencias. Cada referencia se
+    // calcula desde las otras para ver si están de acuerdo.
+    pub fn cotas(&self) -> Vec<CotaDeReferencia> {
+        let referencias: Vec<&str> = self.referencias.keys().map(|k| k.as_str()).collect();
+        let desde_todas = self.propagar(&referencias);
+        let mut puntos: BTreeSet<&str> = self.vecinos().keys().copied().collect();
+        puntos.extend(referencias.iter());
+
+        puntos.into_iter().map(|p| {
+            let conocida = self.referencias.get(p).copied();
+            let calculada = match conocida {
+                Some(_) => {
+                    let otras: Vec<&str> = referencias.iter().filter(|r| **r != p).copied().collect();
+                    self.propagar(&otras).remove(p)
+                },
+                None => desde_todas.get(p).cloned(),
+            };
+            CotaDeReferencia {
+                punto: p.to_string(),
+                conocida,
+                diferencia: conocida.zip(calculada.as_ref().map(|c| c.0)).map(|(k, c)| c - k),
+                calculada: calculada.as_ref().map(|c| c.0),
+                desde: calculada.map(|c| c.1),
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cogo::reducir;
+    use crate::file_parser::registros_de_lineas;
+
+    fn nivelacion() -> Nivelacion {
+        let registros = registros_de_lineas(vec![
+            "--SP,PN1,N 0.000,E 0.000,EL100.000,--PP",
+            "--SP,PN3,N 100.000,E 100.000,EL101.205,--PP",
+            "OC,OP1,N 0.00000,E 0.00000,EL100.000",
+            "TR,OP1,FP2,AZ90.0000,CE0.500,HD100.000",
+            "SS,OP1,FP9,AZ45.0000,CE2.000,HD5.000",
+            "OC,OP2,N 0.00000,E 100.00000,EL100.500",
+            "SS,OP2,FP1,AZ270.0000,CE-0.504,HD100.000",
+            "TR,OP2,FP3,AZ0.0000,CE0.700,HD100.000",
+            "OC,OP3,N 100.00000,E 100.00000,EL101.200",
+            "TR,OP3,FP4,AZ270.0000,CE-0.300,HD100.000",
+            "OC,OP4,N 100.00000,E 0.00000,EL100.900",
+            "TR,OP4,FP1,AZ180.0000,CE-0.890,HD100.000",
+        ]);
+        Nivelacion::de_reduccion(&reducir(&registros.iter().collect::<Vec<_>>()))
+    }
+
+    #[test]
+    fn test_reciprocas() {
+        let n = nivelacion();
+        // El 9 es un lateral y no entra
+        assert_eq!(n.desniveles.len(), 4);
+        let d12 = &n.desniveles[0];
+        assert_eq!((d12.idas, d12.vueltas), (1, 1));
+        assert!((d12.desnivel - 0.502).abs() < 1e-9);
+        assert!((d12.diferencia_reciproca.unwrap() + 0.004).abs() < 1e-9);
+        assert!((n.desnivel("4", "1").unwrap() + 0.89).abs() < 1e-9);
+        assert_eq!(n.desniveles[1].diferencia_reciproca, None);
+    }
+
+    #[test]
+    fn test_reciprocas_trigonometricas() {
+        // Ida con cenital y vuelta con ángulo vertical, con alturas distintas
+        // en cada punta
+        let registros = registros_de_lineas(vec![
+            "OC,OP5,N 0.00000,E 0.00000,EL100.000",
+            "LS,HI1.600000,HR1.300000",
+            "TR,OP5,FP6,AZ90.0000,ZE89.3000,SD200.000",
+            "OC,OP6,N 0.00000,E 200.00000,EL102.000",
+            "LS,HI1.450000,HR1.700000",
+            "TR,OP6,FP5,AZ270.0000,VA-0.3000,SD200.000",
+        ]);
+        let n = Nivelacion::de_reduccion(&reducir(&registros.iter().collect::<Vec<_>>()));
+        let d = &n.desniveles[0];
+        assert_eq!((d.idas, d.vueltas), (1, 1));
+        let v = 200. * 0.5f64.to_radians().sin();
+        let (ida, vuelta) = (v + 1.6 - 1.3, -v + 1.45 - 1.7);
+        assert!((d.desnivel - (ida - vuelta) / 2.).abs() < 1e-9);
+        assert!((d.diferencia_reciproca.unwrap() - 0.05).abs() < 1e-9);
+        assert!((d.distancia - 200. * 0.5f64.to_radians().cos()).abs() < 1e-9);
+        assert!((n.desnivel("6", "5").unwrap() + (ida - vuelta) / 2.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_cierre_y_cotas() {
+        let n = nivelacion();
+        let c = n.cierre(&["1", "2", "3", "4", "1"]).unwrap();
+        assert!((c.cierre - 0.012).abs() < 1e-9);
+        assert!((c.longitud - 400.).abs() < 1e-9);
+        assert!(n.cierre(&["1", "2", "3"]).is_err());
+
+        let lazos = n.lazos();
+        assert_eq!(lazos.len(), 1);
+        assert_eq!(lazos[0].puntos.len(), 5);
+        assert!((lazos[0].cierre.abs() - 0.012).abs() < 1e-9);
+
+        let cotas = n.cotas();
+        assert_eq!(cotas.len(), 4);
+        let c3 = cotas.iter().find(|c| c.punto == "3").unwrap();
+        assert_eq!(c3.desde.as_deref(), Some("1"));
+        assert!((c3.diferencia.unwrap() + 0.003).abs() < 1e-9);
+        let c4 = cotas.iter().find(|c| c.punto == "4").unwrap();
+        assert_eq!(c4.conocida, None);
+        assert!((c4.calculada.unwrap() - 100.89).abs() < 1e-9);
+
+        let mut csv = vec![];
+        crate::exportar::cotas_a_csv(&cotas, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().nth(1).unwrap(), "1,100.0000,100.0030,3,0.0030");
+    }
+}
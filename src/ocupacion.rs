@@ -0,0 +1,86 @@
+use crate::record_parser_gps::{GPSRecord, QRecord};
+use rinex::prelude::Duration;
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TipoDeOcupacion {
+    Estatica,
+    StopAndGo,
+    TomaRapida,
+}
+
+impl fmt::Display for TipoDeOcupacion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TipoDeOcupacion::Estatica => write!(f, "ESTATICA"),
+            TipoDeOcupacion::StopAndGo => write!(f, "STOP AND GO"),
+            TipoDeOcupacion::TomaRapida => write!(f, "TOMA RAPIDA"),
+        }
+    }
+}
+
+// Límites para clasificar una ocupación por su duración (fin - inicio del
+// GT) y la cantidad de lecturas válidas del bloque de calidad.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct UmbralesOcupacion {
+    // Desde esta duración la ocupación se puede procesar como estática
+    pub estatica: Duration,
+    // Hasta esta duración es una toma rápida
+    pub toma_rapida: Duration,
+    // Con estas lecturas o menos también es una toma rápida
+    pub lecturas_toma_rapida: u32,
+}
+
+impl Default for UmbralesOcupacion {
+    fn default() -> Self {
+        UmbralesOcupacion {
+            estatica: Duration::from_seconds(300.),
+            toma_rapida: Duration::from_seconds(2.),
+            lecturas_toma_rapida: 1,
+        }
+    }
+}
+
+impl UmbralesOcupacion {
+    // Sin duración ni lecturas no hay con qué clasificar
+    pub fn clasificar(&self, duracion: Option<Duration>, lecturas: Option<u32>) -> Option<TipoDeOcupacion> {
+        match (duracion, lecturas) {
+            (Some(d), _) if d >= self.estatica => Some(TipoDeOcupacion::Estatica),
+            (Some(d), _) if d <= self.toma_rapida => Some(TipoDeOcupacion::TomaRapida),
+            (_, Some(n)) if n <= self.lecturas_toma_rapida => Some(TipoDeOcupacion::TomaRapida),
+            (None, None) => None,
+            _ => Some(TipoDeOcupacion::StopAndGo),
+        }
+    }
+
+    pub fn clasificar_punto(&self, gps: &GPSRecord, calidad: Option<&QRecord>) -> Option<TipoDeOcupacion> {
+        let duracion = match (gps.start_time, gps.end_time) {
+            (Some(inicio), Some(fin)) => Some(fin - inicio),
+            _ => None,
+        };
+        let lecturas = calidad.and_then(|q| q.lecturas_validas).map(|(n, _)| n);
+        self.clasificar(duracion, lecturas)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clasificar() {
+        let u = UmbralesOcupacion::default();
+        let s = Duration::from_seconds;
+
+        assert_eq!(u.clasificar(Some(s(10.)), Some(10)), Some(TipoDeOcupacion::StopAndGo));
+        assert_eq!(u.clasificar(Some(s(1.)), None), Some(TipoDeOcupacion::TomaRapida));
+        assert_eq!(u.clasificar(Some(s(10.)), Some(1)), Some(TipoDeOcupacion::TomaRapida));
+        assert_eq!(u.clasificar(Some(s(1800.)), Some(1800)), Some(TipoDeOcupacion::Estatica));
+        assert_eq!(u.clasificar(None, Some(10)), Some(TipoDeOcupacion::StopAndGo));
+        assert_eq!(u.clasificar(None, None), None);
+
+        let u = UmbralesOcupacion { estatica: s(10.), ..UmbralesOcupacion::default() };
+        assert_eq!(u.clasificar(Some(s(10.)), Some(10)), Some(TipoDeOcupacion::Estatica));
+    }
+}
@@ -0,0 +1,498 @@
+use crate::algebra::Matriz;
+use crate::cogo::{azimut, normalizar, ReduccionTopografica};
+use crate::record_parser::TipoDeVisual;
+use anyhow::anyhow;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub enum MetodoDeAjuste {
+    // Brújula (Bowditch): proporcional a la longitud de cada lado
+    #[default]
+    Brujula,
+    // Tránsito: proporcional a |ΔN| y |ΔE| de cada lado
+    Transito,
+    // Crandall: ángulos fijos, sólo se corrigen las distancias por
+    // mínimos cuadrados con peso inverso a la longitud
+    Crandall,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum TipoDePoligonal {
+    // Vuelve a la estación de partida
+    Cerrada,
+    // Llega a un punto de control distinto del de partida
+    Encuadrada,
+    // Sin punto conocido al final, no hay cierre
+    Abierta,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Lado {
+    pub desde: String,
+    pub hasta: String,
+    // Ángulo medido en la estación desde, de la visual atrás a este lado
+    pub angulo: f64,
+    pub distancia: f64,
+    pub desnivel: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Poligonal {
+    pub tipo: TipoDePoligonal,
+    // Índices de las estaciones recorridas en ReduccionTopografica
+    pub puestas: Vec<usize>,
+    pub lados: Vec<Lado>,
+    pub azimut_atras_inicial: f64,
+    pub inicio: (f64, f64, f64),
+    pub fin_conocido: Option<(f64, f64, f64)>,
+    // Ángulo medido en la estación final, si lo hay, y azimut conocido de
+    // esa dirección
+    pub cierre_angular: Option<(Option<f64>, f64)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Cierre {
+    // Error de cierre angular en grados, medido - conocido
+    pub angular: Option<f64>,
+    pub norte: f64,
+    pub este: f64,
+    pub elevacion: f64,
+    pub lineal: f64,
+    pub perimetro: f64,
+}
+
+impl Cierre {
+    // Denominador de la precisión 1:X
+    pub fn precision(&self) -> f64 {
+        if self.lineal > 0. { self.perimetro / self.lineal } else { f64::INFINITY }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EstacionAjustada {
+    pub puesta: usize,
+    pub nombre: String,
+    pub norte: f64,
+    pub este: f64,
+    pub elevacion: f64,
+    // Azimut atrás ajustado, para reorientar las laterales de la estación
+    pub azimut_atras: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PoligonalAjustada {
+    pub metodo: MetodoDeAjuste,
+    pub cierre: Cierre,
+    pub estaciones: Vec<EstacionAjustada>,
+}
+
+// Encadena estaciones consecutivas en las que la visual TR va a la
+// estación siguiente y ésta mira atrás a la anterior. La cadena termina en
+// el primer TR que no lleva a una estación ocupada, o en una estación sin
+// TR.
+pub fn detectar(reduccion: &ReduccionTopografica) -> Vec<Poligonal> {
+    let mut poligonales = vec![];
+    let estaciones = &reduccion.estaciones;
+    let adelante = |k: usize| reduccion.puntos.iter()
+        .rfind(|p| p.puesta == k && p.tipo == TipoDeVisual::Poligonal);
+
+    let mut k = 0;
+    while k < estaciones.len() {
+        let inicial = &estaciones[k];
+        let (azimut_atras_inicial, primero) = match (inicial.azimut_atras, adelante(k)) {
+            (Some(az), Some(p)) => (az, p),
+            _ => { k += 1; continue; }
+        };
+
+        let mut puestas = vec![];
+        let mut lados = vec![];
+        let mut actual = k;
+        let mut visual = primero;
+        let fin = loop {
+            let e = &estaciones[actual];
+            puestas.push(actual);
+            lados.push(Lado {
+                desde: e.nombre.clone(),
+                hasta: visual.nombre.clone(),
+                angulo: (visual.azimut - e.azimut_atras.unwrap_or(0.)).rem_euclid(360.),
+                distancia: visual.distancia_horizontal,
+                desnivel: visual.desnivel,
+            });
+            let siguiente = estaciones.get(actual + 1)
+                .filter(|s| s.nombre == visual.nombre && s.atras.as_deref() == Some(e.nombre.as_str()));
+            match siguiente {
+                Some(s) => match adelante(actual + 1) {
+                    Some(v) if s.azimut_atras.is_some() => { actual += 1; visual = v; },
+                    _ => break Some(actual + 1),
+                },
+                None => break None,
+            }
+        };
+
+        let nombre_fin = lados.last().map(|l| l.hasta.clone()).unwrap_or_default();
+        let inicio = (inicial.norte, inicial.este, inicial.elevacion);
+        let (tipo, fin_conocido) = if nombre_fin == inicial.nombre {
+            (TipoDePoligonal::Cerrada, Some(inicio))
+        } else if let Some(c) = reduccion.control.get(&nombre_fin) {
+            (TipoDePoligonal::Encuadrada, Some(*c))
+        } else {
+            (TipoDePoligonal::Abierta, None)
+        };
+
+        // Orientación de cierre: en la cerrada, si la estación de partida
+        // miró atrás a la última, o si al final se volvió a ocupar la
+        // partida y se visó el punto atrás original; en la encuadrada, una
+        // visual desde la estación final ocupada a un punto de control.
+        let cierre_angular = match (tipo, fin, fin_conocido) {
+            (TipoDePoligonal::Cerrada, _, _) if inicial.atras.as_deref() == lados.last().map(|l| l.desde.as_str()) =>
+                Some((None, azimut_atras_inicial)),
+            (TipoDePoligonal::Cerrada, Some(f), _) => {
+                let e = &estaciones[f];
+                reduccion.puntos.iter()
+                    .filter(|p| p.puesta == f)
+                    .find(|p| inicial.atras.as_deref() == Some(p.nombre.as_str()))
+                    .and_then(|p| e.azimut_atras.map(|az| (Some((p.azimut - az).rem_euclid(360.)), azimut_atras_inicial)))
+            },
+            (TipoDePoligonal::Encuadrada, Some(f), Some(c)) => {
+                let e = &estaciones[f];
+                reduccion.puntos.iter()
+                    .filter(|p| p.puesta == f)
+                    .find_map(|p| reduccion.control.get(&p.nombre).map(|r| (p, r)))
+                    .and_then(|(p, r)| e.azimut_atras.map(|az| (Some((p.azimut - az).rem_euclid(360.)), azimut((c.0, c.1), (r.0, r.1)))))
+            },
+            _ => None,
+        };
+        if let Some(f) = fin {
+            puestas.push(f);
+        }
+
+        k = puestas.last().map(|u| u + 1).unwrap_or(k + 1);
+        poligonales.push(Poligonal { tipo, puestas, lados, azimut_atras_inicial, inicio, fin_conocido, cierre_angular });
+    }
+    poligonales
+}
+
+impl Poligonal {
+    fn azimuts(&self, correccion_angular: f64) -> Vec<f64> {
+        let mut atras = self.azimut_atras_inicial;
+        self.lados.iter()
+            .map(|l| {
+                let az = (atras + l.angulo + correccion_angular).rem_euclid(360.);
+                atras = az + 180.;
+                az
+            })
+            .collect()
+    }
+
+    fn cantidad_de_angulos(&self) -> usize {
+        self.lados.len() + usize::from(matches!(self.cierre_angular, Some((Some(_), _))))
+    }
+
+    // Error angular sin corregir, en grados
+    pub fn cierre_angular(&self) -> Option<f64> {
+        let (angulo, conocido) = self.cierre_angular?;
+        let ultimo = *self.azimuts(0.).last()?;
+        Some(normalizar(ultimo + 180. + angulo.unwrap_or(0.) - conocido))
+    }
+
+    // Corrección por ángulo que reparte el error angular en partes iguales
+    fn correccion_angular(&self) -> f64 {
+        self.cierre_angular().map(|w| -w / self.cantidad_de_angulos() as f64).unwrap_or(0.)
+    }
+
+    fn proyecciones(&self, azimuts: &[f64]) -> Vec<(f64, f64, f64)> {
+        self.lados.iter().zip(azimuts)
+            .map(|(l, az)| {
+                let (s, c) = az.to_radians().sin_cos();
+                (l.distancia * c, l.distancia * s, l.desnivel)
+            })
+            .collect()
+    }
+
+    // Cierre con los ángulos ya corregidos
+    pub fn cierre(&self) -> Option<Cierre> {
+        let fin = self.fin_conocido?;
+        let proyecciones = self.proyecciones(&self.azimuts(self.correccion_angular()));
+        let norte = self.inicio.0 + proyecciones.iter().map(|p| p.0).sum::<f64>() - fin.0;
+        let este = self.inicio.1 + proyecciones.iter().map(|p| p.1).sum::<f64>() - fin.1;
+        let elevacion = self.inicio.2 + proyecciones.iter().map(|p| p.2).sum::<f64>() - fin.2;
+        Some(Cierre {
+            angular: self.cierre_angular(),
+            norte,
+            este,
+            elevacion,
+            lineal: norte.hypot(este),
+            perimetro: self.lados.iter().map(|l| l.distancia).sum(),
+        })
+    }
+
+    pub fn ajustar(&self, metodo: MetodoDeAjuste) -> Result<PoligonalAjustada, anyhow::Error> {
+        let cierre = self.cierre()
+            .ok_or_else(|| anyhow!("La poligonal es abierta, no hay cierre que repartir"))?;
+        let correccion = self.correccion_angular();
+        let azimuts = self.azimuts(correccion);
+        let proyecciones = self.proyecciones(&azimuts);
+        let perimetro = cierre.perimetro;
+
+        let correcciones: Vec<(f64, f64)> = match metodo {
+            MetodoDeAjuste::Brujula => self.lados.iter()
+                .map(|l| (-cierre.norte * l.distancia / perimetro, -cierre.este * l.distancia / perimetro))
+                .collect(),
+            MetodoDeAjuste::Transito => {
+                let suma_n: f64 = proyecciones.iter().map(|p| p.0.abs()).sum();
+                let suma_e: f64 = proyecciones.iter().map(|p| p.1.abs()).sum();
+                proyecciones.iter()
+                    .map(|p| (-cierre.norte * p.0.abs() / suma_n.max(f64::EPSILON),
+                              -cierre.este * p.1.abs() / suma_e.max(f64::EPSILON)))
+                    .collect()
+            },
+            MetodoDeAjuste::Crandall => {
+                // δL = L (cos az λ1 + sen az λ2), con Σ δL (cos az, sen az) = -cierre
+                let mut n = Matriz::ceros(2, 2);
+                for (l, az) in self.lados.iter().zip(azimuts.iter()) {
+                    let (s, c) = az.to_radians().sin_cos();
+                    n[(0, 0)] += l.distancia * c * c;
+                    n[(0, 1)] += l.distancia * c * s;
+                    n[(1, 1)] += l.distancia * s * s;
+                }
+                n[(1, 0)] = n[(0, 1)];
+                let lambda = n.resolver(&[-cierre.norte, -cierre.este])?;
+                self.lados.iter().zip(azimuts.iter())
+                    .map(|(l, az)| {
+                        let (s, c) = az.to_radians().sin_cos();
+                        let dl = l.distancia * (c * lambda[0] + s * lambda[1]);
+                        (dl * c, dl * s)
+                    })
+                    .collect()
+            },
+        };
+
+        let mut estaciones = vec![];
+        let (mut n, mut e, mut z) = self.inicio;
+        let mut atras = self.azimut_atras_inicial;
+        for (i, (lado, (p, c))) in self.lados.iter().zip(proyecciones.iter().zip(correcciones.iter())).enumerate() {
+            if let Some(puesta) = self.puestas.get(i) {
+                estaciones.push(EstacionAjustada { puesta: *puesta, nombre: lado.desde.clone(), norte: n, este: e, elevacion: z, azimut_atras: atras });
+            }
+            n += p.0 + c.0;
+            e += p.1 + c.1;
+            z += p.2 - cierre.elevacion * lado.distancia / perimetro;
+            atras = (azimuts[i] + 180.).rem_euclid(360.);
+        }
+        // La estación final ocupada, si la hay
+        if let (Some(puesta), Some(ultimo)) = (self.puestas.get(self.lados.len()), self.lados.last()) {
+            estaciones.push(EstacionAjustada { puesta: *puesta, nombre: ultimo.hasta.clone(), norte: n, este: e, elevacion: z, azimut_atras: atras });
+        }
+
+        Ok(PoligonalAjustada { metodo, cierre, estaciones })
+    }
+}
+
+impl ReduccionTopografica {
+    // Mueve las estaciones de la poligonal a sus coordenadas ajustadas y
+    // recalcula desde ellas las visuales, reorientadas con el azimut atrás
+    // ajustado.
+    pub fn aplicar_poligonal(&mut self, ajustada: &PoligonalAjustada) {
+        for a in ajustada.estaciones.iter() {
+            let estacion = &mut self.estaciones[a.puesta];
+            let giro = a.azimut_atras - estacion.azimut_atras.unwrap_or(a.azimut_atras);
+            estacion.norte = a.norte;
+            estacion.este = a.este;
+            estacion.elevacion = a.elevacion;
+            estacion.azimut_atras = Some(a.azimut_atras);
+            self.conocidos.insert(a.nombre.clone(), (a.norte, a.este, a.elevacion));
+
+            for p in self.puntos.iter_mut().filter(|p| p.puesta == a.puesta) {
+                p.azimut = (p.azimut + giro).rem_euclid(360.);
+                let (s, c) = p.azimut.to_radians().sin_cos();
+                p.norte = a.norte + p.distancia_horizontal * c;
+                p.este = a.este + p.distancia_horizontal * s;
+                p.elevacion = a.elevacion + p.desnivel;
+            }
+        }
+        // Las visuales de la poligonal caen en las estaciones ajustadas
+        for p in self.puntos.iter_mut().filter(|p| p.tipo == TipoDeVisual::Poligonal) {
+            if let Some(a) = ajustada.estaciones.iter().find(|a| a.nombre == p.nombre) {
+                p.norte = a.norte;
+                p.este = a.este;
+                p.elevacion = a.elevacion;
+            }
+        }
+        for p in self.puntos.iter() {
+            self.conocidos.insert(p.nombre.clone(), (p.norte, p.este, p.elevacion));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cogo::reducir;
+    use crate::file_parser::registros_de_lineas;
+
+    fn reducir_lineas(lineas: Vec<&str>) -> ReduccionTopografica {
+        let registros = registros_de_lineas(lineas);
+        reducir(&registros.iter().collect::<Vec<_>>())
+    }
+
+    // Cuadrado de 100 m recorrido en sentido antihorario mirando a la
+    // derecha: 270° en cada vértice. Hay 20" de más en la estación 2 y
+    // 2 cm de más en el segundo lado.
+    fn cuadrado() -> ReduccionTopografica {
+        reducir_lineas(vec![
+            "OC,OP1,N 1000.000,E 1000.000,EL100.000",
+            "BK,OP1,BP4,BS90.0000,BC0.0000",
+            "LS,HI1.500000,HR1.500000",
+            "SS,OP1,FP50,AR45.0000,ZE90.0000,HD10.000",
+            "TR,OP1,FP2,AR270.0000,ZE90.0000,HD100.000",
+            "OC,OP2,N 1100.000,E 1000.000,EL100.000",
+            "BK,OP2,BP1,BS180.0000,BC0.0000",
+            "TR,OP2,FP3,AR270.0020,ZE90.0000,HD100.020",
+            "OC,OP3,N 1100.000,E 1100.000,EL100.000",
+            "BK,OP3,BP2,BS270.0000,BC0.0000",
+            "SS,OP3,FP51,AR0.0000,ZE90.0000,HD10.000",
+            "TR,OP3,FP4,AR270.0000,ZE90.0000,HD100.000",
+            "OC,OP4,N 1000.000,E 1100.000,EL100.000",
+            "BK,OP4,BP3,BS0.0000,BC0.0000",
+            "TR,OP4,FP1,AR270.0000,ZE90.0000,HD100.000",
+        ])
+    }
+
+    #[test]
+    fn test_cierre_poligonal_cerrada() {
+        let r = cuadrado();
+        let poligonales = detectar(&r);
+        assert_eq!(poligonales.len(), 1);
+        let p = &poligonales[0];
+        assert_eq!(p.tipo, TipoDePoligonal::Cerrada);
+        assert_eq!(p.lados.len(), 4);
+        assert_eq!(p.puestas, vec![0, 1, 2, 3]);
+
+        let cierre = p.cierre().unwrap();
+        assert!((cierre.angular.unwrap() * 3600. - 20.).abs() < 1e-6);
+        assert!((cierre.perimetro - 400.02).abs() < 1e-9);
+        // Repartir los 20" gira los lados: los 2 cm del segundo lado quedan
+        // en 15 mm al este y aparecen 5 mm al sur
+        assert!((cierre.este - 0.015152).abs() < 1e-5 && (cierre.norte + 0.004849).abs() < 1e-5);
+        assert!((cierre.precision() - 400.02 / cierre.lineal).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_cierre_reocupando_la_partida() {
+        // El mismo cuadrado, con la partida orientada a 9 al sur. Al final
+        // se vuelve a 1 y se cierra el ángulo contra 9.
+        let r = reducir_lineas(vec![
+            "OC,OP1,N 1000.000,E 1000.000,EL100.000",
+            "BK,OP1,BP9,BS180.0000,BC0.0000",
+            "LS,HI1.500000,HR1.500000",
+            "TR,OP1,FP2,AR180.0000,ZE90.0000,HD100.000",
+            "OC,OP2,N 1100.000,E 1000.000,EL100.000",
+            "BK,OP2,BP1,BS180.0000,BC0.0000",
+            "TR,OP2,FP3,AR270.0020,ZE90.0000,HD100.020",
+            "OC,OP3,N 1100.000,E 1100.000,EL100.000",
+            "BK,OP3,BP2,BS270.0000,BC0.0000",
+            "TR,OP3,FP4,AR270.0000,ZE90.0000,HD100.000",
+            "OC,OP4,N 1000.000,E 1100.000,EL100.000",
+            "BK,OP4,BP3,BS0.0000,BC0.0000",
+            "TR,OP4,FP1,AR270.0000,ZE90.0000,HD100.000",
+            "OC,OP1,N 1000.000,E 1000.000,EL100.000",
+            "BK,OP1,BP4,BS90.0000,BC0.0000",
+            "SS,OP1,FP9,AR90.0000,ZE90.0000,HD100.000",
+        ]);
+        let p = &detectar(&r)[0];
+        assert_eq!(p.tipo, TipoDePoligonal::Cerrada);
+        assert_eq!(p.puestas, vec![0, 1, 2, 3, 4]);
+        // Cinco ángulos: el de partida, tres vértices y el de cierre
+        assert_eq!(p.cantidad_de_angulos(), 5);
+        let cierre = p.cierre().unwrap();
+        assert!((cierre.angular.unwrap() * 3600. - 20.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_ajustes() {
+        let r = cuadrado();
+        let p = &detectar(&r)[0];
+        for metodo in [MetodoDeAjuste::Brujula, MetodoDeAjuste::Transito, MetodoDeAjuste::Crandall] {
+            let a = p.ajustar(metodo).unwrap();
+            assert_eq!(a.estaciones.len(), 4);
+            // La partida no se mueve
+            assert_eq!((a.estaciones[0].norte, a.estaciones[0].este), (1000., 1000.));
+            let e3 = &a.estaciones[2];
+            assert!((e3.norte - 1100.).abs() < 0.005 && (e3.este - 1100.).abs() < 0.01, "{:?} {:?}", metodo, e3);
+        }
+        // Brújula reparte por longitud; tránsito carga el error este en los
+        // lados que avanzan al este
+        let b = p.ajustar(MetodoDeAjuste::Brujula).unwrap();
+        let t = p.ajustar(MetodoDeAjuste::Transito).unwrap();
+        assert!((b.estaciones[1].este - t.estaciones[1].este).abs() > 1e-3);
+        assert!((t.estaciones[1].este - 1000. + 0.00242).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_aplicar_poligonal() {
+        let mut r = cuadrado();
+        let p = detectar(&r).remove(0);
+        let a = p.ajustar(MetodoDeAjuste::Brujula).unwrap();
+        r.aplicar_poligonal(&a);
+
+        assert_eq!(r.estaciones[2].norte, a.estaciones[2].norte);
+        // La lateral desde 3, al oeste, se mueve con la estación
+        let p51 = r.puntos.iter().find(|p| p.nombre == "51").unwrap();
+        assert!((p51.norte - a.estaciones[2].norte).abs() < 1e-3);
+        assert!((p51.este - a.estaciones[2].este + 10.).abs() < 1e-3);
+        assert_eq!(r.conocidos["51"], (p51.norte, p51.este, p51.elevacion));
+        // La de la partida no
+        let p50 = r.puntos.iter().find(|p| p.nombre == "50").unwrap();
+        assert!((p50.norte - (1000. + 10. * 135f64.to_radians().cos())).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_poligonal_encuadrada() {
+        let r = reducir_lineas(vec![
+            "--SP,PN10,N 0.0000,E 0.0000,EL50.000",
+            "--SP,PN11,N -100.0000,E 0.0000,EL50.000",
+            "--SP,PN13,N 70.7107,E 170.7107,EL50.000",
+            "--SP,PN14,N 170.7107,E 170.7107,EL50.000",
+            "OC,OP10,N 0.0000,E 0.0000,EL50.000",
+            "BK,OP10,BP11,BS180.0000,BC0.0000",
+            "LS,HI1.600000,HR1.600000",
+            "TR,OP10,FP12,AR225.0000,ZE90.0000,HD100.000",
+            "OC,OP12,N 70.7107,E 70.7107,EL50.000",
+            "BK,OP12,BP10,BS225.0000,BC0.0000",
+            "TR,OP12,FP13,AR225.0000,ZE90.0000,HD100.030",
+            "OC,OP13,N 70.7107,E 170.7107,EL50.000",
+            "BK,OP13,BP12,BS270.0000,BC0.0000",
+            "SS,OP13,FP14,AR90.0010,ZE90.0000,HD100.000",
+        ]);
+        let poligonales = detectar(&r);
+        assert_eq!(poligonales.len(), 1);
+        let p = &poligonales[0];
+        assert_eq!(p.tipo, TipoDePoligonal::Encuadrada);
+        assert_eq!(p.puestas, vec![0, 1, 2]);
+
+        let cierre = p.cierre().unwrap();
+        assert!((cierre.angular.unwrap() * 3600. - 10.).abs() < 1e-6);
+        assert!((cierre.este - 0.029).abs() < 1e-3 && (cierre.norte - 0.0044).abs() < 1e-3);
+
+        // Cualquier método cierra en el punto de control
+        for metodo in [MetodoDeAjuste::Brujula, MetodoDeAjuste::Transito, MetodoDeAjuste::Crandall] {
+            let a = p.ajustar(metodo).unwrap();
+            let fin = a.estaciones.last().unwrap();
+            assert_eq!(fin.nombre, "13");
+            assert!((fin.norte - 70.7107).abs() < 1e-6 && (fin.este - 170.7107).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_poligonal_abierta() {
+        let r = reducir_lineas(vec![
+            "OC,OP1,N 0.000,E 0.000,EL0.000",
+            "BK,OP1,BP9,BS0.0000,BC0.0000",
+            "TR,OP1,FP2,AR90.0000,ZE90.0000,HD10.000",
+        ]);
+        let p = &detectar(&r)[0];
+        assert_eq!(p.tipo, TipoDePoligonal::Abierta);
+        assert_eq!(p.cierre(), None);
+        assert!(p.ajustar(MetodoDeAjuste::Brujula).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{file_parser::Record, record_parser::TRecord, record_parser_gps::{ATRecord, BPRecord, GPSRecord, TipoDeReceptor}};
+use crate::{calibracion::{Calibracion, CoordenadaLocal}, escala::{self, FactoresDeEscala}, file_parser::Record, filtros::FiltroCalidad, geodesia::{self, LineaBase}, geoide::{Geoide, TipoDeAltura}, ocupacion::{TipoDeOcupacion, UmbralesOcupacion}, promedios::{self, PuntoPromediado}, proyeccion::{Elipsoide, Geodesicas, TransversaMercator}, record_parser_gps::{ATRecord, BPRecord, GPSRecord, QRecord, TipoDeReceptor}, tiempo::{self, DesfasajeReloj, HusoHorario, Interpolacion}, verificacion::{self, ResiduoGrilla}};
 use chrono::NaiveDateTime;
 use itertools::Itertools;
 use std::{collections::{BTreeMap, HashMap}, convert::TryInto};
@@ -48,7 +48,9 @@ pub fn combinar_registros(registros: Vec<Record>) -> Vec<Record> {
                     (Record::BP(g), Record::GS(p)) => genera_rama!(g, p, Record::BP, skip),
                     (Record::AT(g), Record::LS(p)) => genera_rama!(g, p, Record::AT, skip),
                     (Record::EH(g), Record::AT(p)) => genera_rama!(g, p, Record::AT, skip),
+                    (Record::Q(g), Record::Q(p)) => genera_rama!(g, p, Record::Q, skip),
                     (Record::T(g), Record::T(p)) => genera_rama!(g, p, Record::T, skip),
+                    (Record::OF(g), Record::SS(p)) => genera_rama!(g, p, Record::SS, skip),
                     _ => Some(t.0),
                 }
             }
@@ -63,6 +65,14 @@ pub struct RelevamientoGNSS<'a> {
     antenas_b: BTreeMap<Epoch, &'a ATRecord>,
     antenas_r: BTreeMap<Epoch, &'a ATRecord>,
     puntos: BTreeMap<Epoch, (&'a GPSRecord,&'a ATRecord)>,
+    calidades: BTreeMap<Epoch, &'a QRecord>,
+    // Para llevar la hora DT/TM de la controladora a una época
+    huso: HusoHorario,
+    interpolacion: Interpolacion,
+    umbrales: UmbralesOcupacion,
+    // Qué guardó la controladora en EL, según el pie del trabajo
+    altura: TipoDeAltura,
+    geoide: Option<Geoide>,
 }
 
 impl <'a> RelevamientoGNSS<'a> {
@@ -75,34 +85,77 @@ impl <'a> RelevamientoGNSS<'a> {
             antenas_b: BTreeMap::default(),
             antenas_r: BTreeMap::default(),
             puntos: BTreeMap::default(),
+            calidades: BTreeMap::default(),
+            huso: HusoHorario::default(),
+            interpolacion: Interpolacion::default(),
+            umbrales: UmbralesOcupacion::default(),
+            altura: TipoDeAltura::default(),
+            geoide: None,
             }
     }
 
+    // Infiere el huso de la controladora comparando DT/TM con GT y lo
+    // aplica a los registros que sólo tienen DT/TM (bases y cambios de
+    // antena). Hay que llamarlo antes de consolidar.
+    pub fn estimar_reloj(&mut self) -> Option<DesfasajeReloj> {
+        let estimado = tiempo::estimar_desfasaje(&self.records);
+        if let Some(d) = estimado {
+            self.huso = d.huso();
+        }
+        estimado
+    }
+
+    pub fn con_huso(&mut self, huso: HusoHorario) -> &Self {
+        self.huso = huso;
+        self
+    }
+
+    pub fn huso(&self) -> HusoHorario {
+        self.huso
+    }
+
+    pub fn con_interpolacion(&mut self, interpolacion: Interpolacion) -> &Self {
+        self.interpolacion = interpolacion;
+        self
+    }
+
+    fn epocas(&self) -> Vec<Option<Epoch>> {
+        tiempo::resolver_epocas(&self.records, &self.huso, self.interpolacion)
+    }
+
+    // Bases, cambios de antena y puntos a los que no se les pudo asignar
+    // una hora. No entran en el relevamiento consolidado.
+    pub fn sin_hora(&self) -> Vec<&'a Record> {
+        self.records.iter().zip(self.epocas())
+            .filter(|(r, e)| e.is_none() && matches!(r, Record::AT(_) | Record::BP(_) | Record::GPS(_)))
+            .map(|(r, _)| *r)
+            .collect()
+    }
+
     pub fn consolidar_antenas(&mut self) -> &Self
     {
         let mut antena_b = &ATRecord::default();
         let mut antena_r = &ATRecord::default();
-        let mut reloj = &TRecord::default();
+        let epocas = self.epocas();
 
-        for r in self.records.iter() {
+        for (r, epoca) in self.records.iter().zip(epocas) {
             match r {
-                Record::T(rr) => {
-                    reloj = rr;
-                    },
                 Record::AT(rr) => {
+                    let epoca = match (rr.modo, epoca) {
+                        (None, _) => continue,
+                        (Some(_), Some(e)) => e,
+                        (Some(_), None) => {eprintln!("Cambio de antena sin hora {}, saltea", rr.tipo);
+                        continue;},
+                    };
                     match rr.modo {
                         Some(TipoDeReceptor::Base) => if rr != antena_b
                         {
-                            self.antenas_b.insert(
-                                reloj.clone().get_epoch(),
-                                rr);
+                            self.antenas_b.insert(epoca, rr);
                             antena_b = rr;
                         },
                         Some(TipoDeReceptor::Rotador) => if rr != antena_r
                         {
-                            self.antenas_r.insert(
-                                reloj.clone().get_epoch(),
-                                rr);
+                            self.antenas_r.insert(epoca, rr);
                             antena_r = rr;
                         },
                         _ => ()
@@ -117,18 +170,20 @@ impl <'a> RelevamientoGNSS<'a> {
     pub fn consolidar_bases(&mut self) -> &Self {
         let mut ultima_base = &BPRecord::default();
         let mut ultima_antena = &ATRecord::default();
-        let mut reloj = &TRecord::default();
+        let epocas = self.epocas();
 
-        for r in self.records.iter() {
+        for (r, epoca) in self.records.iter().zip(epocas) {
             match r {
-                Record::T(rr) => {
-                    reloj = rr;
-                },
                 Record::BP(bp) => {
+                    let epoca = match epoca {
+                        None => {eprintln!("Base sin hora {}, saltea", bp.occupy_point);
+                        continue;},
+                        Some(e) => e
+                    };
                     // Buscar la antena vigente en el BTreeMap antenas_b
-                    if let Some((_, antena)) = self.antenas_b.range(..=reloj.get_epoch()).last() {
+                    if let Some((_, antena)) = self.antenas_b.range(..=epoca).last() {
                         if bp != ultima_base || antena != &ultima_antena {
-                            self.bases.insert(reloj.get_epoch(), (bp, antena));
+                            self.bases.insert(epoca, (bp, antena));
                             ultima_base = bp;
                             ultima_antena = antena;
                         }
@@ -142,15 +197,27 @@ impl <'a> RelevamientoGNSS<'a> {
 
     pub fn consolidar_puntos(&mut self) -> &Self {
 
-        let mut reloj = &TRecord::default();
+        let epocas = self.epocas();
+        // El bloque de calidad viene después del GPS y su DT/TM
+        let mut ultimo: Option<Epoch> = None;
+        // Fin de la ocupación anterior. Las bases no cuentan: su DT/TM puede
+        // ser posterior al inicio del punto siguiente.
+        let mut fin_anterior: Option<Epoch> = None;
 
-        for r in self.records.iter() {
+        for (r, epoca) in self.records.iter().zip(epocas) {
             match r {
-                Record::T(rr) => {
-                    reloj = rr;
+                Record::Q(q) => {
+                    if let Some(k) = ultimo.take() {
+                        self.calidades.insert(k, q);
+                    }
                 },
                 Record::GPS(gps) => {
-                    let start_time = gps.start_time.unwrap_or(reloj.get_epoch()); 
+                    ultimo = None;
+                    let start_time = match gps.start_time.or(epoca) {
+                        None => {eprintln!("Punto sin hora {}, saltea", gps.occupy_point);
+                        continue;},
+                        Some(e) => e
+                    };
                     // Buscar la antena vigente en el BTreeMap antenas_r
                     let antena = match self.antenas_r.range(..=start_time).last() {
                         None => {eprintln!("Sin antena definida {}, saltea",start_time);
@@ -158,12 +225,14 @@ impl <'a> RelevamientoGNSS<'a> {
                         Some((_,a)) => a
                     };
 
-                    if start_time < reloj.get_epoch() {
+                    if fin_anterior.is_some_and(|f| start_time < f) {
                         eprintln!("Tiempo en reversa {}, saltea",start_time);
                         continue;
                     }
 
                     self.puntos.insert(start_time, (gps, antena));
+                    ultimo = Some(start_time);
+                    fin_anterior = Some(gps.end_time.unwrap_or(start_time));
                 },
                 _ => ()
             }
@@ -171,14 +240,141 @@ impl <'a> RelevamientoGNSS<'a> {
         self
     }
 
+    pub fn calidad(&self, epoca: &Epoch) -> Option<&'a QRecord> {
+        self.calidades.get(epoca).copied()
+    }
+
+    pub fn con_umbrales(&mut self, umbrales: UmbralesOcupacion) -> &Self {
+        self.umbrales = umbrales;
+        self
+    }
+
+    pub fn ocupacion(&self, epoca: &Epoch) -> Option<TipoDeOcupacion> {
+        let (gps, _) = self.puntos.get(epoca)?;
+        self.umbrales.clasificar_punto(gps, self.calidad(epoca))
+    }
+
+    // Puntos con su tipo de ocupación, en orden de inicio
+    pub fn ocupaciones(&self) -> Vec<(Epoch, &'a GPSRecord, Option<TipoDeOcupacion>)> {
+        self.puntos.iter()
+            .map(|(k,(p,_))| (*k, *p, self.ocupacion(k)))
+            .collect()
+    }
+
+    // Ocupaciones suficientemente largas para procesarlas como estáticas
+    pub fn estaticas(&self) -> Vec<(Epoch, &'a GPSRecord)> {
+        self.ocupaciones().into_iter()
+            .filter(|(_,_,t)| *t == Some(TipoDeOcupacion::Estatica))
+            .map(|(k,p,_)| (k,p))
+            .collect()
+    }
+
+    // Saca del relevamiento los puntos que no cumplen el predicado, antes
+    // de exportarlos o pasarlos a eventos. Devuelve cuántos se sacaron.
+    pub fn filtrar<F>(&mut self, predicado: F) -> usize
+    where F: Fn(&GPSRecord, Option<&QRecord>) -> bool
+    {
+        let antes = self.puntos.len();
+        let calidades = &self.calidades;
+        self.puntos.retain(|k, (p, _)| predicado(p, calidades.get(k).copied()));
+        antes - self.puntos.len()
+    }
+
+    pub fn filtrar_calidad(&mut self, filtro: &FiltroCalidad) -> usize {
+        self.filtrar(|_, q| filtro.acepta(q))
+    }
+
+    pub fn con_altura(&mut self, altura: TipoDeAltura) -> &Self {
+        self.altura = altura;
+        self
+    }
+
+    pub fn con_geoide(&mut self, geoide: Geoide) -> &Self {
+        self.geoide = Some(geoide);
+        self
+    }
+
+    pub fn tipo_de_altura(&self) -> TipoDeAltura {
+        self.altura
+    }
+
+    // EL del registro llevada a `tipo`. None si hace falta el geoide y no
+    // está cargado o el punto cae fuera de él.
+    pub fn altura_de<G: Geodesicas>(&self, registro: &G, tipo: TipoDeAltura) -> Option<f64> {
+        let (latitud, longitud, elevacion) = registro.geodesicas();
+        if tipo == self.altura {
+            return Some(elevacion);
+        }
+        self.geoide.as_ref()?.convertir(latitud, longitud, elevacion, self.altura, tipo)
+    }
+
+    // Base vigente al inicio de cada punto
+    pub fn base_de(&self, epoca: &Epoch) -> Option<&'a BPRecord> {
+        self.bases.range(..=epoca).last().map(|(_, (b, _))| *b)
+    }
+
+    // Vector desde la base vigente a cada punto, sobre el elipsoide del
+    // trabajo (el de la proyección del Crs)
+    pub fn lineas_base(&self, elipsoide: &Elipsoide) -> Vec<(Epoch, LineaBase)> {
+        self.puntos.iter()
+            .filter_map(|(k, (p, _))| {
+                let base = self.base_de(k)?;
+                Some((*k, geodesia::linea_base(elipsoide, base, *p)))
+            })
+            .collect()
+    }
+
+    // Escala, elevación y convergencia en cada punto, con altura elipsoidal
+    pub fn factores_de_escala(&self, proyeccion: &TransversaMercator) -> Vec<(Epoch, FactoresDeEscala)> {
+        self.puntos.iter()
+            .map(|(k, (p, _))| {
+                let altura = self.altura_de(*p, TipoDeAltura::Elipsoidal).unwrap_or(p.elevation);
+                (*k, escala::factores(proyeccion, *p, altura))
+            })
+            .collect()
+    }
+
+    // Todos los puntos en el sistema local de una calibración
+    pub fn coordenadas_locales(&self, calibracion: &Calibracion) -> Vec<(Epoch, CoordenadaLocal)> {
+        self.puntos.iter()
+            .map(|(k, (p, _))| (*k, calibracion.aplicar(*p)))
+            .collect()
+    }
+
+    // Compara el --GS de bases y puntos con LA/LN proyectadas
+    pub fn verificar_grilla(&self, proyeccion: &TransversaMercator) -> Vec<ResiduoGrilla> {
+        let bases = self.bases.values()
+            .filter_map(|(b,_)| verificacion::residuo_grilla(proyeccion, *b));
+        let puntos = self.puntos.values()
+            .filter_map(|(p,_)| verificacion::residuo_grilla(proyeccion, *p));
+        bases.chain(puntos).collect()
+    }
+
+    // Promedio ponderado de los puntos medidos más de una vez. Las tomas
+    // con residuo estandarizado mayor a `umbral` se marcan como atípicas.
+    pub fn promediar_puntos(&self, umbral: f64) -> Vec<PuntoPromediado<'a>> {
+        let tomas = self.puntos.iter()
+            .map(|(k,(p,_))| (*k, *p, self.calidad(k)))
+            .collect();
+        let mut promedios = promedios::promediar(tomas, umbral);
+        promedios.iter_mut().for_each(|p| p.tipo_altura = self.altura);
+        promedios
+    }
+
     pub fn puntos_a_eventos(&mut self) -> rxevent::Record {
         self.puntos.iter().map(|(k,(p,a))| {
             let marker = GeodeticMarker::default();
-            let pos = GroundPosition::from_geodetic((p.latitude,p.longitude,p.elevation));
+            // El RINEX lleva alturas elipsoidales
+            let h = self.altura_de(*p, TipoDeAltura::Elipsoidal).unwrap_or(p.elevation);
+            let pos = GroundPosition::from_geodetic((p.latitude,p.longitude,h));
             let ant = Antenna::default();
+            let comments = match self.ocupacion(k) {
+                Some(t) => vec![format!("OCUPACION {}", t)],
+                None => vec![],
+            };
 
             let ev_info = rxevent::Event {
-                    comments: vec![],
+                    comments,
                     geodetic_marker: Some(
                         marker.with_name(p.occupy_point.as_str()
                             )),
@@ -194,14 +390,30 @@ impl <'a> RelevamientoGNSS<'a> {
     }
 }
 
-pub fn registros_a_eventos(registros_gps: Vec<Record>) -> rxevent::Record
+// `altura` es la que indica el pie del trabajo (geoide::tipo_de_altura_de_lineas)
+pub fn registros_a_eventos(registros_gps: Vec<Record>, filtro: &FiltroCalidad, altura: TipoDeAltura) -> rxevent::Record
 {
 
         let mut rel = RelevamientoGNSS::new(&registros_gps);
+        rel.con_altura(altura);
+        if altura != TipoDeAltura::Elipsoidal {
+            eprintln!("El trabajo tiene alturas {:?} y no hay geoide: los eventos las llevan sin convertir", altura);
+        }
+        if let Some(d) = rel.estimar_reloj() {
+            let huso = d.huso();
+            eprintln!("Desfasaje GT - DT/TM: {} (mediana de {} pares, entre {} y {})",
+                      d.desfasaje, d.pares, d.minimo, d.maximo);
+            eprintln!("Hora de la controladora: UTC{:+} en escala {:?}",
+                      huso.utc_offset.to_seconds() / 3600., huso.escala);
+        }
         rel.consolidar_antenas();
         rel.consolidar_bases();
         rel.consolidar_puntos();
-        
+        let descartados = rel.filtrar_calidad(filtro);
+        if descartados > 0 {
+            eprintln!("{} puntos no pasan el filtro de calidad", descartados);
+        }
+
         let evt_record = rel.puntos_a_eventos();
         evt_record
 }
@@ -213,9 +425,17 @@ mod test {
 
     use rinex::{observation::HeaderFields, record, writer::BufferedWriter};
 
-    use crate::{file_parser::{leer_archivo_y_parsear, Record}, post_parse_gps::{combinar_registros, gps_gs_gt_3}};
+    use rinex::prelude::{Duration, Epoch, TimeScale};
+
+    use crate::{file_parser::{de_archivo_a_registros, leer_archivo_y_parsear, lineas_a_registros, Record}, post_parse_gps::{combinar_registros, gps_gs_gt_3}};
 
     use super::RelevamientoGNSS;
+    use crate::tiempo::Interpolacion;
+    use crate::ocupacion::{TipoDeOcupacion, UmbralesOcupacion};
+    use crate::filtros::FiltroCalidad;
+    use crate::crs::leer_crs;
+    use crate::verificacion::resumir;
+    use crate::geoide::{leer_geoide, TipoDeAltura};
 
     #[test]
     fn test_gps_gs_gt_3()
@@ -282,6 +502,12 @@ mod test {
             _ => None
         }).collect::<Vec<_>>().len(), n_bp );
 
+        // 17 lineas por bloque promediado, una por toma rápida
+        assert_eq!(registros_gps.clone().iter().filter_map(|r| match r {
+            Record::Q(_) => Some(1),
+            _ => None
+        }).collect::<Vec<_>>().len(), 486 * 17 + 69 );
+
         while largo_n != largo_p
         {
             largo_p = largo_n;
@@ -327,8 +553,14 @@ mod test {
             _ => None
         }).collect::<Vec<_>>().len(), 0 );
 
-        // una antena por cada punto y cada base
-        assert_eq!(registros_gps.len(), 3 * (n_bp + n_gps));
+        assert_eq!(registros_gps.clone().iter().filter_map(|r| match r {
+            Record::Q(_) => Some(1),
+            _ => None
+        }).collect::<Vec<_>>().len(), n_gps );
+
+        // una antena por cada punto y cada base, un bloque de calidad por
+        // punto y el MO del trabajo
+        assert_eq!(registros_gps.len(), 3 * (n_bp + n_gps) + n_gps + 1);
 
 
     }
@@ -442,6 +674,226 @@ mod test {
         println!("{:?}",rel.puntos);
     }
 
+    #[test]
+    fn test_estimar_reloj()
+    {
+        let registros_gps = de_archivo_a_registros(std::path::Path::new("tests/test.rw5"));
+        let mut rel = RelevamientoGNSS::new(&registros_gps);
+
+        let d = rel.estimar_reloj().unwrap();
+        assert_eq!(d.pares, 555);
+
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+
+        // Base del 12-11-2021 13:10:56 hora local, hora GPS en UTC-3
+        let (primera, _) = rel.bases.iter().next().unwrap();
+        let esperada = Epoch::from_gregorian_utc(2021, 11, 12, 16, 10, 38, 0);
+        assert_eq!(*primera, esperada);
+        assert_eq!(primera.time_scale, TimeScale::GPST);
+        assert_eq!(rel.puntos.len(), 555);
+        assert!(rel.sin_hora().is_empty());
+
+        let mut rel = RelevamientoGNSS::new(&registros_gps);
+        rel.estimar_reloj();
+        rel.con_interpolacion(Interpolacion::EntreGT);
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+        assert_eq!(rel.puntos.len(), 555);
+    }
+
+    #[test]
+    fn test_ocupaciones()
+    {
+        let registros_gps = de_archivo_a_registros(std::path::Path::new("tests/test.rw5"));
+        let mut rel = RelevamientoGNSS::new(&registros_gps);
+        rel.estimar_reloj();
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+
+        let ocupaciones = rel.ocupaciones();
+        let cuenta = |t: TipoDeOcupacion| ocupaciones.iter().filter(|o| o.2 == Some(t)).count();
+        assert_eq!(cuenta(TipoDeOcupacion::StopAndGo), 486);
+        assert_eq!(cuenta(TipoDeOcupacion::TomaRapida), 69);
+        assert!(rel.estaticas().is_empty());
+
+        rel.con_umbrales(UmbralesOcupacion { estatica: Duration::from_seconds(5.), ..UmbralesOcupacion::default() });
+        // Hay cuatro ocupaciones promediadas de menos de 5 s
+        assert_eq!(rel.estaticas().len(), 482);
+
+        let eventos = rel.puntos_a_eventos();
+        let (_, (_, ev)) = eventos.iter().next().unwrap();
+        assert!(ev.comments[0].starts_with("OCUPACION "));
+    }
+
+    #[test]
+    fn test_filtrar()
+    {
+        let registros_gps = de_archivo_a_registros(std::path::Path::new("tests/test.rw5"));
+        let mut rel = RelevamientoGNSS::new(&registros_gps);
+        rel.estimar_reloj();
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+
+        // Una toma rápida flotante y tres promedios con lecturas no fijas
+        assert_eq!(rel.filtrar_calidad(&FiltroCalidad::fijas()), 4);
+        assert_eq!(rel.puntos.len(), 551);
+
+        // PN58 se midió dos veces
+        assert_eq!(rel.filtrar(|p, _| p.occupy_point != "58"), 2);
+        assert_eq!(rel.puntos.len(), 549);
+    }
+
+    #[test]
+    fn test_verificar_grilla()
+    {
+        let archivo = std::path::Path::new("tests/test.rw5");
+        let registros_gps = de_archivo_a_registros(archivo);
+        let mut rel = RelevamientoGNSS::new(&registros_gps);
+        rel.estimar_reloj();
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+
+        let crs = leer_crs(archivo).unwrap().unwrap();
+        let residuos = rel.verificar_grilla(&crs.proyeccion);
+        assert_eq!(residuos.len(), rel.bases.len() + rel.puntos.len());
+
+        // El GS de la controladora no es GK 58.5W puro: hay una
+        // localización que corre todo el trabajo unos 16.6 km al oeste
+        let resumen = resumir(&residuos, 0.01).unwrap();
+        assert_eq!(resumen.fuera_de_tolerancia, residuos.len());
+        assert!(resumen.medio_este < -16_000.);
+    }
+
+    #[test]
+    fn test_alturas()
+    {
+        let registros_gps = de_archivo_a_registros(std::path::Path::new("tests/test.rw5"));
+        let mut rel = RelevamientoGNSS::new(&registros_gps);
+        rel.estimar_reloj();
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+        let p = rel.puntos.values().next().unwrap().0;
+
+        assert_eq!(rel.tipo_de_altura(), TipoDeAltura::Elipsoidal);
+        assert_eq!(rel.altura_de(p, TipoDeAltura::Elipsoidal), Some(p.elevation));
+        assert_eq!(rel.altura_de(p, TipoDeAltura::Ortometrica), None);
+
+        // Geoide constante de 15 m alrededor del trabajo
+        let mut archivo = tempfile::Builder::new().suffix(".asc").tempfile().unwrap();
+        write!(archivo, "ncols 2\nnrows 2\nxllcenter -59\nyllcenter -36\ncellsize 1\n15 15\n15 15\n").unwrap();
+        rel.con_geoide(leer_geoide(archivo.path()).unwrap());
+        let h = rel.altura_de(p, TipoDeAltura::Ortometrica).unwrap();
+        assert!((h - (p.elevation - 15.)).abs() < 1e-9);
+
+        let mut promedios = rel.promediar_puntos(3.);
+        let e = promedios[0].elevacion;
+        promedios[0].convertir_altura(TipoDeAltura::Ortometrica, &leer_geoide(archivo.path()).unwrap()).unwrap();
+        assert_eq!(promedios[0].tipo_altura, TipoDeAltura::Ortometrica);
+        assert!((promedios[0].elevacion - (e - 15.)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_lineas_base()
+    {
+        let registros_gps = de_archivo_a_registros(std::path::Path::new("tests/test.rw5"));
+        let mut rel = RelevamientoGNSS::new(&registros_gps);
+        rel.estimar_reloj();
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+
+        let crs = leer_crs(std::path::Path::new("tests/test.rw5")).unwrap().unwrap();
+        let lineas = rel.lineas_base(&crs.proyeccion.elipsoide);
+        assert_eq!(lineas.len(), rel.puntos.len());
+        // Un relevamiento RTK de campo: todas las líneas son cortas
+        assert!(lineas.iter().all(|(_, l)| l.longitud < 10_000.));
+
+        // Las mismas LA/LN sobre otro elipsoide son otro vector
+        let inchauspe = rel.lineas_base(&crate::proyeccion::INTERNACIONAL_1924);
+        assert!(lineas.iter().zip(inchauspe.iter()).any(|((_, a), (_, b))| (a.dz - b.dz).abs() > 1e-4));
+    }
+
+    #[test]
+    fn test_factores_de_escala() {
+        let registros = de_archivo_a_registros(std::path::Path::new("tests/test.rw5"));
+        let mut rel = RelevamientoGNSS::new(&registros);
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+
+        let crs = leer_crs(std::path::Path::new("tests/test.rw5")).unwrap().unwrap();
+        let factores = rel.factores_de_escala(&crs.proyeccion);
+        assert_eq!(factores.len(), rel.puntos.len());
+        // Trabajo chico, cerca del nivel del mar y a unos 20 km del meridiano central
+        let f = crate::escala::factor_del_trabajo(&factores.into_iter().map(|(_, f)| f).collect::<Vec<_>>()).unwrap();
+        assert!((f - 1.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_coordenadas_locales() {
+        use crate::calibracion::{calibrar, emparejar, PuntoDeControl};
+
+        let registros = de_archivo_a_registros(std::path::Path::new("tests/test.rw5"));
+        let mut rel = RelevamientoGNSS::new(&registros);
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+        let crs = leer_crs(std::path::Path::new("tests/test.rw5")).unwrap().unwrap();
+
+        // El --GS no sirve de sistema local: cada sesión de base tiene su
+        // propio corrimiento. Se inventa uno con giro y escala conocidos.
+        let local = |lat: f64, lon: f64| {
+            let (n, e) = crs.proyeccion.a_plano(lat, lon);
+            let (c, s) = (0.99 * 1.2f64.to_radians().cos(), 0.99 * 1.2f64.to_radians().sin());
+            (2000. + s * (e - 521_000.) + c * (n - 6_124_900.), 3000. + c * (e - 521_000.) - s * (n - 6_124_900.))
+        };
+        let promedios = rel.promediar_puntos(3.);
+        let control: Vec<PuntoDeControl> = promedios.iter().step_by(50)
+            .map(|p| {
+                let (norte, este) = local(p.latitud, p.longitud);
+                PuntoDeControl { nombre: p.nombre.clone(), norte, este, elevacion: p.elevacion + 16.3 }
+            })
+            .collect();
+        let pares = emparejar(&control, &promedios);
+        let cal = calibrar(&crs.proyeccion, &pares).unwrap();
+        assert!((cal.horizontal.escala() - 0.99).abs() < 1e-9);
+        assert!(cal.residuos(&pares).iter().all(|r| r.horizontal() < 1e-4 && r.residuo_elevacion.abs() < 1e-4));
+
+        let locales = rel.coordenadas_locales(&cal);
+        assert_eq!(locales.len(), rel.puntos.len());
+        for (k, l) in locales.iter() {
+            let p = rel.puntos[k].0;
+            let (n, e) = local(p.latitude, p.longitude);
+            assert!((n - l.norte).hypot(e - l.este) < 1e-3);
+            assert!((p.elevation + 16.3 - l.elevacion).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_registros_sin_hora()
+    {
+        let registros = lineas_a_registros(vec![
+            "--Antenna Type: [HX-CSX049A],RA0.0645m,SHMP0.0925m,L10.0260m,L20.0222m,--",
+            "GPS,PN1,LA-35.02154763,LN-58.26577623,EL1.244110,--esq",
+        ]).unwrap().registros;
+        let registros = combinar_registros(registros);
+        let mut rel = RelevamientoGNSS::new(&registros);
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+
+        assert_eq!(rel.sin_hora().len(), 2);
+        assert!(rel.antenas_r.is_empty());
+        assert!(rel.puntos.is_empty());
+    }
+
 
     use tempfile::NamedTempFile;
     use std::io::Write;
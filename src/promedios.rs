@@ -0,0 +1,259 @@
+use crate::geoide::{Geoide, TipoDeAltura};
+use crate::record_parser_gps::{GPSRecord, QRecord};
+use anyhow::anyhow;
+use rinex::prelude::Epoch;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// Piso para los desvíos, hay bloques con SD 0.0000 que tendrían peso infinito.
+pub const DESVIO_MINIMO: f64 = 0.001;
+// Desvío que se asume para tomas sin bloque de calidad.
+pub const DESVIO_POR_DEFECTO: f64 = 0.02;
+
+const RADIO_MEDIO: f64 = 6_371_000.;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Toma<'a> {
+    pub epoca: Epoch,
+    pub registro: &'a GPSRecord,
+    // (norte, este, elevación) en metros
+    pub desvios: (f64, f64, f64),
+    pub residuos: (f64, f64, f64),
+    pub atipica: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PuntoPromediado<'a> {
+    pub nombre: String,
+    pub latitud: f64,
+    pub longitud: f64,
+    pub elevacion: f64,
+    pub norte: Option<f64>,
+    pub este: Option<f64>,
+    pub desvio_norte: f64,
+    pub desvio_este: f64,
+    pub desvio_elevacion: f64,
+    pub tipo_altura: TipoDeAltura,
+    pub tomas: Vec<Toma<'a>>,
+}
+
+impl PuntoPromediado<'_> {
+    pub fn atipicas(&self) -> usize {
+        self.tomas.iter().filter(|t| t.atipica).count()
+    }
+
+    pub fn convertir_altura(&mut self, a: TipoDeAltura, geoide: &Geoide) -> Result<(), anyhow::Error> {
+        self.elevacion = geoide.convertir(self.latitud, self.longitud, self.elevacion, self.tipo_altura, a)
+            .ok_or_else(|| anyhow!(format!("Punto {} fuera del geoide", self.nombre)))?;
+        self.tipo_altura = a;
+        Ok(())
+    }
+}
+
+// La controladora agrega "(1)", "(2)"... al repetir un nombre de punto.
+pub fn nombre_base(nombre: &str) -> &str {
+    match nombre.strip_suffix(')').and_then(|s| s.rsplit_once('(')) {
+        Some((base, n)) if !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()) => base,
+        _ => nombre,
+    }
+}
+
+fn metros_por_grado(latitud: f64) -> (f64, f64) {
+    let m = RADIO_MEDIO * std::f64::consts::PI / 180.;
+    (m, m * latitud.to_radians().cos())
+}
+
+// Media pesada de un eje, devuelve (media, desvío de la media)
+fn media_pesada(valores: &[(f64, f64)]) -> (f64, f64) {
+    let (sw, swx) = valores.iter()
+        .fold((0., 0.), |(sw, swx), (x, s)| (sw + 1. / (s * s), swx + x / (s * s)));
+    (swx / sw, (1. / sw).sqrt())
+}
+
+struct Media {
+    latitud: (f64, f64),
+    longitud: (f64, f64),
+    elevacion: (f64, f64),
+    norte: Option<f64>,
+    este: Option<f64>,
+}
+
+impl Media {
+    fn de(tomas: &[&Toma]) -> Media {
+        let eje = |f: &dyn Fn(&Toma) -> (f64, f64)| {
+            media_pesada(&tomas.iter().map(|t| f(t)).collect::<Vec<_>>())
+        };
+        let grilla = tomas.iter().all(|t| t.registro.north.is_some() && t.registro.east.is_some());
+        Media {
+            latitud: eje(&|t| (t.registro.latitude, t.desvios.0)),
+            longitud: eje(&|t| (t.registro.longitude, t.desvios.1)),
+            elevacion: eje(&|t| (t.registro.elevation, t.desvios.2)),
+            norte: if grilla { Some(eje(&|t| (t.registro.north.unwrap_or_default(), t.desvios.0)).0) } else { None },
+            este: if grilla { Some(eje(&|t| (t.registro.east.unwrap_or_default(), t.desvios.1)).0) } else { None },
+        }
+    }
+
+    fn residuos(&self, t: &Toma) -> (f64, f64, f64) {
+        let (mn, me) = metros_por_grado(self.latitud.0);
+        ((t.registro.latitude - self.latitud.0) * mn,
+         (t.registro.longitude - self.longitud.0) * me,
+         t.registro.elevation - self.elevacion.0)
+    }
+
+    // Mayor residuo estandarizado de la toma, contra la varianza del
+    // residuo de una media pesada (s² - sm²).
+    fn estandarizado(&self, t: &Toma) -> f64 {
+        let (mn, me) = metros_por_grado(self.latitud.0);
+        let r = self.residuos(t);
+        let z = |r: f64, s: f64, sm: f64| {
+            let v = s * s - sm * sm;
+            if v > 0. { r.abs() / v.sqrt() } else { 0. }
+        };
+        z(r.0, t.desvios.0, self.latitud.1 * mn)
+            .max(z(r.1, t.desvios.1, self.longitud.1 * me))
+            .max(z(r.2, t.desvios.2, self.elevacion.1))
+    }
+}
+
+fn promediar_tomas<'a>(nombre: &str, mut tomas: Vec<Toma<'a>>, umbral: f64) -> PuntoPromediado<'a> {
+    // Se descarta de a una la toma con mayor residuo estandarizado.
+    // Con dos tomas incompatibles no se sabe cuál es la mala: se marcan
+    // ambas y la media queda con las dos.
+    loop {
+        let usadas: Vec<&Toma> = tomas.iter().filter(|t| !t.atipica).collect();
+        let n_usadas = usadas.len();
+        let media = Media::de(&usadas);
+        let peor = tomas.iter().enumerate()
+            .filter(|(_, t)| !t.atipica)
+            .map(|(i, t)| (i, media.estandarizado(t)))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+
+        match peor {
+            Some((i, z)) if z > umbral && n_usadas > 2 => tomas[i].atipica = true,
+            Some((_, z)) if z > umbral => {
+                tomas.iter_mut().for_each(|t| t.atipica = true);
+                break;
+            },
+            _ => break,
+        }
+    }
+
+    let usadas: Vec<&Toma> = if tomas.iter().all(|t| t.atipica) {
+        tomas.iter().collect()
+    } else {
+        tomas.iter().filter(|t| !t.atipica).collect()
+    };
+    let media = Media::de(&usadas);
+    for t in tomas.iter_mut() {
+        t.residuos = media.residuos(t);
+    }
+    let (mn, me) = metros_por_grado(media.latitud.0);
+
+    PuntoPromediado {
+        nombre: nombre.to_string(),
+        latitud: media.latitud.0,
+        longitud: media.longitud.0,
+        elevacion: media.elevacion.0,
+        norte: media.norte,
+        este: media.este,
+        desvio_norte: media.latitud.1 * mn,
+        desvio_este: media.longitud.1 * me,
+        desvio_elevacion: media.elevacion.1,
+        tipo_altura: TipoDeAltura::default(),
+        tomas,
+    }
+}
+
+// Agrupa las tomas por nombre de punto y promedia cada grupo. Los puntos
+// medidos una sola vez quedan con su única toma.
+pub fn promediar<'a>(tomas: Vec<(Epoch, &'a GPSRecord, Option<&'a QRecord>)>, umbral: f64) -> Vec<PuntoPromediado<'a>> {
+    let mut grupos: BTreeMap<String, Vec<Toma<'a>>> = BTreeMap::new();
+
+    for (epoca, registro, calidad) in tomas {
+        let (sn, se, su) = calidad
+            .and_then(|q| q.desvios())
+            .unwrap_or((DESVIO_POR_DEFECTO, DESVIO_POR_DEFECTO, DESVIO_POR_DEFECTO));
+        grupos.entry(nombre_base(&registro.occupy_point).to_string())
+            .or_default()
+            .push(Toma {
+                epoca,
+                registro,
+                desvios: (sn.max(DESVIO_MINIMO), se.max(DESVIO_MINIMO), su.max(DESVIO_MINIMO)),
+                residuos: (0., 0., 0.),
+                atipica: false,
+            });
+    }
+
+    grupos.into_iter()
+        .map(|(nombre, tomas)| promediar_tomas(&nombre, tomas, umbral))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::record_parser_gps::{parse_gps_record, parse_quality_record};
+
+    fn gps(linea: &str) -> GPSRecord {
+        parse_gps_record(linea).unwrap()
+    }
+
+    #[test]
+    fn test_nombre_base() {
+        assert_eq!(nombre_base("base1(1)"), "base1");
+        assert_eq!(nombre_base("base1"), "base1");
+        assert_eq!(nombre_base("casa(a)"), "casa(a)");
+    }
+
+    #[test]
+    fn test_promedio_pesado() {
+        let p1 = gps("GPS,PN58,LA-35.02171400,LN-58.27046700,EL1.000,--casa4");
+        let p2 = gps("GPS,PN58(1),LA-35.02171410,LN-58.27046710,EL1.030,--casa4");
+        let q1 = parse_quality_record("--HSDV:0.0100, VSDV:0.0100, STATUS:FIJO, NSDV:0.0100, ESDV:0.0100").unwrap();
+        let q2 = parse_quality_record("--HSDV:0.0200, VSDV:0.0200, STATUS:FIJO, NSDV:0.0200, ESDV:0.0200").unwrap();
+        let t0 = Epoch::from_gregorian_utc(2022, 4, 12, 19, 0, 0, 0);
+
+        let r = promediar(vec![(t0, &p1, Some(&q1)), (t0, &p2, Some(&q2))], 3.);
+
+        assert_eq!(r.len(), 1);
+        let p = &r[0];
+        assert_eq!(p.nombre, "58");
+        assert_eq!(p.tomas.len(), 2);
+        assert_eq!(p.atipicas(), 0);
+        // pesos 4:1
+        assert!((p.elevacion - 1.006).abs() < 1e-9);
+        assert!((p.desvio_elevacion - (1f64 / (1. / 1e-4 + 1. / 4e-4)).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_promedio_con_atipica() {
+        let p1 = gps("GPS,PN7,LA-35.0,LN-58.0,EL1.000,--");
+        let p2 = gps("GPS,PN7,LA-35.0,LN-58.0,EL1.002,--");
+        let p3 = gps("GPS,PN7,LA-35.0,LN-58.0,EL1.004,--");
+        let p4 = gps("GPS,PN7,LA-35.0,LN-58.0,EL1.500,--");
+        let q = parse_quality_record("--HSDV:0.0100, VSDV:0.0100, NSDV:0.0100, ESDV:0.0100").unwrap();
+        let t0 = Epoch::from_gregorian_utc(2022, 4, 12, 19, 0, 0, 0);
+
+        let r = promediar(vec![(t0, &p1, Some(&q)), (t0, &p2, Some(&q)),
+                               (t0, &p3, Some(&q)), (t0, &p4, Some(&q))], 3.);
+
+        let p = &r[0];
+        assert_eq!(p.atipicas(), 1);
+        assert!(p.tomas[3].residuos.2 > 0.49);
+        assert!(p.tomas[3].atipica);
+        assert!((p.elevacion - 1.002).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_dos_tomas_incompatibles() {
+        let p1 = gps("GPS,PN58,LA-35.02171418,LN-58.2704674,EL0.721919,--casa4");
+        let p2 = gps("GPS,PN58,LA-35.0217149,LN-58.27046732,EL0.533261,--casa4");
+        let q = parse_quality_record("--HSDV:0.0100, VSDV:0.0100, NSDV:0.0100, ESDV:0.0100").unwrap();
+        let t0 = Epoch::from_gregorian_utc(2022, 4, 12, 19, 0, 0, 0);
+
+        let r = promediar(vec![(t0, &p1, Some(&q)), (t0, &p2, Some(&q))], 3.);
+
+        assert_eq!(r[0].atipicas(), 2);
+        assert!((r[0].elevacion - 0.62759).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,389 @@
+use crate::promedios::PuntoPromediado;
+use crate::record_parser_gps::{BPRecord, GPSRecord, GSRecord};
+use anyhow::anyhow;
+use serde::Serialize;
+use std::f64::consts::FRAC_PI_2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Elipsoide {
+    pub nombre: &'static str,
+    // Semieje mayor en metros
+    pub a: f64,
+    // Inversa del achatamiento
+    pub inv_f: f64,
+}
+
+pub const GRS80: Elipsoide = Elipsoide { nombre: "GRS80", a: 6_378_137., inv_f: 298.257_222_101 };
+pub const WGS84: Elipsoide = Elipsoide { nombre: "WGS84", a: 6_378_137., inv_f: 298.257_223_563 };
+pub const INTERNACIONAL_1924: Elipsoide = Elipsoide { nombre: "International 1924", a: 6_378_388., inv_f: 297. };
+
+impl Elipsoide {
+    pub fn f(&self) -> f64 {
+        1. / self.inv_f
+    }
+
+    pub fn b(&self) -> f64 {
+        self.a * (1. - self.f())
+    }
+
+    // Primera excentricidad al cuadrado
+    pub fn e2(&self) -> f64 {
+        self.f() * (2. - self.f())
+    }
+
+    // Tercer achatamiento, el parámetro de las series de Krüger
+    pub fn n(&self) -> f64 {
+        self.f() / (2. - self.f())
+    }
+
+    // Media geométrica de los radios de curvatura del meridiano y del
+    // primer vertical a una latitud en grados
+    pub fn radio_medio(&self, latitud: f64) -> f64 {
+        let w2 = 1. - self.e2() * latitud.to_radians().sin().powi(2);
+        self.a * (1. - self.e2()).sqrt() / w2
+    }
+
+    pub fn por_nombre(nombre: &str) -> Option<Elipsoide> {
+        match nombre.to_uppercase().replace([' ', '-'], "").as_str() {
+            "GRS80" | "GRS1980" => Some(GRS80),
+            "WGS84" | "WGS1984" => Some(WGS84),
+            "INTERNATIONAL1924" | "INTERNACIONAL1924" | "HAYFORD" | "INTL" => Some(INTERNACIONAL_1924),
+            _ => None,
+        }
+    }
+}
+
+// Transversa Mercator con las series de Krüger a sexto orden en n
+// (Karney 2011), con error submilimétrico dentro de unos 4000 km del
+// meridiano central. Ángulos en grados, distancias en metros.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TransversaMercator {
+    pub elipsoide: Elipsoide,
+    pub meridiano_central: f64,
+    pub latitud_origen: f64,
+    pub escala: f64,
+    pub falso_este: f64,
+    pub falso_norte: f64,
+}
+
+// Lo que se necesita de un registro para proyectarlo
+pub trait Geodesicas {
+    fn nombre(&self) -> &str;
+    fn geodesicas(&self) -> (f64, f64, f64);
+    // (norte, este) del --GS, si lo hubo
+    fn grilla(&self) -> Option<(f64, f64)>;
+    // EL del --GS
+    fn elevacion_grilla(&self) -> Option<f64> {
+        None
+    }
+}
+
+impl Geodesicas for GPSRecord {
+    fn nombre(&self) -> &str {
+        &self.occupy_point
+    }
+    fn geodesicas(&self) -> (f64, f64, f64) {
+        (self.latitude, self.longitude, self.elevation)
+    }
+    fn grilla(&self) -> Option<(f64, f64)> {
+        self.north.zip(self.east)
+    }
+    fn elevacion_grilla(&self) -> Option<f64> {
+        self.elevation_alt
+    }
+}
+
+impl Geodesicas for BPRecord {
+    fn nombre(&self) -> &str {
+        &self.occupy_point
+    }
+    fn geodesicas(&self) -> (f64, f64, f64) {
+        (self.latitude, self.longitude, self.elevation)
+    }
+    fn grilla(&self) -> Option<(f64, f64)> {
+        self.north.zip(self.east)
+    }
+    fn elevacion_grilla(&self) -> Option<f64> {
+        self.elevation_alt
+    }
+}
+
+impl Geodesicas for PuntoPromediado<'_> {
+    fn nombre(&self) -> &str {
+        &self.nombre
+    }
+    fn geodesicas(&self) -> (f64, f64, f64) {
+        (self.latitud, self.longitud, self.elevacion)
+    }
+    fn grilla(&self) -> Option<(f64, f64)> {
+        self.norte.zip(self.este)
+    }
+}
+
+fn alfa(n: f64) -> [f64; 6] {
+    let n2 = n * n;
+    let n3 = n2 * n;
+    let n4 = n3 * n;
+    let n5 = n4 * n;
+    let n6 = n5 * n;
+    [
+        n / 2. - 2. * n2 / 3. + 5. * n3 / 16. + 41. * n4 / 180. - 127. * n5 / 288. + 7891. * n6 / 37800.,
+        13. * n2 / 48. - 3. * n3 / 5. + 557. * n4 / 1440. + 281. * n5 / 630. - 1983433. * n6 / 1935360.,
+        61. * n3 / 240. - 103. * n4 / 140. + 15061. * n5 / 26880. + 167603. * n6 / 181440.,
+        49561. * n4 / 161280. - 179. * n5 / 168. + 6601661. * n6 / 7257600.,
+        34729. * n5 / 80640. - 3418889. * n6 / 1995840.,
+        212378941. * n6 / 319334400.,
+    ]
+}
+
+fn beta(n: f64) -> [f64; 6] {
+    let n2 = n * n;
+    let n3 = n2 * n;
+    let n4 = n3 * n;
+    let n5 = n4 * n;
+    let n6 = n5 * n;
+    [
+        n / 2. - 2. * n2 / 3. + 37. * n3 / 96. - n4 / 360. - 81. * n5 / 512. + 96199. * n6 / 604800.,
+        n2 / 48. + n3 / 15. - 437. * n4 / 1440. + 46. * n5 / 105. - 1118711. * n6 / 3870720.,
+        17. * n3 / 480. - 37. * n4 / 840. - 209. * n5 / 4480. + 5569. * n6 / 90720.,
+        4397. * n4 / 161280. - 11. * n5 / 504. - 830251. * n6 / 7257600.,
+        4583. * n5 / 161280. - 108847. * n6 / 3991680.,
+        20648693. * n6 / 638668800.,
+    ]
+}
+
+impl TransversaMercator {
+    pub fn new(elipsoide: Elipsoide, meridiano_central: f64, escala: f64, falso_este: f64, falso_norte: f64) -> Self {
+        TransversaMercator { elipsoide, meridiano_central, latitud_origen: 0., escala, falso_este, falso_norte }
+    }
+
+    // Gauss-Krüger argentino: fajas de 3° numeradas desde el meridiano 72°O,
+    // origen de latitudes en el polo sur y el número de faja en el falso este.
+    pub fn faja_argentina(faja: u8, elipsoide: Elipsoide) -> Result<Self, anyhow::Error> {
+        if !(1..=7).contains(&faja) {
+            return Err(anyhow!(format!("Faja Gauss-Krüger inexistente: {}", faja)));
+        }
+        Ok(TransversaMercator {
+            elipsoide,
+            meridiano_central: -72. + 3. * (faja as f64 - 1.),
+            latitud_origen: -90.,
+            escala: 1.,
+            falso_este: faja as f64 * 1e6 + 500_000.,
+            falso_norte: 0.,
+        })
+    }
+
+    pub fn utm(zona: u8, sur: bool) -> Result<Self, anyhow::Error> {
+        if !(1..=60).contains(&zona) {
+            return Err(anyhow!(format!("Zona UTM inexistente: {}", zona)));
+        }
+        Ok(TransversaMercator::new(
+            WGS84, -183. + 6. * zona as f64, 0.9996, 500_000., if sur { 10_000_000. } else { 0. }))
+    }
+
+    // Radio rectificante: longitud de un cuadrante de meridiano sobre π/2
+    fn radio_rectificante(&self) -> f64 {
+        let n = self.elipsoide.n();
+        let n2 = n * n;
+        self.elipsoide.a / (1. + n) * (1. + n2 / 4. + n2 * n2 / 64. + n2 * n2 * n2 / 256.)
+    }
+
+    // Coordenadas de Gauss normalizadas (ξ, η) de una latitud y diferencia
+    // de longitud en radianes.
+    fn gauss(&self, phi: f64, lambda: f64) -> (f64, f64) {
+        let (xi, eta, _) = self.gauss_y_derivada(phi, lambda);
+        (xi, eta)
+    }
+
+    // Además de (ξ, η), la derivada de la serie (p', q') y la tangente de
+    // la latitud conforme, que dan la escala y la convergencia.
+    fn gauss_y_derivada(&self, phi: f64, lambda: f64) -> (f64, f64, (f64, f64, f64)) {
+        let e = self.elipsoide.e2().sqrt();
+        let t = (phi.sin().atanh() - e * (e * phi.sin()).atanh()).sinh();
+        let xi1 = t.atan2(lambda.cos());
+        let eta1 = (lambda.sin() / (1. + t * t).sqrt()).atanh();
+
+        alfa(self.elipsoide.n()).iter().enumerate()
+            .fold((xi1, eta1, (1., 0., t)), |(xi, eta, (p, q, t)), (j, a)| {
+                let k = 2. * (j as f64 + 1.);
+                (xi + a * (k * xi1).sin() * (k * eta1).cosh(),
+                 eta + a * (k * xi1).cos() * (k * eta1).sinh(),
+                 (p + k * a * (k * xi1).cos() * (k * eta1).cosh(),
+                  q + k * a * (k * xi1).sin() * (k * eta1).sinh(),
+                  t))
+            })
+    }
+
+    fn xi_origen(&self) -> f64 {
+        if self.latitud_origen.abs() >= 90. {
+            FRAC_PI_2.copysign(self.latitud_origen)
+        } else {
+            self.gauss(self.latitud_origen.to_radians(), 0.).0
+        }
+    }
+
+    // (latitud, longitud) en grados a (norte, este)
+    pub fn a_plano(&self, latitud: f64, longitud: f64) -> (f64, f64) {
+        let (xi, eta) = self.gauss(latitud.to_radians(), (longitud - self.meridiano_central).to_radians());
+        let ka = self.escala * self.radio_rectificante();
+        (self.falso_norte + ka * (xi - self.xi_origen()),
+         self.falso_este + ka * eta)
+    }
+
+    // (norte, este) a (latitud, longitud) en grados
+    pub fn a_geodesicas(&self, norte: f64, este: f64) -> (f64, f64) {
+        let ka = self.escala * self.radio_rectificante();
+        let xi = (norte - self.falso_norte) / ka + self.xi_origen();
+        let eta = (este - self.falso_este) / ka;
+
+        let (xi1, eta1) = beta(self.elipsoide.n()).iter().enumerate()
+            .fold((xi, eta), |(x, y), (j, b)| {
+                let k = 2. * (j as f64 + 1.);
+                (x - b * (k * xi).sin() * (k * eta).cosh(),
+                 y - b * (k * xi).cos() * (k * eta).sinh())
+            });
+
+        let lambda = eta1.sinh().atan2(xi1.cos());
+        // Tangente de la latitud conforme, y de ahí la geodésica por Newton
+        let tau1 = xi1.sin() / (eta1.sinh().powi(2) + xi1.cos().powi(2)).sqrt();
+        let e2 = self.elipsoide.e2();
+        let e = e2.sqrt();
+        let mut tau = tau1;
+        for _ in 0..5 {
+            let sigma = (e * (e * tau / (1. + tau * tau).sqrt()).atanh()).sinh();
+            let tau_i = tau * (1. + sigma * sigma).sqrt() - sigma * (1. + tau * tau).sqrt();
+            tau += (tau1 - tau_i) / (1. + tau_i * tau_i).sqrt()
+                * (1. + (1. - e2) * tau * tau) / ((1. - e2) * (1. + tau * tau).sqrt());
+        }
+
+        (tau.atan().to_degrees(), self.meridiano_central + lambda.to_degrees())
+    }
+
+    // Factor de escala puntual: distancia en la grilla sobre distancia en
+    // el elipsoide, en el entorno del punto
+    pub fn factor_de_escala(&self, latitud: f64, longitud: f64) -> f64 {
+        let (phi, lambda) = (latitud.to_radians(), (longitud - self.meridiano_central).to_radians());
+        let (_, _, (p, q, t)) = self.gauss_y_derivada(phi, lambda);
+        let n = self.elipsoide.n();
+        self.escala * self.radio_rectificante() / self.elipsoide.a
+            * (1. + ((1. - n) / (1. + n) * phi.tan()).powi(2)).sqrt()
+            * ((p * p + q * q) / (t * t + lambda.cos().powi(2))).sqrt()
+    }
+
+    // Convergencia de meridianos en grados: ángulo del norte de la grilla
+    // medido desde el norte geográfico en sentido horario. Acimut de
+    // grilla = acimut geodésico - convergencia.
+    pub fn convergencia(&self, latitud: f64, longitud: f64) -> f64 {
+        let (phi, lambda) = (latitud.to_radians(), (longitud - self.meridiano_central).to_radians());
+        let (_, _, (p, q, t)) = self.gauss_y_derivada(phi, lambda);
+        ((t * lambda.tan()).atan2((1. + t * t).sqrt()) + q.atan2(p)).to_degrees()
+    }
+
+    // Equivalente al --GS que escribe la controladora
+    pub fn a_gs<G: Geodesicas>(&self, registro: &G) -> GSRecord {
+        let (latitud, longitud, elevacion) = registro.geodesicas();
+        let (north, east) = self.a_plano(latitud, longitud);
+        GSRecord {
+            occupy_point: registro.nombre().to_string(),
+            north,
+            east,
+            elevation: elevacion,
+            note: "".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::record_parser_gps::{parse_bp_record, parse_gps_record};
+
+    fn gk_58_5() -> TransversaMercator {
+        TransversaMercator { latitud_origen: -90., ..TransversaMercator::new(GRS80, -58.5, 1., 500_000., 0.) }
+    }
+
+    #[test]
+    fn test_gauss_kruger() {
+        let proy = gk_58_5();
+
+        // Referencia calculada con las fórmulas de Redfearn
+        let bp = parse_bp_record("BP,PN0,LA-35.02153129,LN-58.26579176,ET1.6280,AG1.5230,PA1.6278,ATAPC,SRBASE,--").unwrap();
+        let gs = proy.a_gs(&bp);
+        assert_eq!(gs.occupy_point, "0");
+        assert_eq!(gs.elevation, 1.628);
+        assert!((gs.north - 6124959.0585).abs() < 0.001);
+        assert!((gs.east - 521374.8600).abs() < 0.001);
+
+        let proy = TransversaMercator::faja_argentina(5, GRS80).unwrap();
+        let (n, e) = proy.a_plano(-35.02153129, -58.26579176);
+        assert!((n - 6123609.2143).abs() < 0.001);
+        assert!((e - 5658279.5348).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_completar_gs() {
+        let proy = gk_58_5();
+        let gps = parse_gps_record("GPS,PN0,LA-35.02153129,LN-58.26579176,EL1.6280,--").unwrap();
+        let gs = proy.a_gs(&gps);
+        let gps = gps.aplicar_gs(gs.clone()).unwrap();
+        assert_eq!(gps.north, Some(gs.north));
+        assert_eq!(gps.east, Some(gs.east));
+    }
+
+    #[test]
+    fn test_ida_y_vuelta() {
+        let proy = TransversaMercator::faja_argentina(5, INTERNACIONAL_1924).unwrap();
+        for (lat, lon) in [(-35.0215, -58.2658), (-22.5, -61.9), (-54.8, -68.3)] {
+            let (n, e) = proy.a_plano(lat, lon);
+            let (lat2, lon2) = proy.a_geodesicas(n, e);
+            assert!((lat - lat2).abs() < 1e-9);
+            assert!((lon - lon2).abs() < 1e-9);
+        }
+        // Sobre el meridiano central el este es el falso este
+        let (_, e) = proy.a_plano(-35., -60.);
+        assert!((e - 5_500_000.).abs() < 1e-6);
+
+        assert!(TransversaMercator::faja_argentina(8, GRS80).is_err());
+    }
+
+    #[test]
+    fn test_escala_y_convergencia() {
+        let proy = gk_58_5();
+        // Sobre el meridiano central la escala es la del origen y no hay convergencia
+        assert!((proy.factor_de_escala(-35., -58.5) - 1.).abs() < 1e-12);
+        assert!(proy.convergencia(-35., -58.5).abs() < 1e-12);
+
+        // Contra diferencias finitas de la propia proyección, en la base PN0
+        let (lat, lon) = (-35.02153129, -58.26579176);
+        let d = 1e-5;
+        let (n0, e0) = proy.a_plano(lat, lon);
+        let (n1, e1) = proy.a_plano(lat + d, lon);
+        let arco = GRS80.a * (1. - GRS80.e2()) / (1. - GRS80.e2() * lat.to_radians().sin().powi(2)).powf(1.5)
+            * d.to_radians();
+        assert!(((n1 - n0).hypot(e1 - e0) / arco - proy.factor_de_escala(lat, lon)).abs() < 1e-8);
+        // El norte geográfico tiene acimut de grilla igual a menos la convergencia
+        let acimut = (e1 - e0).atan2(n1 - n0).to_degrees();
+        assert!((acimut + proy.convergencia(lat, lon)).abs() < 1e-5);
+
+        // Al este del meridiano central en el hemisferio sur la convergencia
+        // es negativa: γ ≈ Δλ sen φ
+        let gamma = proy.convergencia(lat, lon);
+        assert!((gamma - (lon + 58.5) * lat.to_radians().sin()).abs() < 1e-4);
+        // y la escala ≈ 1 + x² / 2R², a 21 km del meridiano central
+        assert!((proy.factor_de_escala(lat, lon) - 1.0000056).abs() < 1e-7);
+    }
+
+    #[test]
+    fn test_utm() {
+        // Sobre el meridiano central el norte es 0.9996 por el arco de
+        // meridiano, 4984944.378 m a 45° en WGS84
+        let proy = TransversaMercator::utm(31, false).unwrap();
+        let (n, e) = proy.a_plano(45., 3.);
+        assert!((n - 0.9996 * 4_984_944.378).abs() < 0.001);
+        assert!((e - 500_000.).abs() < 1e-6);
+
+        let proy = TransversaMercator::utm(21, true).unwrap();
+        let (n, _) = proy.a_plano(0., -57.);
+        assert!((n - 10_000_000.).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::cogo::{reducir_con, ReduccionTopografica};
+use crate::correcciones::Correcciones;
+use crate::file_parser::Record;
+use crate::record_parser::{BacksightRecord, LineOfSightRecord, OccupyRecord, TraverseRecord};
+use crate::tiempo::{self, HusoHorario, Interpolacion};
+use rinex::prelude::Epoch;
+
+// Una puesta de estación total: el OC, su BK, las alturas y todas las
+// visuales hasta el OC siguiente
+#[derive(Debug, Clone)]
+pub struct Puesta<'a> {
+    // Índice en ReduccionTopografica::estaciones
+    pub indice: usize,
+    pub ocupacion: &'a OccupyRecord,
+    pub atras: Option<&'a BacksightRecord>,
+    // Hora del último DT/TM antes del OC
+    pub epoca: Option<Epoch>,
+    // Las alturas vienen de la puesta anterior hasta que un LS las cambia
+    pub altura_instrumento: f64,
+    pub alturas_de_prisma: Vec<f64>,
+    pub visuales: Vec<&'a TraverseRecord>,
+    registros: Vec<&'a Record>,
+    // Instrumento y prisma con los que se llega al OC
+    alturas_heredadas: (f64, f64),
+    // Los SP y el último MO anteriores al OC
+    heredados: Vec<&'a Record>,
+}
+
+impl<'a> Puesta<'a> {
+    pub fn registros(&self) -> &[&'a Record] {
+        &self.registros
+    }
+
+    // Con las coordenadas del OC y la orientación del BK, sin el resto del
+    // trabajo. Los SP y el MO vigente y las alturas heredadas entran como
+    // registros antes del OC.
+    pub fn reducir(&self, correcciones: &Correcciones) -> ReduccionTopografica {
+        let (hi, hr) = self.alturas_heredadas;
+        let alturas = Record::HI(LineOfSightRecord { hi, hr: Some(hr) });
+        let registros: Vec<&Record> = self.heredados.iter().copied()
+            .chain(std::iter::once(&alturas))
+            .chain(self.registros.iter().copied())
+            .collect();
+        reducir_con(&registros, correcciones)
+    }
+}
+
+// Lo que se informa de cada puesta. Residuos del chequeo atrás en segundos
+// y metros.
+#[derive(Debug, Clone)]
+pub struct ResumenDePuesta {
+    pub indice: usize,
+    pub estacion: String,
+    pub atras: Option<String>,
+    pub epoca: Option<Epoch>,
+    pub altura_instrumento: f64,
+    pub alturas_de_prisma: Vec<f64>,
+    pub visuales: usize,
+    pub residuo_azimut: Option<f64>,
+    pub residuo_angular: Option<f64>,
+    pub residuo_distancia: Option<f64>,
+}
+
+pub struct RelevamientoConvencional<'a> {
+    records: Vec<&'a Record>,
+    huso: HusoHorario,
+    correcciones: Correcciones,
+}
+
+impl<'a> RelevamientoConvencional<'a> {
+    pub fn new(records: &'a [Record]) -> RelevamientoConvencional<'a> {
+        RelevamientoConvencional {
+            records: records.iter().collect(),
+            huso: HusoHorario::default(),
+            correcciones: Correcciones::default(),
+        }
+    }
+
+    pub fn con_huso(&mut self, huso: HusoHorario) -> &Self {
+        self.huso = huso;
+        self
+    }
+
+    pub fn con_correcciones(&mut self, correcciones: Correcciones) -> &Self {
+        self.correcciones = correcciones;
+        self
+    }
+
+    // Lo que está antes del primer OC (SP, MO) no es de ninguna puesta,
+    // pero entra en la reducción de cada una
+    pub fn puestas(&self) -> Vec<Puesta<'a>> {
+        let epocas = tiempo::resolver_epocas(&self.records, &self.huso, Interpolacion::Vecino);
+        let mut puestas: Vec<Puesta<'a>> = vec![];
+        let (mut hi, mut hr) = (0., 0.);
+        let (mut control, mut modo): (Vec<&'a Record>, Option<&'a Record>) = (vec![], None);
+
+        for (r, epoca) in self.records.iter().copied().zip(epocas) {
+            match r {
+                Record::OC(oc) => {
+                    puestas.push(Puesta {
+                        indice: puestas.len(),
+                        ocupacion: oc,
+                        atras: None,
+                        epoca,
+                        altura_instrumento: hi,
+                        alturas_de_prisma: vec![hr],
+                        visuales: vec![],
+                        registros: vec![],
+                        alturas_heredadas: (hi, hr),
+                        heredados: control.iter().copied().chain(modo).collect(),
+                    });
+                },
+                Record::HI(ls) => {
+                    hi = ls.hi;
+                    hr = ls.hr.unwrap_or(hr);
+                },
+                Record::LS(ls) => hr = ls.height_rod,
+                Record::SP(_) => control.push(r),
+                Record::MO(_) => modo = Some(r),
+                _ => {},
+            }
+            if let Some(p) = puestas.last_mut() {
+                match r {
+                    Record::BK(bk) if bk.op == p.ocupacion.op => p.atras = Some(bk),
+                    Record::HI(_) | Record::LS(_) => {
+                        p.altura_instrumento = hi;
+                        // La primera altura se reemplaza si todavía no se visó nada
+                        if p.visuales.is_empty() {
+                            p.alturas_de_prisma = vec![hr];
+                        } else if p.alturas_de_prisma.last() != Some(&hr) {
+                            p.alturas_de_prisma.push(hr);
+                        }
+                    },
+                    Record::SS(v) => p.visuales.push(v),
+                    _ => {},
+                }
+                p.registros.push(r);
+            }
+        }
+        puestas
+    }
+
+    pub fn reducir(&self) -> ReduccionTopografica {
+        reducir_con(&self.records, &self.correcciones)
+    }
+
+    pub fn resumen(&self) -> Vec<ResumenDePuesta> {
+        let reduccion = self.reducir();
+        self.puestas().into_iter().map(|p| {
+            let chequeo = reduccion.chequeos.iter().find(|c| c.puesta == p.indice);
+            ResumenDePuesta {
+                indice: p.indice,
+                estacion: p.ocupacion.op.clone(),
+                atras: p.atras.map(|bk| bk.bp.clone()),
+                epoca: p.epoca,
+                altura_instrumento: p.altura_instrumento,
+                alturas_de_prisma: p.alturas_de_prisma.clone(),
+                visuales: p.visuales.len(),
+                residuo_azimut: chequeo.and_then(|c| c.residuo_azimut),
+                residuo_angular: chequeo.and_then(|c| c.residuo_angular),
+                residuo_distancia: chequeo.and_then(|c| c.residuo_distancia),
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file_parser::registros_de_lineas;
+
+    #[test]
+    fn test_puestas() {
+        let registros = registros_de_lineas(vec![
+            "--SP,PN2,N 5100.000,E 5000.000,EL100.000,--PP",
+            "--DT06-27-2003",
+            "--TM14:21:53",
+            "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+            "BK,OP1,BP2,BS0.0000,BC0.0000",
+            "LS,HI1.500000,HR1.800000",
+            "SS,OP1,FP2,AR0.0000,ZE90.0000,SD100.010000",
+            "LS,HR2.000000",
+            "TR,OP1,FP3,AR90.0000,ZE90.0000,SD50.000000",
+            "--DT06-27-2003",
+            "--TM15:02:10",
+            "OC,OP3,N 5000.00000,E 5050.00000,EL100.000",
+            "BK,OP3,BP1,BS270.0000,BC0.0000",
+            "SS,OP3,FP4,AR90.0000,ZE90.0000,SD10.000000",
+        ]);
+        let rel = RelevamientoConvencional::new(&registros);
+        let puestas = rel.puestas();
+        assert_eq!(puestas.len(), 2);
+
+        let p1 = &puestas[0];
+        assert_eq!(p1.atras.unwrap().bp, "2");
+        assert_eq!(p1.altura_instrumento, 1.5);
+        assert_eq!(p1.alturas_de_prisma, vec![1.8, 2.0]);
+        assert_eq!(p1.visuales.len(), 2);
+        assert!(p1.epoca.unwrap() < puestas[1].epoca.unwrap());
+
+        // Las alturas pasan a la puesta siguiente
+        let p3 = &puestas[1];
+        assert_eq!((p3.altura_instrumento, p3.alturas_de_prisma.clone()), (1.5, vec![2.0]));
+        let reduccion = p3.reducir(&Correcciones::default());
+        assert_eq!(reduccion.puntos.len(), 1);
+        // Visual horizontal con 1.5 de instrumento y 2.0 de prisma
+        assert!((reduccion.puntos[0].elevacion - 99.5).abs() < 1e-9);
+
+        let resumen = rel.resumen();
+        assert!((resumen[0].residuo_azimut.unwrap()).abs() < 1e-9);
+        assert!((resumen[0].residuo_distancia.unwrap() - 0.01).abs() < 1e-9);
+        // El 1 es el OC de la primera puesta; sin visual de chequeo sólo se
+        // controla el BS
+        assert_eq!(resumen[1].atras.as_deref(), Some("1"));
+        assert!((resumen[1].residuo_azimut.unwrap()).abs() < 1e-9);
+        assert_eq!(resumen[1].residuo_distancia, None);
+
+        let mut csv = vec![];
+        crate::exportar::puestas_a_csv(&resumen, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv.lines().nth(1).unwrap().contains(",1.500,1.800;2.000,2,"));
+    }
+
+    #[test]
+    fn test_puesta_con_lo_anterior_al_oc() {
+        let registros = registros_de_lineas(vec![
+            "--SP,PN2,N 5100.000,E 5000.000,EL100.000,--PP",
+            "MO,AD0,UN1,SF1.000000,EC0,EO0.010,AU0",
+            "OC,OP1,N 5000.00000,E 5000.00000,EL100.000",
+            "BK,OP1,BP2,BS0.0010,BC0.0000",
+            "LS,HI1.500000,HR1.500000",
+            "SS,OP1,FP2,AR0.0000,ZE90.0000,SD100.000000",
+            "OC,OP3,N 5000.00000,E 5100.00000,EL100.000",
+            "BK,OP3,BP2,BS315.0000,BC0.0000",
+        ]);
+        let puestas = RelevamientoConvencional::new(&registros).puestas();
+
+        // El SP y el MO de antes del OC valen para la puesta sola
+        let c = &puestas[0].reducir(&Correcciones::default()).chequeos[0];
+        assert!(c.azimut_calculado.unwrap().abs() < 1e-9);
+        assert!((c.residuo_azimut.unwrap() - 10.).abs() < 1e-6);
+        assert!((c.distancia_medida.unwrap() - 100.01).abs() < 1e-9);
+        assert!((c.residuo_distancia.unwrap() - 0.01).abs() < 1e-9);
+
+        // Y también para las puestas siguientes
+        let c = &puestas[1].reducir(&Correcciones::default()).chequeos[0];
+        assert!((c.azimut_calculado.unwrap() - 315.).abs() < 1e-9);
+        assert!(c.residuo_azimut.unwrap().abs() < 1e-6);
+    }
+}
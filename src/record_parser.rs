@@ -5,11 +5,12 @@
 // A GENERIC ERROR FOR PARSING FIELD
 
 use anyhow::anyhow;
-use rinex::prelude::{Duration, Epoch};
+use rinex::prelude::Epoch;
 use std::convert::TryFrom;
-use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use chrono::{NaiveDate, NaiveDateTime};
 use serde::Serialize;
 use crate::record_parser_gps as gps;
+use crate::tiempo::HusoHorario;
 
 #[derive(Debug)]
 pub struct CustomError {
@@ -38,23 +39,24 @@ impl std::error::Error for CustomError {}
 pub struct TRecord {
     date: Option<(i32,u8,u8)>,
     time: Option<(u8,u8,u8)>,
-    dt: Option<Epoch>
+    // Hora civil de la controladora, sin huso ni escala de tiempo
+    #[serde(with = "gps::serialize_opt_ndt")]
+    local: Option<NaiveDateTime>
 }
 
 impl TRecord {
     pub fn with_date(&self, t:(i32,u8,u8)) -> TRecord {
-        TRecord { date: Some(t), time: self.time, dt: self.dt }
+        TRecord { date: Some(t), time: self.time, local: self.local }
     }
     pub fn with_time(&self, t:(u8,u8,u8)) -> TRecord {
-        TRecord { date: self.date, time: Some(t), dt: self.dt }
+        TRecord { date: self.date, time: Some(t), local: self.local }
     }
     pub fn combine(self) -> TRecord {
         match self {
-            TRecord { date: Some(d), time: Some(t), dt: _ } =>
+            TRecord { date: Some(d), time: Some(t), local: _ } =>
                 TRecord { date: self.date, time: self.time,
-                          dt: Some(
-                               Epoch::from_gregorian_tai(d.0,d.1,d.2,t.0,t.1,t.2,0)
-                               ) 
+                          local: NaiveDate::from_ymd_opt(d.0, d.1 as u32, d.2 as u32)
+                              .and_then(|f| f.and_hms_opt(t.0 as u32, t.1 as u32, t.2 as u32))
                 },
             _ => self.clone()
         }
@@ -62,17 +64,17 @@ impl TRecord {
 
     pub fn merge(self, other: TRecord) -> Result<TRecord, anyhow::Error> {
         match self {
-            TRecord { date: Some(d), time: None, dt: _ } =>
+            TRecord { date: Some(d), time: None, local: _ } =>
                 match other {
-                    TRecord { date: None, time: Some(t), dt: _ } =>
+                    TRecord { date: None, time: Some(t), local: _ } =>
                         Ok(TRecord::default().with_time(t).with_date(d).combine()),
-                    TRecord { date: Some(d1), time: Some(_t), dt: _ } =>
+                    TRecord { date: Some(d1), time: Some(_t), local: _ } =>
                         if d1 == d {
                             Ok(other)
                         } else {
                             Err(anyhow!("Fechas incompatibles"))
                         },
-                    TRecord { date: Some(d1), time: None, dt: _ } =>
+                    TRecord { date: Some(d1), time: None, local: _ } =>
                         if d1 == d {
                             Ok(other)
                         } else {
@@ -80,17 +82,17 @@ impl TRecord {
                         },
                     _ => Ok(self)
                 }
-            TRecord { date: None, time: Some(t), dt: _ } =>
+            TRecord { date: None, time: Some(t), local: _ } =>
                 match other {
-                    TRecord { date: Some(d), time: None, dt: _ } =>
+                    TRecord { date: Some(d), time: None, local: _ } =>
                         Ok(TRecord::default().with_time(t).with_date(d).combine()),
-                    TRecord { date: Some(_d), time: Some(t1), dt: _ } =>
+                    TRecord { date: Some(_d), time: Some(t1), local: _ } =>
                         if t1 == t {
                             Ok(other)
                         } else {
                             Err(anyhow!("Horas incompatibles"))
                         }
-                    TRecord { date: None, time: Some(t1), dt: _ } =>
+                    TRecord { date: None, time: Some(t1), local: _ } =>
                         if t1 == t {
                             Ok(other)
                         } else {
@@ -98,20 +100,22 @@ impl TRecord {
                         }
                     _ => Ok(self)
                 }
-            TRecord { date: None, time: None, dt: _ } => {
+            TRecord { date: None, time: None, local: _ } => {
                 Err(anyhow!("Nada que combinar"))
             }
-            TRecord { date: Some(_), time: Some(_), dt: _ } => {
+            TRecord { date: Some(_), time: Some(_), local: _ } => {
                 Err(anyhow!("Ya está combinado"))
             }
         }
     }
 
-    pub fn get_epoch(&self) -> Epoch {
-        match self.dt {
-            None => Epoch::from_gpst_duration(Duration::from_seconds(0.)),
-            Some(v) => v
-        }
+    pub fn get_local(&self) -> Option<NaiveDateTime> {
+        self.local
+    }
+
+    // Sin fecha y hora completas no hay época
+    pub fn get_epoch(&self, huso: &HusoHorario) -> Option<Epoch> {
+        self.local.map(|v| huso.a_epoca(v))
     }
 }
 
@@ -139,7 +143,7 @@ pub fn parse_dt_record(line: &str) -> Result<TRecord, anyhow::Error>
     Ok(tr.combine())
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct BacksightRecord {
     pub op: String,
     pub bp: String,
@@ -154,13 +158,13 @@ pub struct JobRecord {
     pub tm: String,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct LineOfSightRecord {
     pub hi: f64,
     pub hr: Option<f64>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct ModeSetupRecord {
     pub ad: u32,
     pub un: u32,
@@ -170,7 +174,7 @@ pub struct ModeSetupRecord {
     pub au: u32,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct OccupyRecord {
     pub op: String,
     pub n: f64,
@@ -179,14 +183,14 @@ pub struct OccupyRecord {
     pub note: String,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct OffCenterShotRecord {
     pub ar: f64,
     pub ze: f64,
     pub sd: f64,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct StorePointRecord {
     pub pn: String,
     pub n: f64,
@@ -200,7 +204,7 @@ pub struct LabelRecord {
     pub label: String,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
 pub enum AngleOption {
     Azimuth(f64),
     Bearing(f64),
@@ -210,29 +214,91 @@ pub enum AngleOption {
     DeflectionLeft(f64),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
 pub enum ZenithOption {
     Zenith(f64),
     VerticalAngle(f64),
     ChangeElevation(f64),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
 pub enum DistanceOption {
     SlopeDistance(f64),
     HorizontalDistance(f64),
 }
 
-#[derive(Debug, PartialEq)]
+// Código de la línea: SS lateral, TR poligonal, BD/BR atrás en directa e
+// inversa, FD/FR adelante en directa e inversa
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub enum TipoDeVisual {
+    Lateral,
+    Poligonal,
+    AtrasDirecta,
+    AtrasInversa,
+    AdelanteDirecta,
+    AdelanteInversa,
+}
+
+impl TryFrom<&str> for TipoDeVisual {
+    type Error = anyhow::Error;
+
+    fn try_from(codigo: &str) -> Result<Self, Self::Error> {
+        match codigo {
+            "SS" => Ok(TipoDeVisual::Lateral),
+            "TR" => Ok(TipoDeVisual::Poligonal),
+            "BD" => Ok(TipoDeVisual::AtrasDirecta),
+            "BR" => Ok(TipoDeVisual::AtrasInversa),
+            "FD" => Ok(TipoDeVisual::AdelanteDirecta),
+            "FR" => Ok(TipoDeVisual::AdelanteInversa),
+            _ => Err(anyhow!(format!("Tipo de visual desconocido: {}", codigo))),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct TraverseRecord {
+    pub tipo: TipoDeVisual,
     pub occupy_point: String,
     pub foresight_point: String,
     pub angle_option: AngleOption,
     pub zenith_option: ZenithOption,
     pub distance_option: DistanceOption,
     pub note: String,
+    // Lo que venía en la visual antes de aplicarle un OF
+    pub original: Option<Observacion>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub struct Observacion {
+    pub angle_option: AngleOption,
+    pub zenith_option: ZenithOption,
+    pub distance_option: DistanceOption,
 }
 
+impl TraverseRecord {
+    // El OF es la puntería al prisma excéntrico, que da la distancia (con
+    // su cenit); la visual que le sigue es la puntería al centro del
+    // objeto, que da el ángulo horizontal. La visual queda con la
+    // observación combinada y la suya como original.
+    pub fn aplicar_of(self, of: OffCenterShotRecord) -> Result<Self, anyhow::Error> {
+        if self.original.is_some() {
+            return Err(anyhow!(format!("La visual a {} ya tiene un OF", self.foresight_point)));
+        }
+        Ok(TraverseRecord {
+            original: Some(Observacion {
+                angle_option: self.angle_option,
+                zenith_option: self.zenith_option,
+                distance_option: self.distance_option,
+            }),
+            zenith_option: ZenithOption::Zenith(of.ze),
+            distance_option: DistanceOption::SlopeDistance(of.sd),
+            ..self
+        })
+    }
+}
+
+crate::genera_try_from!(OffCenterShotRecord => TraverseRecord, aplicar_of);
+
 
 pub fn parse_label_record(line: &str) -> Result<(), Box<dyn std::error::Error>> {
     if line.len() < 2 || !line.starts_with("--") {
@@ -351,12 +417,14 @@ pub fn parse_store_point_record(line: &str) -> Result<StorePointRecord, Box<dyn
     }
 }
 
-fn parse_traverse_record(line: &str) -> Result<TraverseRecord, Box<dyn std::error::Error>> {
+pub fn parse_traverse_record(line: &str) -> Result<TraverseRecord, Box<dyn std::error::Error>> {
     let fields: Vec<&str> = line.split(',').collect();
-    if fields.len() > 7 {
+    if fields.len() > 7 || fields.len() < 6 {
         return Err("Invalid traverse record format".into());
     }
 
+    let tipo = TipoDeVisual::try_from(fields[0]).map_err(|e| CustomError::new(&e.to_string(), fields[0]))?;
+
     let occupy_point = fields[1].trim_start_matches("OP").to_string();
     let foresight_point = fields[2].trim_start_matches("FP").to_string();
 
@@ -383,15 +451,18 @@ fn parse_traverse_record(line: &str) -> Result<TraverseRecord, Box<dyn std::erro
         _ => return Err("Invalid distance option".into()),
     };
 
-    let note = fields[6].trim_start_matches("--").to_string();
+    // La nota es opcional
+    let note = fields.get(6).map(|n| n.trim_start_matches("--").to_string()).unwrap_or_default();
 
     Ok(TraverseRecord {
+        tipo,
         occupy_point,
         foresight_point,
         angle_option,
         zenith_option,
         distance_option,
         note,
+        original: None,
     })
 }
 
@@ -460,6 +531,19 @@ pub fn parse_record_line(line: &str) -> Result<(), Box<dyn std::error::Error>> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_trecord_get_epoch() {
+        let huso = HusoHorario::utc(-3.);
+        assert_eq!(TRecord::default().get_epoch(&huso), None);
+
+        let fecha = parse_dt_record("--DT04-12-2022").unwrap();
+        assert_eq!(fecha.get_epoch(&huso), None);
+
+        let hora = parse_dt_record("--TM16:15:06").unwrap();
+        let t = fecha.merge(hora).unwrap();
+        assert_eq!(t.get_epoch(&huso), Some(Epoch::from_gregorian_utc(2022, 4, 12, 19, 15, 6, 0)));
+    }
+
     #[test]
     fn test_parse_backsight_record() {
         let line = "BK,OP1,BP2,BS315.0000,BC0.0044";
@@ -537,6 +621,20 @@ mod tests {
         assert_eq!(record.sd, 25.55);
     }
 
+    #[test]
+    fn test_aplicar_of() {
+        let of = parse_off_center_shot_record("OF,AR90.3333,ZE90.0000,SD25.550000").unwrap();
+        let visual = parse_traverse_record("SS,OP1,FP4,AR90.0000,ZE90.0000,SD25.000000,--ARBOL").unwrap();
+        let corregida = TraverseRecord::try_from((of.clone(), visual.clone())).unwrap();
+        // Ángulo de la puntería al centro, distancia de la excéntrica
+        assert_eq!(corregida.angle_option, AngleOption::AngleRight(90.0));
+        assert_eq!(corregida.distance_option, DistanceOption::SlopeDistance(25.55));
+        assert_eq!(corregida.note, "ARBOL");
+        assert_eq!(corregida.original.unwrap().angle_option, visual.angle_option);
+        // Un segundo OF no se aplica sobre el primero
+        assert!(TraverseRecord::try_from((of, corregida)).is_err());
+    }
+
     #[test]
     fn test_parse_store_point_record() {
         let line = "SP,PN100,N 5002.0000,E 5000.0000,EL100.0000,--PP";
@@ -554,12 +652,14 @@ mod tests {
     fn test_parse_traverse_record_azimuth() {
         let line = "TR,OP1,FP4,AZ90.3333,ZE90.3333,SD25.550000,--CP";
         let expected = TraverseRecord {
+            tipo: TipoDeVisual::Poligonal,
             occupy_point: "1".to_string(),
             foresight_point: "4".to_string(),
             angle_option: AngleOption::Azimuth(90.3333),
             zenith_option: ZenithOption::Zenith(90.3333),
             distance_option: DistanceOption::SlopeDistance(25.550000),
             note: "CP".to_string(),
+            original: None,
         };
         assert_eq!(expected, parse_traverse_record(line).unwrap());
     }
@@ -568,12 +668,14 @@ mod tests {
     fn test_parse_traverse_record_bearing() {
         let line = "BD,OP1,FP2,BR123.4500,ZE86.0133,SD10.313750,--CP";
         let expected = TraverseRecord {
+            tipo: TipoDeVisual::AtrasDirecta,
             occupy_point: "1".to_string(),
             foresight_point: "2".to_string(),
             angle_option: AngleOption::Bearing(123.4500),
             zenith_option: ZenithOption::Zenith(86.0133),
             distance_option: DistanceOption::SlopeDistance(10.313750),
             note: "CP".to_string(),
+            original: None,
         };
         assert_eq!(expected, parse_traverse_record(line).unwrap());
     }
@@ -582,12 +684,14 @@ mod tests {
     fn test_parse_traverse_record_angle_right() {
         let line = "TR,OP1,FP4,AR45.6789,ZE90.3333,SD25.550000,--CP";
         let expected = TraverseRecord {
+            tipo: TipoDeVisual::Poligonal,
             occupy_point: "1".to_string(),
             foresight_point: "4".to_string(),
             angle_option: AngleOption::AngleRight(45.6789),
             zenith_option: ZenithOption::Zenith(90.3333),
             distance_option: DistanceOption::SlopeDistance(25.550000),
             note: "CP".to_string(),
+            original: None,
         };
         assert_eq!(expected, parse_traverse_record(line).unwrap());
     }
@@ -596,12 +700,14 @@ mod tests {
     fn test_parse_traverse_record_angle_left() {
         let line = "SS,OP1,FP2,AL12.3456,ZE86.0133,SD10.313750,--CP";
         let expected = TraverseRecord {
+            tipo: TipoDeVisual::Lateral,
             occupy_point: "1".to_string(),
             foresight_point: "2".to_string(),
             angle_option: AngleOption::AngleLeft(12.3456),
             zenith_option: ZenithOption::Zenith(86.0133),
             distance_option: DistanceOption::SlopeDistance(10.313750),
             note: "CP".to_string(),
+            original: None,
         };
         assert_eq!(expected, parse_traverse_record(line).unwrap());
     }
@@ -610,15 +716,27 @@ mod tests {
     fn test_parse_traverse_record_deflection_right() {
         let line = "FR,OP1,FP3,DR34.5678,ZE89.4305,SD7.393000,--CP";
         let expected = TraverseRecord {
+            tipo: TipoDeVisual::AdelanteInversa,
             occupy_point: "1".to_string(),
             foresight_point: "3".to_string(),
             angle_option: AngleOption::DeflectionRight(34.5678),
             zenith_option: ZenithOption::Zenith(89.4305),
             distance_option: DistanceOption::SlopeDistance(7.393000),
             note: "CP".to_string(),
+            original: None,
         };
         assert_eq!(expected, parse_traverse_record(line).unwrap());
     }
 
-}
+    #[test]
+    fn test_parse_traverse_record_sin_nota() {
+        let record = parse_traverse_record("SS,OP1,FP5,AR90.3000,ZE90.0000,HD12.5").unwrap();
+        assert_eq!(record.tipo, TipoDeVisual::Lateral);
+        assert_eq!(record.distance_option, DistanceOption::HorizontalDistance(12.5));
+        assert_eq!(record.note, "");
+
+        assert!(parse_traverse_record("SS,OP1,FP5,AR90.3000").is_err());
+        assert!(parse_traverse_record("XX,OP1,FP5,AR90.3000,ZE90.0000,HD12.5").is_err());
+    }
 
+}
@@ -2,7 +2,7 @@
 
 use std::convert::{TryFrom, TryInto};
 // use crate::record_parser::CustomError;
-use chrono::naive::{NaiveDate,NaiveDateTime};
+use chrono::{Datelike, naive::NaiveDate};
 //use chrono::Duration;
 use anyhow::anyhow;
 use rinex::prelude::{Duration, Epoch, TimeScale};
@@ -28,31 +28,15 @@ use serde::Serialize;
 // 
 // const GSD: NaiveDateTime = gps_start_date();
 
-fn count_leap_seconds(date: NaiveDate) -> usize {
-    // Lista de fechas con segundos intercalados (leap seconds)
-    let leap_seconds_dates = vec![
-        NaiveDate::from_ymd_opt(1981, 6, 30).unwrap(),
-        NaiveDate::from_ymd_opt(1982, 6, 30).unwrap(),
-        NaiveDate::from_ymd_opt(1983, 6, 30).unwrap(),
-        NaiveDate::from_ymd_opt(1985, 6, 30).unwrap(),
-        NaiveDate::from_ymd_opt(1987, 12, 31).unwrap(),
-        NaiveDate::from_ymd_opt(1989, 12, 31).unwrap(),
-        NaiveDate::from_ymd_opt(1990, 12, 31).unwrap(),
-        NaiveDate::from_ymd_opt(1992, 6, 30).unwrap(),
-        NaiveDate::from_ymd_opt(1993, 6, 30).unwrap(),
-        NaiveDate::from_ymd_opt(1994, 6, 30).unwrap(),
-        NaiveDate::from_ymd_opt(1995, 12, 31).unwrap(),
-        NaiveDate::from_ymd_opt(1997, 6, 30).unwrap(),
-        NaiveDate::from_ymd_opt(1998, 12, 31).unwrap(),
-        NaiveDate::from_ymd_opt(2005, 12, 31).unwrap(),
-        NaiveDate::from_ymd_opt(2008, 12, 31).unwrap(),
-        NaiveDate::from_ymd_opt(2012, 6, 30).unwrap(),
-        NaiveDate::from_ymd_opt(2015, 6, 30).unwrap(),
-        NaiveDate::from_ymd_opt(2016, 12, 31).unwrap(),
-    ];
-
-    // Contar cuántas fechas de segundos intercalados son anteriores a la fecha dada
-    leap_seconds_dates.iter().filter(|&&leap_date| leap_date < date).count()
+// Segundos intercalares acumulados desde el inicio de GPS (GPST - UTC) a
+// la fecha dada. Se usa la tabla de hifitime en lugar de mantener una propia.
+pub fn count_leap_seconds(date: NaiveDate) -> usize {
+    let e = Epoch::from_gregorian_utc_at_midnight(date.year(), date.month() as u8, date.day() as u8);
+    // TAI - UTC era 19 s al 6 de enero de 1980
+    match e.leap_seconds(true) {
+        Some(tai_utc) if tai_utc >= 19. => (tai_utc - 19.) as usize,
+        _ => 0
+    }
 }
 
 
@@ -80,23 +64,13 @@ pub struct BPRecord {
 
 impl BPRecord {
     // Necesito que mueva la referencia para reusarlo
+    // Una elevación distinta en el GS no es un error: queda en
+    // elevation_alt y la informa verificacion::residuo_grilla
     pub fn aplicar_gs(self, gr: GSRecord) -> Result<Self,anyhow::Error> {
 
         if self.occupy_point != gr.occupy_point { 
             Err(anyhow!(format!("No coinciden los registros BP y GS {} != {}",self.occupy_point,gr.occupy_point)))
         }
-        else if (self.elevation - gr.elevation).abs() > 0.001 {
-            // Err(anyhow!(format!("No coinciden los registros GPS y GS {} != {}",self.elevation,gr.elevation)))
-            Ok( Self 
-                { occupy_point:self.occupy_point,
-                  latitude: self.latitude, longitude: self.longitude,
-                  elevation: self.elevation, antenna_ground: self.antenna_ground, 
-                  phase_antenna: self.phase_antenna, elevation_alt: Some(gr.elevation),
-                  note: self.note, north: Some(gr.north),
-                  east: Some(gr.east), start_time: self.start_time,
-                  end_time: self.end_time
-            })
-        }
         else {
             Ok( Self 
                 { occupy_point:self.occupy_point,
@@ -128,27 +102,17 @@ pub struct GPSRecord {
 
 impl GPSRecord {
     // Necesito que mueva la referencia para reusarlo
+    // Como en BPRecord, la elevación del GS queda en elevation_alt
     pub fn aplicar_gs(self, gr: GSRecord) -> Result<GPSRecord,anyhow::Error> {
 
         if self.occupy_point != gr.occupy_point { 
             Err(anyhow!(format!("No coinciden los registros GPS y GS {} != {}",self.occupy_point,gr.occupy_point)))
         }
-        else if (self.elevation - gr.elevation).abs() > 0.001 {
-            // Err(anyhow!(format!("No coinciden los registros GPS y GS {} != {}",self.elevation,gr.elevation)))
-            Ok( GPSRecord 
-                { occupy_point:self.occupy_point,
-                  latitude: self.latitude, longitude: self.longitude,
-                  elevation: self.elevation, elevation_alt: Some(gr.elevation),
-                  note: self.note, north: Some(gr.north),
-                  east: Some(gr.east), start_time: self.start_time,
-                  end_time: self.end_time
-            })
-        }
         else {
             Ok( GPSRecord 
                 { occupy_point:self.occupy_point,
                   latitude: self.latitude, longitude: self.longitude,
-                  elevation: self.elevation, elevation_alt: self.elevation_alt,
+                  elevation: self.elevation, elevation_alt: Some(gr.elevation),
                   note: self.note, north: Some(gr.north),
                   east: Some(gr.east), start_time: self.start_time,
                   end_time: self.end_time
@@ -195,6 +159,164 @@ pub struct LSRecord {
     pub height_rod: f64,
 }
 
+#[derive(Debug,Copy,Clone,Serialize,PartialEq)]
+pub enum EstadoSolucion {
+    Fija,
+    Flotante,
+    Diferencial,
+    Autonoma
+}
+
+impl TryFrom<&str> for EstadoSolucion {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.trim().to_uppercase().as_str() {
+            "FIJO" | "FIXED" => Ok(EstadoSolucion::Fija),
+            "FLOTANTE" | "FLOAT" => Ok(EstadoSolucion::Flotante),
+            "DGPS" | "DIFERENCIAL" => Ok(EstadoSolucion::Diferencial),
+            "AUTONOMO" | "AUTÓNOMO" | "AUTONOMOUS" | "SINGLE" => Ok(EstadoSolucion::Autonoma),
+            _ => Err(anyhow!(format!("'{}' no es un estado de solución válido", value))),
+        }
+    }
+}
+
+// Estadísticos de las lineas de calidad (Avg/SD/Min/Max), cada linea
+// completa sólo una parte.
+#[derive(Debug,Copy,Clone,Serialize,Default,PartialEq)]
+pub struct Estadistico {
+    pub promedio: Option<f64>,
+    pub desvio: Option<f64>,
+    pub minimo: Option<f64>,
+    pub maximo: Option<f64>,
+}
+
+fn unir_campo<T: Clone>(a: &Option<T>, b: &Option<T>) -> Result<Option<T>,anyhow::Error> {
+    match (a, b) {
+        (Some(_), Some(_)) => Err(anyhow!("Campo de calidad repetido")),
+        _ => Ok(a.clone().or_else(|| b.clone()))
+    }
+}
+
+impl Estadistico {
+    pub fn merge(&self, other: &Estadistico) -> Result<Self,anyhow::Error> {
+        Ok( Self {
+            promedio: unir_campo(&self.promedio, &other.promedio)?,
+            desvio: unir_campo(&self.desvio, &other.desvio)?,
+            minimo: unir_campo(&self.minimo, &other.minimo)?,
+            maximo: unir_campo(&self.maximo, &other.maximo)?,
+        })
+    }
+}
+
+fn unir_estadistico(a: &Option<Estadistico>, b: &Option<Estadistico>) -> Result<Option<Estadistico>,anyhow::Error> {
+    match (a, b) {
+        (Some(x), Some(y)) => Ok(Some(x.merge(y)?)),
+        _ => Ok(a.or(*b))
+    }
+}
+
+// Bloque de calidad que sigue a cada punto GPS. Las lineas se parsean por
+// separado y se unen en combinar_registros, igual que DT/TM.
+#[derive(Debug,Clone,Serialize,Default,PartialEq)]
+pub struct QRecord {
+    pub lecturas_validas: Option<(u32,u32)>,
+    pub lecturas_fijas: Option<(u32,u32)>,
+    pub estado: Option<EstadoSolucion>,
+    pub norte: Option<Estadistico>,
+    pub este: Option<Estadistico>,
+    pub elevacion: Option<Estadistico>,
+    pub nrms: Option<Estadistico>,
+    pub erms: Option<Estadistico>,
+    pub hsdv: Option<Estadistico>,
+    pub vsdv: Option<Estadistico>,
+    pub nsdv: Option<Estadistico>,
+    pub esdv: Option<Estadistico>,
+    pub hdop: Option<Estadistico>,
+    pub vdop: Option<Estadistico>,
+    pub pdop: Option<Estadistico>,
+    pub edad: Option<Estadistico>,
+    pub satelites: Option<Estadistico>,
+}
+
+impl QRecord {
+    pub fn merge(self, other: QRecord) -> Result<QRecord, anyhow::Error> {
+        Ok( QRecord {
+            lecturas_validas: unir_campo(&self.lecturas_validas, &other.lecturas_validas)?,
+            lecturas_fijas: unir_campo(&self.lecturas_fijas, &other.lecturas_fijas)?,
+            estado: unir_campo(&self.estado, &other.estado)?,
+            norte: unir_estadistico(&self.norte, &other.norte)?,
+            este: unir_estadistico(&self.este, &other.este)?,
+            elevacion: unir_estadistico(&self.elevacion, &other.elevacion)?,
+            nrms: unir_estadistico(&self.nrms, &other.nrms)?,
+            erms: unir_estadistico(&self.erms, &other.erms)?,
+            hsdv: unir_estadistico(&self.hsdv, &other.hsdv)?,
+            vsdv: unir_estadistico(&self.vsdv, &other.vsdv)?,
+            nsdv: unir_estadistico(&self.nsdv, &other.nsdv)?,
+            esdv: unir_estadistico(&self.esdv, &other.esdv)?,
+            hdop: unir_estadistico(&self.hdop, &other.hdop)?,
+            vdop: unir_estadistico(&self.vdop, &other.vdop)?,
+            pdop: unir_estadistico(&self.pdop, &other.pdop)?,
+            edad: unir_estadistico(&self.edad, &other.edad)?,
+            satelites: unir_estadistico(&self.satelites, &other.satelites)?,
+        })
+    }
+
+    // Desvíos (norte, este, elevación) del punto. Se usa el SD de las
+    // lecturas promediadas; si no está, el NSDV/ESDV/VSDV del receptor.
+    pub fn desvios(&self) -> Option<(f64,f64,f64)> {
+        let sd = |e: &Option<Estadistico>| e.and_then(|v| v.desvio);
+        let avg = |e: &Option<Estadistico>| e.and_then(|v| v.promedio);
+        let h = avg(&self.hsdv).map(|v| v / 2f64.sqrt());
+
+        let sn = sd(&self.norte).or(avg(&self.nsdv)).or(h)?;
+        let se = sd(&self.este).or(avg(&self.esdv)).or(h)?;
+        let su = sd(&self.elevacion).or(avg(&self.vsdv))?;
+        Some((sn, se, su))
+    }
+
+    // Los bloques promediados no traen STATUS: si todas las lecturas
+    // válidas fueron fijas la solución se toma como fija.
+    pub fn estado_solucion(&self) -> Option<EstadoSolucion> {
+        match (self.estado, self.lecturas_fijas, self.lecturas_validas) {
+            (Some(e), _, _) => Some(e),
+            (None, Some((f, _)), Some((v, _))) if v > 0 && f == v => Some(EstadoSolucion::Fija),
+            _ => None,
+        }
+    }
+
+    // Lecturas fijas sobre lecturas válidas
+    pub fn proporcion_fijas(&self) -> Option<f64> {
+        match (self.lecturas_fijas, self.lecturas_validas) {
+            (Some((f, _)), Some((v, _))) if v > 0 => Some(f as f64 / v as f64),
+            _ => None,
+        }
+    }
+
+    fn con_estadistico(self, nombre: &str, e: Estadistico) -> Result<QRecord, anyhow::Error> {
+        let mut q = QRecord::default();
+        let e = Some(e);
+        match nombre {
+            "Nor" => q.norte = e,
+            "Eas" => q.este = e,
+            "Elv" => q.elevacion = e,
+            "NRMS" => q.nrms = e,
+            "ERMS" => q.erms = e,
+            "HSDV" => q.hsdv = e,
+            "VSDV" => q.vsdv = e,
+            "NSDV" => q.nsdv = e,
+            "ESDV" => q.esdv = e,
+            "HDOP" => q.hdop = e,
+            "VDOP" => q.vdop = e,
+            "PDOP" => q.pdop = e,
+            "AGE" => q.edad = e,
+            "SATS" | "Number of Satellites" => q.satelites = e,
+            _ => return Err(anyhow!(format!("Campo de calidad desconocido: {}", nombre))),
+        };
+        self.merge(q)
+    }
+}
+
 #[derive(Debug,Clone,Serialize,Default,PartialEq)]
 pub struct ATRecord {
     pub tipo: String,
@@ -357,31 +479,11 @@ pub fn parse_gt_record(line: &str) -> Result<GTRecord, anyhow::Error> {
         let sdelta = Duration::from_days((sw * 7) as f64) + Duration::from_milliseconds(st as f64);
         let edelta = Duration::from_days((ew * 7) as f64) + Duration::from_milliseconds(et as f64);
 
+        // Semana y milisegundos GPS: las épocas quedan en escala GPST y
+        // hifitime se ocupa de los segundos intercalares al convertir.
         let stime = Epoch::from_gpst_duration(sdelta);
-
-        //let stime = GSD + sdelta;
-        /* Tiempos en TAI, obviamos segundo intercalar.
-        let leap = Duration::seconds(count_leap_seconds(stime.into()) as i64);
-        stime = stime - leap;
-
-        if Duration::seconds(count_leap_seconds(stime.into()) as i64) > leap {
-            stime = stime - Duration::seconds(1);
-        };
-        */
-
         let etime = Epoch::from_gpst_duration(edelta);
 
-        //let etime = GSD + edelta;
-        /* Tiempos en TAI, obviamos segundo intercalar.
-        let leap = Duration::seconds(count_leap_seconds(etime.into()) as i64);
-        etime = etime - leap;
-
-        if Duration::seconds(count_leap_seconds(etime.into()) as i64) > leap {
-            stime = stime - Duration::seconds(1);
-        };
-        */
-
-
         Ok(GTRecord { occupy_point: op, start: stime, end: etime })
     } else {
         Err(anyhow!("Invalid GT record format"))
@@ -462,6 +564,65 @@ pub fn parse_entered_height_record(line: &str) -> Result<EHRecord, anyhow::Error
     }
 }
 
+pub fn parse_quality_record(line: &str) -> Result<QRecord, anyhow::Error> {
+    let cuerpo = line.trim_start_matches("--").trim();
+
+    // Formato de toma rápida, todo en una linea:
+    // --HSDV:0.0144, VSDV:0.0168, STATUS:FIJO, SATS:16, AGE:1, ...
+    if cuerpo.contains(',') {
+        let mut q = QRecord::default();
+        for campo in cuerpo.split(',') {
+            let (k, v) = campo.split_once(':')
+                .ok_or_else(|| anyhow!("Invalid Quality record format"))?;
+            q = match k.trim() {
+                "STATUS" => q.merge(QRecord { estado: Some(v.try_into()?), ..QRecord::default() })?,
+                k => q.con_estadistico(k, Estadistico { promedio: Some(v.trim().parse::<f64>()?), ..Estadistico::default() })?,
+            };
+        }
+        return Ok(q);
+    }
+
+    let (etiqueta, valores) = cuerpo.split_once(':')
+        .ok_or_else(|| anyhow!("Invalid Quality record format"))?;
+
+    // --Valid Readings: 10 of 10
+    if let Some(tipo) = etiqueta.strip_suffix(" Readings") {
+        let (n, total) = valores.split_once(" of ")
+            .ok_or_else(|| anyhow!("Invalid Readings record format"))?;
+        let lecturas = Some((n.trim().parse::<u32>()?, total.trim().parse::<u32>()?));
+        return match tipo {
+            "Valid" => Ok(QRecord { lecturas_validas: lecturas, ..QRecord::default() }),
+            "Fixed" => Ok(QRecord { lecturas_fijas: lecturas, ..QRecord::default() }),
+            _ => Err(anyhow!("Invalid Readings record format")),
+        };
+    }
+
+    // --Nor Avg: 6123196.6946  SD: 0.0040
+    // --Number of Satellites Avg: 20 Min: 20 Max: 20
+    let (nombre, primera) = etiqueta.rsplit_once(' ')
+        .ok_or_else(|| anyhow!("Invalid Quality record format"))?;
+    let resto = valores.replace(':', " ");
+    let mut tokens = resto.split_whitespace();
+    let mut clave = primera.to_string();
+    let mut e = Estadistico::default();
+    while let Some(v) = tokens.next() {
+        let v = Some(v.parse::<f64>()?);
+        match clave.as_str() {
+            "Avg" => e.promedio = v,
+            "SD" => e.desvio = v,
+            "Min" => e.minimo = v,
+            "Max" => e.maximo = v,
+            _ => return Err(anyhow!(format!("Estadístico desconocido: {}", clave))),
+        };
+        match tokens.next() {
+            Some(k) => clave = k.to_string(),
+            None => break,
+        }
+    }
+
+    QRecord::default().con_estadistico(nombre, e)
+}
+
 crate::genera_try_from!(BPRecord = GSRecord, aplicar_gs);
 
 crate::genera_try_from!(GPSRecord = GSRecord, aplicar_gs);
@@ -470,6 +631,8 @@ crate::genera_try_from!(GPSRecord = GTRecord, aplicar_gt);
 crate::genera_try_from!(ATRecord = LSRecord, aplicar_ls);
 crate::genera_try_from!(EHRecord => ATRecord, aplicar_eh);
 
+crate::genera_try_from!(QRecord = QRecord, merge);
+
 pub mod serialize_ndt {
     use chrono::NaiveDateTime;
     use serde::{self, Serializer};
@@ -681,6 +844,7 @@ mod tests {
         //let e = NaiveDate::from_ymd_opt(2022,04,12).unwrap().and_hms_opt(19,15,06).unwrap(); ????
         let e = Epoch::from_gregorian_utc(2022,04,12,19,14,49,0);
         assert_eq!(record.end, e);
+        assert_eq!(record.end.time_scale, TimeScale::GPST);
         //assert_eq!(record.end, "???");
     }
 
@@ -801,6 +965,52 @@ mod tests {
         let input_date = NaiveDate::from_ymd_opt(1990, 12, 31).unwrap().and_hms_opt(23,59,59).unwrap();
         let leap_seconds_count = count_leap_seconds(input_date.into());
         assert_eq!(leap_seconds_count, 6);
+
+        let input_date = NaiveDate::from_ymd_opt(2022, 4, 12).unwrap();
+        assert_eq!(count_leap_seconds(input_date), 18);
+    }
+
+    #[test]
+    fn test_parse_quality_record() {
+        let line = "--Nor Avg: 6123196.6946  SD: 0.0040";
+        let record = parse_quality_record(line).unwrap();
+        assert_eq!(record.norte.unwrap().promedio, Some(6123196.6946));
+        assert_eq!(record.norte.unwrap().desvio, Some(0.0040));
+
+        let line = "--Number of Satellites Avg: 20 Min: 19 Max: 21";
+        let record = parse_quality_record(line).unwrap();
+        assert_eq!(record.satelites.unwrap().minimo, Some(19.));
+        assert_eq!(record.satelites.unwrap().maximo, Some(21.));
+
+        let line = "--Fixed Readings: 4 of 5";
+        let record = parse_quality_record(line).unwrap();
+        assert_eq!(record.lecturas_fijas, Some((4,5)));
+    }
+
+    #[test]
+    fn test_parse_quality_record_toma_rapida() {
+        let line = "--HSDV:0.0144, VSDV:0.0168, STATUS:FIJO, SATS:16, AGE:1, PDOP:1.4000, HDOP:0.8500, VDOP:1.2400, NSDV:0.0102, ESDV:0.0102";
+        let record = parse_quality_record(line).unwrap();
+        assert_eq!(record.estado, Some(EstadoSolucion::Fija));
+        assert_eq!(record.satelites.unwrap().promedio, Some(16.));
+        assert_eq!(record.desvios(), Some((0.0102, 0.0102, 0.0168)));
+    }
+
+    #[test]
+    fn test_merge_quality_record() {
+        let q1 = parse_quality_record("--Nor Min: 6123196.6893  Max: 6123196.7030").unwrap();
+        let q2 = parse_quality_record("--Nor Avg: 6123196.6946  SD: 0.0040").unwrap();
+        let q3 = parse_quality_record("--Eas Avg: 504619.0351  SD: 0.0038").unwrap();
+        let q4 = parse_quality_record("--Elv Avg: 1.2441  SD: 0.0011").unwrap();
+
+        let r:Result<QRecord,_> = (q1.clone(),q2.clone()).try_into();
+        let q = r.unwrap().merge(q3).unwrap().merge(q4).unwrap();
+        assert_eq!(q.norte.unwrap().minimo, Some(6123196.6893));
+        assert_eq!(q.desvios(), Some((0.0040, 0.0038, 0.0011)));
+
+        // Dos lineas con el mismo dato no se combinan
+        let r:Result<QRecord,_> = (q2.clone(),q2).try_into();
+        assert!(r.is_err());
     }
 
     #[test]
@@ -0,0 +1,694 @@
+use crate::algebra::{minimos_cuadrados, Matriz};
+use crate::calibracion::PuntoDeControl;
+use crate::cogo::{azimut, normalizar, ReduccionTopografica};
+use crate::correcciones::Correcciones;
+use crate::post_parse_gps::RelevamientoGNSS;
+use crate::promedios::{nombre_base, DESVIO_MINIMO, DESVIO_POR_DEFECTO};
+use crate::proyeccion::{Geodesicas, TransversaMercator};
+use crate::record_parser_gps::{Estadistico, QRecord};
+use anyhow::anyhow;
+use serde::Serialize;
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
+
+// Desvíos a priori de la estación total y nivel de la prueba global
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PrecisionRed {
+    // Segundos
+    pub direccion: f64,
+    // Metros más partes por millón
+    pub distancia: f64,
+    pub distancia_ppm: f64,
+    pub desnivel: f64,
+    pub significacion: f64,
+}
+
+impl Default for PrecisionRed {
+    fn default() -> Self {
+        PrecisionRed { direccion: 5., distancia: 0.003, distancia_ppm: 2., desnivel: 0.01, significacion: 0.05 }
+    }
+}
+
+// Vector de la base al móvil en la grilla de la proyección. Las
+// coordenadas de los extremos sirven de aproximadas.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LineaGNSS {
+    pub desde: String,
+    pub hasta: String,
+    pub norte: f64,
+    pub este: f64,
+    pub elevacion: f64,
+    pub desvios: (f64, f64, f64),
+    pub origen: (f64, f64, f64),
+    pub destino: (f64, f64, f64),
+}
+
+// HSDV/VSDV si están; si no, NRMS/ERMS. Lo que falte sale de los desvíos
+// del punto.
+pub fn desvios_gnss(q: &QRecord) -> Option<(f64, f64, f64)> {
+    let avg = |e: &Option<Estadistico>| e.and_then(|v| v.promedio);
+    let horizontal = avg(&q.hsdv).map(|h| (h / 2f64.sqrt(), h / 2f64.sqrt()))
+        .or_else(|| avg(&q.nrms).zip(avg(&q.erms)));
+    let vertical = avg(&q.vsdv).or_else(|| q.desvios().map(|d| d.2));
+    horizontal.zip(vertical).map(|((n, e), v)| (n, e, v)).or_else(|| q.desvios())
+}
+
+// Una línea por cada punto con base. Hay que consolidar antes.
+pub fn lineas_gnss(relevamiento: &RelevamientoGNSS, proyeccion: &TransversaMercator) -> Vec<LineaGNSS> {
+    let plano = |g: &dyn Geodesicas| {
+        let (lat, lon, h) = g.geodesicas();
+        let (n, e) = proyeccion.a_plano(lat, lon);
+        (n, e, h)
+    };
+    relevamiento.ocupaciones().into_iter()
+        .filter_map(|(epoca, punto, _)| {
+            let base = relevamiento.base_de(&epoca)?;
+            let (origen, destino) = (plano(base), plano(punto));
+            let (sn, se, su) = relevamiento.calidad(&epoca)
+                .and_then(desvios_gnss)
+                .unwrap_or((DESVIO_POR_DEFECTO, DESVIO_POR_DEFECTO, DESVIO_POR_DEFECTO));
+            Some(LineaGNSS {
+                desde: nombre_base(&base.occupy_point).to_string(),
+                hasta: nombre_base(&punto.occupy_point).to_string(),
+                norte: destino.0 - origen.0,
+                este: destino.1 - origen.1,
+                elevacion: destino.2 - origen.2,
+                desvios: (sn.max(DESVIO_MINIMO), se.max(DESVIO_MINIMO), su.max(DESVIO_MINIMO)),
+                origen,
+                destino,
+            })
+        })
+        .collect()
+}
+
+// Observaciones de una visual. La dirección está en el marco de la puesta:
+// cada puesta lleva una incógnita de orientación.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VisualDeRed {
+    pub puesta: usize,
+    pub desde: String,
+    pub hasta: String,
+    pub direccion: Option<f64>,
+    pub distancia: Option<f64>,
+    pub desnivel: Option<f64>,
+}
+
+// Las visuales calculadas y la puntería atrás de cada BK. El chequeo atrás
+// aporta distancia y desnivel cuando no quedó como punto. Las líneas GNSS
+// son diferencias en metros en la grilla: si la reducción no llevó las
+// distancias a `proyeccion`, se llevan acá con el factor en cada estación en
+// lugar del SF, y todo pasa a metros.
+pub fn visuales_de_reduccion(r: &ReduccionTopografica, proyeccion: &TransversaMercator) -> Vec<VisualDeRed> {
+    let factor = |puesta: usize, desnivel: f64| match (r.correcciones.get(puesta), r.estaciones.get(puesta)) {
+        (Some(c), Some(e)) if c.proyeccion.is_none() => {
+            let a_grilla = Correcciones { proyeccion: Some(*proyeccion), ..*c };
+            a_grilla.factor_de_distancia(e.norte, e.este, e.elevacion + desnivel / 2.) / c.escala * c.metros_por_unidad
+        },
+        (Some(c), _) => c.metros_por_unidad,
+        _ => 1.,
+    };
+    let metros = |puesta: usize| r.correcciones.get(puesta).map_or(1., |c| c.metros_por_unidad);
+    let mut visuales: Vec<VisualDeRed> = r.puntos.iter().map(|p| VisualDeRed {
+        puesta: p.puesta,
+        desde: p.estacion.clone(),
+        hasta: p.nombre.clone(),
+        direccion: Some(p.azimut),
+        distancia: Some(p.distancia_horizontal * factor(p.puesta, p.desnivel)),
+        desnivel: Some(p.desnivel * metros(p.puesta)),
+    }).collect();
+    let estaciones: BTreeSet<&str> = r.estaciones.iter().map(|e| e.nombre.as_str()).collect();
+    for c in r.chequeos.iter() {
+        let visto = r.puntos.iter().any(|p| p.puesta == c.puesta && p.nombre == c.atras);
+        let determinado = visto || estaciones.contains(c.atras.as_str()) || r.control.contains_key(&c.atras);
+        if !determinado {
+            continue;
+        }
+        visuales.push(VisualDeRed {
+            puesta: c.puesta,
+            desde: c.estacion.clone(),
+            hasta: c.atras.clone(),
+            direccion: Some(c.azimut_atras),
+            distancia: if visto { None } else { c.distancia_medida.map(|d| d * factor(c.puesta, c.desnivel_medido.unwrap_or(0.))) },
+            desnivel: if visto { None } else { c.desnivel_medido.map(|d| d * metros(c.puesta)) },
+        });
+    }
+    visuales
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum TipoDeObservacion {
+    GnssNorte,
+    GnssEste,
+    GnssElevacion,
+    Direccion,
+    Distancia,
+    Desnivel,
+}
+
+// Residuo como corrección a la observación: segundos en las direcciones y
+// metros en el resto. El estandarizado no está si la observación no tiene
+// redundancia.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResiduoRed {
+    pub tipo: TipoDeObservacion,
+    pub desde: String,
+    pub hasta: String,
+    pub residuo: f64,
+    pub estandarizado: Option<f64>,
+}
+
+// Elipse de error estándar. Orientación del semieje mayor como azimut.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Elipse {
+    pub semieje_mayor: f64,
+    pub semieje_menor: f64,
+    pub orientacion: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PuntoAjustado {
+    pub nombre: String,
+    pub norte: f64,
+    pub este: f64,
+    pub elevacion: Option<f64>,
+    pub sigma_norte: f64,
+    pub sigma_este: f64,
+    pub sigma_elevacion: Option<f64>,
+    pub elipse: Option<Elipse>,
+    pub fijo: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PruebaChiCuadrado {
+    // vᵀPv con varianza a priori 1
+    pub estadistico: f64,
+    pub grados_de_libertad: usize,
+    pub inferior: f64,
+    pub superior: f64,
+    pub pasa: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AjusteDeRed {
+    pub puntos: Vec<PuntoAjustado>,
+    // Azimut del cero de cada puesta
+    pub orientaciones: BTreeMap<usize, f64>,
+    pub residuos: Vec<ResiduoRed>,
+    pub varianza_unitaria: f64,
+    pub grados_de_libertad: usize,
+    pub prueba: PruebaChiCuadrado,
+    pub iteraciones: usize,
+}
+
+// Cuantil de la normal estándar (Acklam), error relativo del orden de 1e-9
+pub fn cuantil_normal(p: f64) -> f64 {
+    const A: [f64; 6] = [-3.969683028665376e+01, 2.209460984245205e+02, -2.759285104469687e+02,
+                         1.38357751867269e+02, -3.066479806614716e+01, 2.506628277459239e+00];
+    const B: [f64; 5] = [-5.447609879822406e+01, 1.615858368580409e+02, -1.556989798598866e+02,
+                         6.680131188771972e+01, -1.328068155288572e+01];
+    const C: [f64; 6] = [-7.784894002430293e-03, -3.223964580411365e-01, -2.400758277161838e+00,
+                         -2.549732539343734e+00, 4.374664141464968e+00, 2.938163982698783e+00];
+    const D: [f64; 4] = [7.784695709041462e-03, 3.224671290700398e-01, 2.445134137142996e+00,
+                         3.754408661907416e+00];
+    let cola = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.)
+    };
+    if p < 0.02425 {
+        cola((-2. * p.ln()).sqrt())
+    } else if p > 1. - 0.02425 {
+        -cola((-2. * (1. - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.)
+    }
+}
+
+// Wilson-Hilferty: buena desde unos pocos grados de libertad
+pub fn cuantil_chi_cuadrado(p: f64, grados_de_libertad: usize) -> f64 {
+    let r = grados_de_libertad as f64;
+    let k = 2. / (9. * r);
+    (r * (1. - k + cuantil_normal(p) * k.sqrt()).powi(3)).max(0.)
+}
+
+fn elipse(qnn: f64, qee: f64, qne: f64) -> Elipse {
+    let media = (qnn + qee) / 2.;
+    let radio = (((qnn - qee) / 2.).powi(2) + qne * qne).sqrt();
+    Elipse {
+        semieje_mayor: (media + radio).sqrt(),
+        semieje_menor: (media - radio).max(0.).sqrt(),
+        orientacion: (0.5 * (2. * qne).atan2(qnn - qee)).to_degrees().rem_euclid(180.),
+    }
+}
+
+// Una fila de observación: (columna, derivada), observado menos calculado,
+// peso y de qué es
+struct Ecuacion {
+    derivadas: Vec<(usize, f64)>,
+    l: f64,
+    peso: f64,
+    tipo: TipoDeObservacion,
+    desde: String,
+    hasta: String,
+}
+
+#[derive(Default)]
+pub struct Red {
+    lineas: Vec<LineaGNSS>,
+    visuales: Vec<VisualDeRed>,
+    control: Vec<PuntoDeControl>,
+    aproximadas: BTreeMap<String, (f64, f64, f64)>,
+    precision: PrecisionRed,
+}
+
+impl Red {
+    // Los puntos de control quedan fijos en las tres coordenadas
+    pub fn new(control: Vec<PuntoDeControl>) -> Self {
+        Red { control, ..Red::default() }
+    }
+
+    pub fn con_lineas(&mut self, lineas: Vec<LineaGNSS>) -> &Self {
+        self.lineas = lineas;
+        self
+    }
+
+    pub fn con_visuales(&mut self, visuales: Vec<VisualDeRed>) -> &Self {
+        self.visuales = visuales;
+        self
+    }
+
+    // Coordenadas aproximadas de los puntos que no son de control ni
+    // extremos de una línea, típicamente ReduccionTopografica::conocidos
+    pub fn con_aproximadas(&mut self, aproximadas: BTreeMap<String, (f64, f64, f64)>) -> &Self {
+        self.aproximadas = aproximadas;
+        self
+    }
+
+    pub fn con_precision(&mut self, precision: PrecisionRed) -> &Self {
+        self.precision = precision;
+        self
+    }
+
+    fn coordenadas_iniciales(&self) -> BTreeMap<String, (f64, f64, f64)> {
+        let mut coordenadas = self.aproximadas.clone();
+        // Promedio de los extremos de las líneas, que son absolutos
+        let mut extremos: BTreeMap<&str, Vec<(f64, f64, f64)>> = BTreeMap::new();
+        for l in self.lineas.iter() {
+            extremos.entry(&l.desde).or_default().push(l.origen);
+            extremos.entry(&l.hasta).or_default().push(l.destino);
+        }
+        for (nombre, v) in extremos {
+            let n = v.len() as f64;
+            let media = v.iter().fold((0., 0., 0.), |a, c| (a.0 + c.0 / n, a.1 + c.1 / n, a.2 + c.2 / n));
+            coordenadas.insert(nombre.to_string(), media);
+        }
+        for c in self.control.iter() {
+            coordenadas.insert(c.nombre.clone(), (c.norte, c.este, c.elevacion));
+        }
+        coordenadas
+    }
+
+    fn ecuaciones(&self, coordenadas: &BTreeMap<String, (f64, f64, f64)>, orientaciones: &BTreeMap<usize, f64>,
+                  horizontal: &BTreeMap<String, usize>, vertical: &BTreeMap<String, usize>,
+                  columna_orientacion: &BTreeMap<usize, usize>) -> Vec<Ecuacion> {
+        let mut ecuaciones = vec![];
+        let h = |nombre: &str, dn: f64, de: f64| -> Vec<(usize, f64)> {
+            horizontal.get(nombre).map(|c| vec![(*c, dn), (*c + 1, de)]).unwrap_or_default()
+        };
+        let v = |nombre: &str, dh: f64| -> Vec<(usize, f64)> {
+            vertical.get(nombre).map(|c| vec![(*c, dh)]).unwrap_or_default()
+        };
+        let ecuacion = |derivadas, l, sigma: f64, tipo, desde: &str, hasta: &str| Ecuacion {
+            derivadas, l, peso: sigma.powi(-2), tipo, desde: desde.to_string(), hasta: hasta.to_string(),
+        };
+
+        for l in self.lineas.iter() {
+            let (a, b) = (coordenadas[&l.desde], coordenadas[&l.hasta]);
+            ecuaciones.push(ecuacion([h(&l.hasta, 1., 0.), h(&l.desde, -1., 0.)].concat(),
+                                     l.norte - (b.0 - a.0), l.desvios.0, TipoDeObservacion::GnssNorte, &l.desde, &l.hasta));
+            ecuaciones.push(ecuacion([h(&l.hasta, 0., 1.), h(&l.desde, 0., -1.)].concat(),
+                                     l.este - (b.1 - a.1), l.desvios.1, TipoDeObservacion::GnssEste, &l.desde, &l.hasta));
+            ecuaciones.push(ecuacion([v(&l.hasta, 1.), v(&l.desde, -1.)].concat(),
+                                     l.elevacion - (b.2 - a.2), l.desvios.2, TipoDeObservacion::GnssElevacion, &l.desde, &l.hasta));
+        }
+
+        let p = self.precision;
+        for vis in self.visuales.iter() {
+            let (a, b) = (coordenadas[&vis.desde], coordenadas[&vis.hasta]);
+            let (dn, de) = (b.0 - a.0, b.1 - a.1);
+            let d2 = dn * dn + de * de;
+            let d = d2.sqrt();
+            if let Some(direccion) = vis.direccion {
+                let calculada = azimut((a.0, a.1), (b.0, b.1)) - orientaciones[&vis.puesta];
+                let mut derivadas = [h(&vis.hasta, -de / d2, dn / d2), h(&vis.desde, de / d2, -dn / d2)].concat();
+                derivadas.push((columna_orientacion[&vis.puesta], -1.));
+                ecuaciones.push(ecuacion(derivadas, normalizar(direccion - calculada).to_radians(),
+                                         (p.direccion / 3600.).to_radians(), TipoDeObservacion::Direccion, &vis.desde, &vis.hasta));
+            }
+            if let Some(distancia) = vis.distancia {
+                ecuaciones.push(ecuacion([h(&vis.hasta, dn / d, de / d), h(&vis.desde, -dn / d, -de / d)].concat(),
+                                         distancia - d, p.distancia + p.distancia_ppm * 1e-6 * distancia,
+                                         TipoDeObservacion::Distancia, &vis.desde, &vis.hasta));
+            }
+            if let Some(desnivel) = vis.desnivel {
+                ecuaciones.push(ecuacion([v(&vis.hasta, 1.), v(&vis.desde, -1.)].concat(),
+                                         desnivel - (b.2 - a.2), p.desnivel, TipoDeObservacion::Desnivel, &vis.desde, &vis.hasta));
+            }
+        }
+        ecuaciones
+    }
+
+    pub fn ajustar(&self) -> Result<AjusteDeRed, anyhow::Error> {
+        let mut coordenadas = self.coordenadas_iniciales();
+        let fijos: BTreeSet<&str> = self.control.iter().map(|c| c.nombre.as_str()).collect();
+
+        // Incógnitas: norte y este de los puntos con observaciones
+        // horizontales, elevación de los que tienen verticales y una
+        // orientación por puesta
+        let (mut con_horizontal, mut con_vertical) = (BTreeSet::new(), BTreeSet::new());
+        for l in self.lineas.iter() {
+            con_horizontal.extend([l.desde.as_str(), l.hasta.as_str()]);
+            con_vertical.extend([l.desde.as_str(), l.hasta.as_str()]);
+        }
+        for v in self.visuales.iter() {
+            if v.direccion.is_some() || v.distancia.is_some() {
+                con_horizontal.extend([v.desde.as_str(), v.hasta.as_str()]);
+            }
+            if v.desnivel.is_some() {
+                con_vertical.extend([v.desde.as_str(), v.hasta.as_str()]);
+            }
+        }
+        for p in con_horizontal.iter().chain(con_vertical.iter()) {
+            if !coordenadas.contains_key(*p) {
+                return Err(anyhow!(format!("Sin coordenadas aproximadas para {}", p)));
+            }
+        }
+        let mut columnas = 0;
+        let mut horizontal = BTreeMap::new();
+        for p in con_horizontal.iter().filter(|p| !fijos.contains(*p)) {
+            horizontal.insert(p.to_string(), columnas);
+            columnas += 2;
+        }
+        let mut vertical = BTreeMap::new();
+        for p in con_vertical.iter().filter(|p| !fijos.contains(*p)) {
+            vertical.insert(p.to_string(), columnas);
+            columnas += 1;
+        }
+        let mut orientaciones = BTreeMap::new();
+        let mut columna_orientacion = BTreeMap::new();
+        for v in self.visuales.iter().filter(|v| v.direccion.is_some()) {
+            if let Entry::Vacant(o) = orientaciones.entry(v.puesta) {
+                let (a, b) = (coordenadas[&v.desde], coordenadas[&v.hasta]);
+                o.insert(normalizar(azimut((a.0, a.1), (b.0, b.1)) - v.direccion.unwrap()));
+                columna_orientacion.insert(v.puesta, columnas);
+                columnas += 1;
+            }
+        }
+        if columnas == 0 {
+            return Err(anyhow!("La red no tiene incógnitas"));
+        }
+
+        for iteracion in 1..=10 {
+            let ecuaciones = self.ecuaciones(&coordenadas, &orientaciones, &horizontal, &vertical, &columna_orientacion);
+            let mut a = Matriz::ceros(ecuaciones.len(), columnas);
+            for (i, e) in ecuaciones.iter().enumerate() {
+                for (j, d) in e.derivadas.iter() {
+                    a[(i, *j)] += d;
+                }
+            }
+            let l: Vec<f64> = ecuaciones.iter().map(|e| e.l).collect();
+            let pesos: Vec<f64> = ecuaciones.iter().map(|e| e.peso).collect();
+            let ajuste = minimos_cuadrados(&a, &l, Some(&pesos))
+                .map_err(|e| anyhow!(format!("La red no se puede resolver: {}", e)))?;
+            let x = &ajuste.incognitas;
+
+            for (p, c) in horizontal.iter() {
+                let q = coordenadas.get_mut(p).unwrap();
+                q.0 += x[*c];
+                q.1 += x[c + 1];
+            }
+            for (p, c) in vertical.iter() {
+                coordenadas.get_mut(p).unwrap().2 += x[*c];
+            }
+            for (k, c) in columna_orientacion.iter() {
+                *orientaciones.get_mut(k).unwrap() += x[*c].to_degrees();
+            }
+            let maximo = horizontal.values().flat_map(|c| [x[*c], x[c + 1]])
+                .chain(vertical.values().map(|c| x[*c]))
+                .fold(0f64, |m, v| m.max(v.abs()));
+            if maximo > 1e-5 {
+                continue;
+            }
+
+            let s0 = ajuste.varianza_unitaria;
+            let q = &ajuste.cofactores;
+            let residuos = ecuaciones.iter().enumerate().map(|(i, e)| {
+                // q_vv = 1/p - a·Qxx·aᵀ
+                let qll: f64 = e.derivadas.iter()
+                    .flat_map(|(j, dj)| e.derivadas.iter().map(move |(k, dk)| (j, dj, k, dk)))
+                    .map(|(j, dj, k, dk)| dj * q[(*j, *k)] * dk)
+                    .sum();
+                let qvv = 1. / e.peso - qll;
+                let v = ajuste.residuos[i];
+                ResiduoRed {
+                    tipo: e.tipo,
+                    desde: e.desde.clone(),
+                    hasta: e.hasta.clone(),
+                    residuo: if e.tipo == TipoDeObservacion::Direccion { v.to_degrees() * 3600. } else { v },
+                    estandarizado: if qvv > 1e-12 / e.peso { Some(v / qvv.sqrt()) } else { None },
+                }
+            }).collect();
+
+            let nombres: BTreeSet<&String> = horizontal.keys().chain(vertical.keys()).collect();
+            let mut puntos: Vec<PuntoAjustado> = self.control.iter().map(|c| PuntoAjustado {
+                nombre: c.nombre.clone(), norte: c.norte, este: c.este, elevacion: Some(c.elevacion),
+                sigma_norte: 0., sigma_este: 0., sigma_elevacion: Some(0.), elipse: None, fijo: true,
+            }).collect();
+            puntos.extend(nombres.into_iter().map(|p| {
+                let (n, e, h) = coordenadas[p];
+                let (sigma_norte, sigma_este, elipse_de) = match horizontal.get(p) {
+                    Some(c) => ((s0 * q[(*c, *c)]).sqrt(), (s0 * q[(c + 1, c + 1)]).sqrt(),
+                                Some(elipse(s0 * q[(*c, *c)], s0 * q[(c + 1, c + 1)], s0 * q[(*c, c + 1)]))),
+                    None => (0., 0., None),
+                };
+                PuntoAjustado {
+                    nombre: p.clone(),
+                    norte: n,
+                    este: e,
+                    elevacion: vertical.get(p).map(|_| h),
+                    sigma_norte,
+                    sigma_este,
+                    sigma_elevacion: vertical.get(p).map(|c| (s0 * q[(*c, *c)]).sqrt()),
+                    elipse: elipse_de,
+                    fijo: false,
+                }
+            }));
+
+            let gl = ajuste.grados_de_libertad;
+            let estadistico = s0 * gl as f64;
+            let alfa = self.precision.significacion;
+            let (inferior, superior) = if gl > 0 {
+                (cuantil_chi_cuadrado(alfa / 2., gl), cuantil_chi_cuadrado(1. - alfa / 2., gl))
+            } else {
+                (0., 0.)
+            };
+            return Ok(AjusteDeRed {
+                puntos,
+                orientaciones: orientaciones.into_iter().map(|(k, o)| (k, o.rem_euclid(360.))).collect(),
+                residuos,
+                varianza_unitaria: s0,
+                grados_de_libertad: gl,
+                prueba: PruebaChiCuadrado {
+                    estadistico,
+                    grados_de_libertad: gl,
+                    inferior,
+                    superior,
+                    pasa: gl > 0 && estadistico >= inferior && estadistico <= superior,
+                },
+                iteraciones: iteracion,
+            });
+        }
+        Err(anyhow!("El ajuste de la red no converge"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn control(nombre: &str, norte: f64, este: f64, elevacion: f64) -> PuntoDeControl {
+        PuntoDeControl { nombre: nombre.to_string(), norte, este, elevacion }
+    }
+
+    // A y B fijos; P y Q libres. Puestas en A y en P visando a los otros
+    // tres, más la línea GNSS de A a Q. Las observaciones llevan un error
+    // de hasta un desvío, con signo alternado.
+    fn red(error_grueso: f64) -> Red {
+        let puntos: BTreeMap<&str, (f64, f64, f64)> = vec![
+            ("A", (1000., 1000., 100.)), ("B", (1000., 1200., 102.)),
+            ("P", (1100., 1100., 101.)), ("Q", (1150., 1000., 99.)),
+        ].into_iter().collect();
+        let p = PrecisionRed::default();
+        let mut ruido = [0.8, -0.6, 0.9, -0.7, 0.5, -1.0, 0.3, -0.4, 1.0].iter().cycle();
+        let mut visuales = vec![];
+        for (puesta, (desde, orientacion)) in [("A", 30.), ("P", 250.)].iter().enumerate() {
+            for hasta in ["A", "B", "P", "Q"].iter().filter(|h| *h != desde) {
+                let (a, b) = (puntos[desde], puntos[hasta]);
+                let d = (b.0 - a.0).hypot(b.1 - a.1);
+                let direccion = azimut((a.0, a.1), (b.0, b.1)) - orientacion + ruido.next().unwrap() * p.direccion / 3600.;
+                let mut distancia = d + ruido.next().unwrap() * (p.distancia + p.distancia_ppm * 1e-6 * d);
+                if (*desde, *hasta) == ("A", "Q") {
+                    distancia += error_grueso;
+                }
+                visuales.push(VisualDeRed {
+                    puesta,
+                    desde: desde.to_string(),
+                    hasta: hasta.to_string(),
+                    direccion: Some(direccion.rem_euclid(360.)),
+                    distancia: Some(distancia),
+                    desnivel: Some(b.2 - a.2 + ruido.next().unwrap() * p.desnivel),
+                });
+            }
+        }
+        let (a, q) = (puntos["A"], puntos["Q"]);
+        let linea = LineaGNSS {
+            desde: "A".to_string(),
+            hasta: "Q".to_string(),
+            norte: q.0 - a.0 - 0.004,
+            este: q.1 - a.1 + 0.003,
+            elevacion: q.2 - a.2 + 0.01,
+            desvios: (0.005, 0.005, 0.01),
+            origen: (a.0 + 0.3, a.1 - 0.2, a.2),
+            destino: (q.0 + 0.3, q.1 - 0.2, q.2),
+        };
+
+        let mut red = Red::new(vec![control("A", a.0, a.1, a.2), control("B", 1000., 1200., 102.)]);
+        red.con_lineas(vec![linea]);
+        red.con_visuales(visuales);
+        red.con_aproximadas(vec![("P".to_string(), (1100.4, 1099.7, 100.))].into_iter().collect());
+        red
+    }
+
+    #[test]
+    fn test_ajuste_de_red() {
+        let ajuste = red(0.).ajustar().unwrap();
+        // 18 observaciones de estación total y 3 GNSS; 6 coordenadas y 2
+        // orientaciones
+        assert_eq!(ajuste.residuos.len(), 21);
+        assert_eq!(ajuste.grados_de_libertad, 13);
+        assert!(ajuste.prueba.pasa, "{:?}", ajuste.prueba);
+        assert!(ajuste.iteraciones > 1);
+
+        let p = ajuste.puntos.iter().find(|p| p.nombre == "P").unwrap();
+        assert!(!p.fijo);
+        assert!((p.norte - 1100.).abs() < 0.005 && (p.este - 1100.).abs() < 0.005);
+        assert!((p.elevacion.unwrap() - 101.).abs() < 0.01);
+        let e = p.elipse.unwrap();
+        assert!(e.semieje_menor <= e.semieje_mayor && e.semieje_mayor < 0.01);
+        assert!((e.semieje_mayor.powi(2) + e.semieje_menor.powi(2)
+                 - p.sigma_norte.powi(2) - p.sigma_este.powi(2)).abs() < 1e-12);
+        assert!((ajuste.orientaciones[&1] - 250.).abs() < 0.001);
+        assert!(ajuste.puntos.iter().find(|p| p.nombre == "B").unwrap().fijo);
+
+        let mut csv = vec![];
+        crate::exportar::puntos_ajustados_a_csv(&ajuste.puntos, &mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap().lines().count(), 5);
+    }
+
+    #[test]
+    fn test_error_grueso() {
+        let ajuste = red(0.2).ajustar().unwrap();
+        assert!(!ajuste.prueba.pasa);
+        assert!(ajuste.prueba.estadistico > ajuste.prueba.superior);
+        let peor = ajuste.residuos.iter()
+            .max_by(|a, b| a.estandarizado.unwrap().abs().partial_cmp(&b.estandarizado.unwrap().abs()).unwrap())
+            .unwrap();
+        assert!(peor.estandarizado.unwrap().abs() > 3.);
+        assert_eq!((peor.tipo, peor.desde.as_str(), peor.hasta.as_str()), (TipoDeObservacion::Distancia, "A", "Q"));
+
+        let mut csv = vec![];
+        crate::exportar::residuos_red_a_csv(&ajuste.residuos, &mut csv).unwrap();
+        assert!(String::from_utf8(csv).unwrap().lines().nth(1).unwrap().starts_with("GnssNorte,A,Q,"));
+    }
+
+    #[test]
+    fn test_sin_aproximadas() {
+        let mut red = red(0.);
+        red.con_aproximadas(BTreeMap::new());
+        assert!(red.ajustar().is_err());
+    }
+
+    #[test]
+    fn test_lineas_gnss() {
+        use crate::crs::leer_crs;
+        use crate::file_parser::de_archivo_a_registros;
+        use std::path::Path;
+
+        let registros = de_archivo_a_registros(Path::new("tests/test.rw5"));
+        let mut rel = RelevamientoGNSS::new(&registros);
+        rel.consolidar_antenas();
+        rel.consolidar_bases();
+        rel.consolidar_puntos();
+        let crs = leer_crs(Path::new("tests/test.rw5")).unwrap().unwrap();
+        let lineas = lineas_gnss(&rel, &crs.proyeccion);
+        assert!(!lineas.is_empty());
+        for l in lineas.iter() {
+            assert!(l.desvios.0 >= DESVIO_MINIMO && l.desvios.2 >= DESVIO_MINIMO);
+            assert!((l.destino.0 - l.origen.0 - l.norte).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_visuales_en_grilla() {
+        use crate::cogo::reducir_con;
+        use crate::file_parser::{registros_de_lineas, Record};
+        use crate::proyeccion::GRS80;
+
+        let proy = TransversaMercator { latitud_origen: -90., ..TransversaMercator::new(GRS80, -58.5, 0.9996, 500_000., 0.) };
+        let registros = registros_de_lineas(vec![
+            "OC,OP1,N 6100000.00000,E 650000.00000,EL1000.000",
+            "BK,OP1,BP2,BS0.0000,BC0.0000",
+            "LS,HI1.500000,HR1.500000",
+            "SS,OP1,FP3,AR90.0000,ZE90.0000,SD100.000000",
+        ]);
+        let refs: Vec<&Record> = registros.iter().collect();
+        let c = Correcciones { proyeccion: Some(proy), ..Correcciones::default() };
+        let factor = c.factor_de_distancia(6_100_000., 650_000., 1000.);
+        assert!((factor - 1.).abs() > 1e-4);
+
+        // Reducida sobre el terreno, la distancia se lleva a la grilla
+        let visuales = visuales_de_reduccion(&reducir_con(&refs, &Correcciones::default()), &proy);
+        assert!((visuales[0].distancia.unwrap() - 100. * factor).abs() < 1e-9);
+        // Ya en la grilla no se corrige dos veces
+        let visuales = visuales_de_reduccion(&reducir_con(&refs, &c), &proy);
+        assert!((visuales[0].distancia.unwrap() - 100. * factor).abs() < 1e-9);
+
+        // En pies y con el SF de la controladora: el SF no se suma al factor
+        // de la proyección y las visuales salen en metros
+        let pies = |m: f64| m / 0.3048;
+        let lineas = [
+            "MO,AD0,UN0,SF0.999600,EC0,EO0.0,AU0".to_string(),
+            format!("OC,OP1,N {:.5},E {:.5},EL{:.3}", pies(6_100_000.), pies(650_000.), pies(1000.)),
+            "BK,OP1,BP2,BS0.0000,BC0.0000".to_string(),
+            "LS,HI1.500000,HR1.000000".to_string(),
+            format!("SS,OP1,FP3,AR90.0000,ZE90.0000,SD{:.6}", pies(100.)),
+        ];
+        let registros = registros_de_lineas(lineas.iter().map(|l| l.as_str()).collect());
+        let refs: Vec<&Record> = registros.iter().collect();
+        for correcciones in [Correcciones::default(), c] {
+            let visuales = visuales_de_reduccion(&reducir_con(&refs, &correcciones), &proy);
+            assert!((visuales[0].distancia.unwrap() - 100. * factor).abs() < 1e-5);
+            assert!((visuales[0].desnivel.unwrap() - 0.5 * 0.3048).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_cuantiles() {
+        assert!((cuantil_normal(0.975) - 1.959964).abs() < 1e-6);
+        assert!((cuantil_normal(0.001) + 3.090232).abs() < 1e-6);
+        // De tablas, 13 grados de libertad al 5% en dos colas
+        assert!((cuantil_chi_cuadrado(0.025, 13) - 5.009).abs() < 0.05);
+        assert!((cuantil_chi_cuadrado(0.975, 13) - 24.736).abs() < 0.05);
+    }
+}
@@ -0,0 +1,372 @@
+use crate::algebra::{minimos_cuadrados, Matriz};
+use crate::cogo::{azimut, dms_a_grados, giro_de_posicion, grados_a_dms, normalizar, reducir_distancia, Estacion};
+use crate::correcciones::Correcciones;
+use crate::file_parser::Record;
+use crate::record_parser::{AngleOption, BacksightRecord, OccupyRecord};
+use anyhow::anyhow;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// Desvíos a priori de las observaciones: segundos y metros
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PrecisionReseccion {
+    pub angular: f64,
+    pub distancia: f64,
+}
+
+impl Default for PrecisionReseccion {
+    fn default() -> Self {
+        PrecisionReseccion { angular: 5., distancia: 0.005 }
+    }
+}
+
+// Visual desde la estación libre a un punto de coordenadas conocidas.
+// Lectura del círculo en grados, distancia horizontal ya reducida.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VisualDeReseccion {
+    pub punto: String,
+    pub lectura: f64,
+    pub distancia: Option<f64>,
+    pub desnivel: Option<f64>,
+    pub conocido: (f64, f64, f64),
+}
+
+// Residuos como corrección a la observación: segundos y metros
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResiduoReseccion {
+    pub punto: String,
+    pub direccion: f64,
+    pub distancia: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Reseccion {
+    pub estacion: String,
+    pub norte: f64,
+    pub este: f64,
+    pub elevacion: f64,
+    // Azimut del cero del círculo, en grados
+    pub orientacion: f64,
+    pub sigma_norte: f64,
+    pub sigma_este: f64,
+    pub grados_de_libertad: usize,
+    pub varianza_unitaria: f64,
+    pub residuos: Vec<ResiduoReseccion>,
+    // Contra el que se arma el BK sintético
+    atras: (String, f64),
+}
+
+// Corte de los círculos de las dos primeras visuales con distancia. De las
+// dos soluciones queda la que respeta el ángulo entre lecturas.
+fn aproximacion(visuales: &[VisualDeReseccion]) -> Result<(f64, f64), anyhow::Error> {
+    let mut con_distancia = visuales.iter().filter(|v| v.distancia.is_some());
+    let (a, b) = match (con_distancia.next(), con_distancia.next()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Err(anyhow!("Hacen falta distancias a dos puntos para la resección")),
+    };
+    let (pa, pb) = ((a.conocido.0, a.conocido.1), (b.conocido.0, b.conocido.1));
+    let (ra, rb) = (a.distancia.unwrap(), b.distancia.unwrap());
+    let (dn, de) = (pb.0 - pa.0, pb.1 - pa.1);
+    let d = dn.hypot(de);
+    if d < 1e-6 {
+        return Err(anyhow!(format!("Los puntos {} y {} coinciden", a.punto, b.punto)));
+    }
+    let x = (ra * ra - rb * rb + d * d) / (2. * d);
+    let h = (ra * ra - x * x).max(0.).sqrt();
+    let (un, ue) = (dn / d, de / d);
+    let angulo = (b.lectura - a.lectura).rem_euclid(360.);
+    [1., -1.].iter()
+        .map(|s| (pa.0 + x * un - s * h * ue, pa.1 + x * ue + s * h * un))
+        .min_by(|p, q| {
+            let error = |p: &(f64, f64)| normalizar(azimut(*p, pb) - azimut(*p, pa) - angulo).abs();
+            error(p).partial_cmp(&error(q)).unwrap()
+        })
+        .ok_or_else(|| anyhow!("Sin aproximación"))
+}
+
+// Mínimos cuadrados sobre norte, este y orientación, iterando hasta que
+// las correcciones sean submilimétricas
+pub fn resolver(estacion: &str, visuales: &[VisualDeReseccion], precision: &PrecisionReseccion) -> Result<Reseccion, anyhow::Error> {
+    let (mut norte, mut este) = aproximacion(visuales)?;
+    let mut orientacion = normalizar(azimut((norte, este), (visuales[0].conocido.0, visuales[0].conocido.1)) - visuales[0].lectura);
+    let peso_angular = (precision.angular / 3600.).to_radians().powi(-2);
+    let peso_distancia = precision.distancia.powi(-2);
+
+    for _ in 0..20 {
+        let (mut filas, mut l, mut pesos) = (vec![], vec![], vec![]);
+        for v in visuales {
+            let (dn, de) = (v.conocido.0 - norte, v.conocido.1 - este);
+            let d2 = dn * dn + de * de;
+            let calculada = azimut((norte, este), (v.conocido.0, v.conocido.1)) - orientacion;
+            filas.push(vec![de / d2, -dn / d2, -1.]);
+            l.push(normalizar(v.lectura - calculada).to_radians());
+            pesos.push(peso_angular);
+            if let Some(d) = v.distancia {
+                let d0 = d2.sqrt();
+                filas.push(vec![-dn / d0, -de / d0, 0.]);
+                l.push(d - d0);
+                pesos.push(peso_distancia);
+            }
+        }
+        let ajuste = minimos_cuadrados(&Matriz::de_filas(&filas), &l, Some(&pesos))?;
+        let x = &ajuste.incognitas;
+        norte += x[0];
+        este += x[1];
+        orientacion += x[2].to_degrees();
+        if x[0].abs() < 1e-5 && x[1].abs() < 1e-5 {
+            // Los residuos vienen en el orden de las filas
+            let mut v_i = ajuste.residuos.iter();
+            let residuos = visuales.iter().map(|v| ResiduoReseccion {
+                punto: v.punto.clone(),
+                direccion: v_i.next().unwrap().to_degrees() * 3600.,
+                distancia: v.distancia.and_then(|_| v_i.next().copied()),
+            }).collect();
+            let desniveles: Vec<f64> = visuales.iter()
+                .filter_map(|v| v.desnivel.map(|dh| v.conocido.2 - dh))
+                .collect();
+            let s0 = ajuste.varianza_unitaria;
+            return Ok(Reseccion {
+                estacion: estacion.to_string(),
+                norte,
+                este,
+                elevacion: if desniveles.is_empty() { 0. } else { desniveles.iter().sum::<f64>() / desniveles.len() as f64 },
+                orientacion: orientacion.rem_euclid(360.),
+                sigma_norte: (s0 * ajuste.cofactores[(0, 0)]).sqrt(),
+                sigma_este: (s0 * ajuste.cofactores[(1, 1)]).sqrt(),
+                grados_de_libertad: ajuste.grados_de_libertad,
+                varianza_unitaria: s0,
+                residuos,
+                atras: (visuales[0].punto.clone(), visuales[0].lectura),
+            });
+        }
+    }
+    Err(anyhow!(format!("La resección de {} no converge", estacion)))
+}
+
+// Arma las visuales de la puesta libre `estacion` a puntos conocidos (SP y
+// OC de otras puestas) y la resuelve
+pub fn reseccion(registros: &[&Record], estacion: &str, precision: &PrecisionReseccion,
+                 correcciones: &Correcciones) -> Result<Reseccion, anyhow::Error> {
+    let mut conocidos: BTreeMap<String, (f64, f64, f64)> = BTreeMap::new();
+    for r in registros {
+        match r {
+            Record::SP(sp) => { conocidos.insert(sp.pn.clone(), (sp.n, sp.e, sp.el)); },
+            Record::OC(oc) if oc.op != estacion => { conocidos.entry(oc.op.clone()).or_insert((oc.n, oc.e, oc.el)); },
+            _ => {},
+        }
+    }
+
+    let mut libre = Estacion {
+        nombre: estacion.to_string(), norte: 0., este: 0., elevacion: 0.,
+        altura_instrumento: 0., altura_prisma: 0.,
+        atras: None, azimut_atras: None, circulo_atras: 0.,
+    };
+    // Cada visual guarda la estación (por las alturas), su observación y
+    // las correcciones vigentes, para reducirla de nuevo
+    let (mut en_puesta, mut visuales, mut medidas) = (false, vec![], vec![]);
+    let mut correcciones = *correcciones;
+    for r in registros {
+        match r {
+            Record::MO(mo) => correcciones = correcciones.con_modo(mo),
+            Record::OC(oc) => {
+                if en_puesta {
+                    break;
+                }
+                en_puesta = oc.op == estacion;
+            },
+            Record::HI(ls) => {
+                libre.altura_instrumento = ls.hi;
+                libre.altura_prisma = ls.hr.unwrap_or(libre.altura_prisma);
+            },
+            Record::LS(ls) => libre.altura_prisma = ls.height_rod,
+            Record::SS(v) if en_puesta => {
+                let conocido = match conocidos.get(&v.foresight_point) {
+                    Some(c) => *c,
+                    None => continue,
+                };
+                let lectura = match v.angle_option {
+                    AngleOption::AngleRight(a) => dms_a_grados(a),
+                    AngleOption::AngleLeft(a) => 360. - dms_a_grados(a),
+                    _ => continue,
+                };
+                let lectura = (lectura + giro_de_posicion(v.zenith_option)).rem_euclid(360.);
+                visuales.push(VisualDeReseccion {
+                    punto: v.foresight_point.clone(), lectura,
+                    distancia: None, desnivel: None, conocido,
+                });
+                medidas.push((libre.clone(), v.zenith_option, v.distance_option, correcciones));
+            },
+            _ => {},
+        }
+    }
+    if visuales.len() < 2 {
+        return Err(anyhow!(format!("La estación {} tiene {} visuales a puntos conocidos", estacion, visuales.len())));
+    }
+
+    // El factor de grilla y de nivel depende de dónde está la estación,
+    // que es lo que se busca: primero se resuelve sin la proyección y
+    // después se reduce de nuevo en la posición resuelta, hasta que no se
+    // mueva
+    let reducir_en = |visuales: &mut Vec<VisualDeReseccion>, posicion: Option<(f64, f64, f64)>| {
+        for (v, (libre, cenit, distancia, correcciones)) in visuales.iter_mut().zip(&medidas) {
+            let (libre, correcciones) = match posicion {
+                Some((norte, este, elevacion)) => (Estacion { norte, este, elevacion, ..libre.clone() }, *correcciones),
+                None => (libre.clone(), Correcciones { proyeccion: None, ..*correcciones }),
+            };
+            let (distancia, desnivel) = reducir_distancia(&libre, *cenit, *distancia, &correcciones);
+            v.distancia = Some(distancia);
+            v.desnivel = Some(desnivel);
+        }
+    };
+    reducir_en(&mut visuales, None);
+    let mut solucion = resolver(estacion, &visuales, precision)?;
+    if medidas.iter().any(|m| m.3.proyeccion.is_some()) {
+        for _ in 0..5 {
+            let anterior = (solucion.norte, solucion.este);
+            reducir_en(&mut visuales, Some((solucion.norte, solucion.este, solucion.elevacion)));
+            solucion = resolver(estacion, &visuales, precision)?;
+            if (solucion.norte - anterior.0).hypot(solucion.este - anterior.1) < 1e-4 {
+                break;
+            }
+        }
+    }
+    Ok(solucion)
+}
+
+impl Reseccion {
+    pub fn a_ocupacion(&self) -> OccupyRecord {
+        OccupyRecord { op: self.estacion.clone(), n: self.norte, e: self.este, el: self.elevacion, note: "--Reseccion".to_string() }
+    }
+
+    // Orientación de la puesta contra el primer punto visado
+    pub fn a_atras(&self) -> BacksightRecord {
+        let (punto, lectura) = &self.atras;
+        BacksightRecord {
+            op: self.estacion.clone(),
+            bp: punto.clone(),
+            bs: grados_a_dms((self.orientacion + lectura).rem_euclid(360.)),
+            bc: grados_a_dms(*lectura),
+        }
+    }
+
+    // Reemplaza el OC de la puesta libre por el calculado, con su BK
+    pub fn sustituir(&self, registros: &[&Record]) -> Vec<Record> {
+        let mut salida = vec![];
+        let mut en_puesta = false;
+        for r in registros {
+            match r {
+                Record::OC(oc) => {
+                    en_puesta = oc.op == self.estacion;
+                    if en_puesta {
+                        salida.push(Record::OC(self.a_ocupacion()));
+                        salida.push(Record::BK(self.a_atras()));
+                        continue;
+                    }
+                },
+                Record::BK(bk) if en_puesta && bk.op == self.estacion => continue,
+                _ => {},
+            }
+            salida.push((*r).clone());
+        }
+        salida
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cogo::reducir;
+    use crate::file_parser::registros_de_lineas;
+    use crate::proyeccion::{TransversaMercator, GRS80};
+
+    // Estación libre en (950, 1040) con el cero del círculo a 30°
+    fn lineas(error_distancia: f64) -> Vec<String> {
+        lineas_desde((950., 1040.), 100., 1., error_distancia)
+    }
+
+    // Las distancias medidas son las de grilla divididas por `factor`
+    fn lineas_desde(libre: (f64, f64), elevacion: f64, factor: f64, error_distancia: f64) -> Vec<String> {
+        let puntos: Vec<(&str, f64, f64)> = [("1", 50., -40.), ("2", 50., 60.), ("3", 150., 10.)].iter()
+            .map(|(p, n, e)| (*p, libre.0 + n, libre.1 + e))
+            .collect();
+        let mut lineas: Vec<String> = puntos.iter()
+            .map(|(p, n, e)| format!("--SP,PN{},N {:.4},E {:.4},EL{:.3},--PP", p, n, e, elevacion))
+            .collect();
+        lineas.push("OC,OP100,N 0.00000,E 0.00000,EL0.000".to_string());
+        lineas.push("LS,HI1.500000,HR1.500000".to_string());
+        for (i, (p, n, e)) in puntos.iter().enumerate() {
+            let lectura = (azimut(libre, (*n, *e)) - 30.).rem_euclid(360.);
+            let d = (n - libre.0).hypot(e - libre.1) / factor + if i == 0 { error_distancia } else { 0. };
+            lineas.push(format!("SS,OP100,FP{},AR{:.6},ZE90.0000,SD{:.4}", p, grados_a_dms(lectura), d));
+        }
+        lineas.push("SS,OP100,FP9,AR0.0000,ZE90.0000,SD10.0000".to_string());
+        lineas
+    }
+
+    fn registros(lineas: &[String]) -> Vec<Record> {
+        registros_de_lineas(lineas.iter().map(|l| l.as_str()).collect())
+    }
+
+    #[test]
+    fn test_reseccion() {
+        let registros = registros(&lineas(0.));
+        let refs: Vec<&Record> = registros.iter().collect();
+        let r = reseccion(&refs, "100", &PrecisionReseccion::default(), &Correcciones::default()).unwrap();
+        assert!((r.norte - 950.).abs() < 1e-4 && (r.este - 1040.).abs() < 1e-4);
+        assert!((r.orientacion - 30.).abs() < 1. / 3600.);
+        assert!((r.elevacion - 100.).abs() < 1e-9);
+        // Tres direcciones y tres distancias para tres incógnitas
+        assert_eq!(r.grados_de_libertad, 3);
+        assert!(r.residuos.iter().all(|v| v.direccion.abs() < 0.1 && v.distancia.unwrap().abs() < 1e-3));
+
+        // Con la puesta resuelta, el punto 9 queda a 10 m en azimut 30°
+        let sustituidos = r.sustituir(&refs);
+        let t = reducir(&sustituidos.iter().collect::<Vec<_>>());
+        let p9 = t.puntos.iter().find(|p| p.nombre == "9").unwrap();
+        assert!((p9.norte - (950. + 10. * 30f64.to_radians().cos())).abs() < 1e-3);
+        assert!((p9.este - (1040. + 10. * 30f64.to_radians().sin())).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_reseccion_con_error() {
+        let registros = registros(&lineas(0.03));
+        let refs: Vec<&Record> = registros.iter().collect();
+        let r = reseccion(&refs, "100", &PrecisionReseccion::default(), &Correcciones::default()).unwrap();
+        // El error se reparte y se ve en el residuo de la distancia al 1
+        assert!(r.residuos[0].distancia.unwrap() < -0.005);
+        assert!(r.varianza_unitaria > 1.);
+        assert!((r.norte - 950.).abs() < 0.03 && (r.este - 1040.).abs() < 0.03);
+
+        assert!(reseccion(&refs[..5], "100", &PrecisionReseccion::default(), &Correcciones::default()).is_err());
+    }
+
+    #[test]
+    fn test_reseccion_en_segunda_posicion() {
+        // La visual al 2 en segunda posición: círculo y cenital dados vuelta
+        let mut lineas = lineas(0.);
+        let lectura = (azimut((950., 1040.), (1000., 1100.)) - 30. + 180.).rem_euclid(360.);
+        lineas[6] = format!("SS,OP100,FP2,AR{:.6},ZE270.0000,SD{:.4}", grados_a_dms(lectura), 50f64.hypot(60.));
+        let registros = registros(&lineas);
+        let refs: Vec<&Record> = registros.iter().collect();
+        let r = reseccion(&refs, "100", &PrecisionReseccion::default(), &Correcciones::default()).unwrap();
+        assert!((r.norte - 950.).abs() < 1e-4 && (r.este - 1040.).abs() < 1e-4);
+        assert!((r.orientacion - 30.).abs() < 1. / 3600.);
+    }
+
+    #[test]
+    fn test_reseccion_en_grilla() {
+        // Lejos del meridiano central y a 1000 m: el factor pasa de 1.0001
+        let proy = TransversaMercator { latitud_origen: -90., ..TransversaMercator::new(GRS80, -58.5, 0.9996, 500_000., 0.) };
+        let c = Correcciones { proyeccion: Some(proy), ..Correcciones::default() };
+        let libre = (6_100_000., 650_000.);
+        let factor = c.factor_de_distancia(libre.0, libre.1, 1000.);
+        assert!((factor - 1.).abs() > 1e-4);
+
+        let registros = registros(&lineas_desde(libre, 1000., factor, 0.));
+        let refs: Vec<&Record> = registros.iter().collect();
+        let r = reseccion(&refs, "100", &PrecisionReseccion::default(), &c).unwrap();
+        assert!((r.norte - libre.0).abs() < 1e-3 && (r.este - libre.1).abs() < 1e-3);
+        assert!((r.elevacion - 1000.).abs() < 1e-9);
+        assert!(r.residuos.iter().all(|v| v.distancia.unwrap().abs() < 1e-3));
+    }
+}
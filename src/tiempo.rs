@@ -0,0 +1,333 @@
+use crate::file_parser::Record;
+use crate::record_parser_gps::count_leap_seconds;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+use rinex::prelude::{Duration, Epoch, TimeScale};
+use serde::Serialize;
+
+// Cómo leer la hora DT/TM de la controladora. La hora civil se interpreta
+// en `escala` (UTC, o GPST en las que muestran la hora GPS sin segundos
+// intercalares) y `utc_offset` es hora local - UTC.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub struct HusoHorario {
+    pub utc_offset: Duration,
+    pub escala: TimeScale,
+}
+
+impl Default for HusoHorario {
+    fn default() -> Self {
+        HusoHorario { utc_offset: Duration::ZERO, escala: TimeScale::UTC }
+    }
+}
+
+impl HusoHorario {
+    // Huso civil, en horas respecto de UTC (-3 para Argentina)
+    pub fn utc(horas: f64) -> Self {
+        HusoHorario { utc_offset: Duration::from_hours(horas), escala: TimeScale::UTC }
+    }
+
+    pub fn a_epoca(&self, local: NaiveDateTime) -> Epoch {
+        let civil = match self.escala {
+            // La hora GPS no tiene segundos intercalares: se cuenta desde
+            // el origen GPS igual que en los registros GT.
+            TimeScale::GPST => {
+                let origen = NaiveDate::from_ymd_opt(1980, 1, 6)
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+                    .expect("Origen GPS");
+                let ms = (local - origen).num_milliseconds();
+                Epoch::from_gpst_duration(Duration::from_milliseconds(ms as f64))
+            },
+            escala => Epoch::from_gregorian(
+                local.year(), local.month() as u8, local.day() as u8,
+                local.hour() as u8, local.minute() as u8, local.second() as u8,
+                local.nanosecond(), escala),
+        };
+        civil - self.utc_offset
+    }
+}
+
+// Diferencia entre el reloj GPS (GT) y la hora de la controladora (DT/TM)
+// leída como UTC.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub struct DesfasajeReloj {
+    pub desfasaje: Duration,
+    pub pares: usize,
+    pub minimo: Duration,
+    pub maximo: Duration,
+    // Fin de la ocupación del par mediano, para los segundos intercalares
+    pub referencia: Epoch,
+}
+
+// Los husos civiles van de a cuarto de hora
+const CUARTO_DE_HORA: f64 = 900.;
+
+impl DesfasajeReloj {
+    // Huso y escala que mejor explican el desfasaje. Si la controladora
+    // lleva hora GPS, el desfasaje difiere del huso en GPST - UTC.
+    pub fn huso(&self) -> HusoHorario {
+        let (y, m, d, ..) = self.referencia.to_gregorian_utc();
+        let intercalares = NaiveDate::from_ymd_opt(y, m as u32, d as u32)
+            .map_or(0, count_leap_seconds);
+
+        let candidato = |desfasaje: f64, escala: TimeScale| {
+            let zona = (desfasaje / CUARTO_DE_HORA).round() * CUARTO_DE_HORA;
+            (HusoHorario { utc_offset: Duration::from_seconds(-zona), escala },
+             (desfasaje - zona).abs())
+        };
+        let (utc, r_utc) = candidato(self.desfasaje.to_seconds(), TimeScale::UTC);
+        let (gpst, r_gpst) = candidato(self.desfasaje.to_seconds() + intercalares as f64, TimeScale::GPST);
+
+        if r_gpst < r_utc { gpst } else { utc }
+    }
+}
+
+// Cada GPS con GT va seguido por su DT/TM, que la controladora escribe al
+// terminar la ocupación. Se compara el fin de la ocupación con esa hora y
+// se toma la mediana de todos los pares del trabajo.
+pub fn estimar_desfasaje(registros: &[&Record]) -> Option<DesfasajeReloj> {
+    let mut pendiente = None;
+    let mut diferencias: Vec<(Duration, Epoch)> = vec![];
+    let utc = HusoHorario::default();
+
+    for r in registros.iter() {
+        match r {
+            Record::GPS(gps) => {
+                pendiente = gps.end_time;
+            },
+            Record::T(t) => {
+                if let (Some(fin), Some(hora)) = (pendiente.take(), t.get_epoch(&utc)) {
+                    diferencias.push((fin - hora, fin));
+                }
+            },
+            _ => {
+                pendiente = None;
+            }
+        }
+    }
+
+    if diferencias.is_empty() {
+        return None;
+    }
+    diferencias.sort_by_key(|d| d.0);
+    let (desfasaje, referencia) = diferencias[diferencias.len() / 2];
+
+    Some(DesfasajeReloj {
+        desfasaje,
+        pares: diferencias.len(),
+        minimo: diferencias[0].0,
+        maximo: diferencias[diferencias.len() - 1].0,
+        referencia,
+    })
+}
+
+// Cómo asignar hora a los registros que no la tienen (bases, antenas)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Default)]
+pub enum Interpolacion {
+    // La última hora conocida; la controladora anota DT/TM antes de
+    // configurar la base o la antena. Lo que está antes de la primera hora
+    // toma la siguiente.
+    #[default]
+    Vecino,
+    // El último DT/TM, acotado entre el fin del GT anterior y el inicio
+    // del siguiente
+    EntreGT,
+}
+
+// Hora propia del registro, sin mirar a los vecinos
+fn epoca_propia(r: &Record, huso: &HusoHorario) -> Option<Epoch> {
+    match r {
+        Record::T(t) => t.get_epoch(huso),
+        Record::GPS(gps) => gps.start_time,
+        _ => None,
+    }
+}
+
+// Una época por registro. Queda None sólo si no hay ninguna hora de la
+// que colgarlo.
+pub fn resolver_epocas(registros: &[&Record], huso: &HusoHorario, estrategia: Interpolacion) -> Vec<Option<Epoch>> {
+    match estrategia {
+        Interpolacion::Vecino => vecino(registros, huso),
+        Interpolacion::EntreGT => entre_gt(registros, huso),
+    }
+}
+
+fn vecino(registros: &[&Record], huso: &HusoHorario) -> Vec<Option<Epoch>> {
+    let mut resultado = Vec::with_capacity(registros.len());
+    let mut ultima = None;
+    for r in registros.iter() {
+        ultima = epoca_propia(r, huso).or(ultima);
+        resultado.push(ultima);
+    }
+    // Lo que está antes de la primera hora toma esa hora
+    if let Some(primera) = resultado.iter().flatten().next().copied() {
+        resultado.iter_mut().take_while(|e| e.is_none()).for_each(|e| *e = Some(primera));
+    }
+    resultado
+}
+
+fn entre_gt(registros: &[&Record], huso: &HusoHorario) -> Vec<Option<Epoch>> {
+    // Inicio del próximo GT, recorriendo de atrás para adelante
+    let mut proximo_inicio = vec![None; registros.len()];
+    let mut inicio = None;
+    for (i, r) in registros.iter().enumerate().rev() {
+        proximo_inicio[i] = inicio;
+        if let Record::GPS(gps) = r {
+            inicio = gps.start_time.or(inicio);
+        }
+    }
+
+    let mut fin_anterior: Option<Epoch> = None;
+    let mut reloj: Option<Epoch> = None;
+    let mut resultado = Vec::with_capacity(registros.len());
+    for (i, r) in registros.iter().enumerate() {
+        let propia = epoca_propia(r, huso);
+        if let Record::T(_) = r {
+            reloj = propia.or(reloj);
+        }
+        let epoca = match (propia, reloj) {
+            (Some(e), _) => Some(e),
+            (_, Some(t)) => {
+                let t = fin_anterior.map_or(t, |f| if t < f { f } else { t });
+                Some(proximo_inicio[i].map_or(t, |s| if t > s { s } else { t }))
+            },
+            (_, None) => fin_anterior.or(proximo_inicio[i]),
+        };
+        resultado.push(epoca);
+        if let Record::GPS(gps) = r {
+            fin_anterior = gps.end_time.or(fin_anterior);
+        }
+    }
+    resultado
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file_parser::{de_archivo_a_registros, lineas_a_registros};
+    use crate::post_parse_gps::combinar_registros;
+
+    #[test]
+    fn test_estimar_desfasaje_sin_gt() {
+        let registros = lineas_a_registros(vec!["--DT04-12-2022", "--TM16:12:43"]).unwrap().registros;
+        let refs: Vec<&Record> = registros.iter().collect();
+        assert_eq!(estimar_desfasaje(&refs), None);
+    }
+
+    #[test]
+    fn test_estimar_desfasaje_un_punto() {
+        let lineas = vec![
+            "GPS,PN1,LA-35.02154763,LN-58.26577623,EL1.244110,--esq",
+            "--GT,PN1,SW2205,ST242097000,EW2205,ET242107000",
+            "--DT04-12-2022",
+            "--TM16:15:06",
+        ];
+        let mut registros = lineas_a_registros(lineas).unwrap().registros;
+        for _ in 0..2 {
+            registros = combinar_registros(registros);
+        }
+        let refs: Vec<&Record> = registros.iter().collect();
+        let d = estimar_desfasaje(&refs).unwrap();
+
+        assert_eq!(d.pares, 1);
+        // TM16:15:06 contra 19:15:07 GPST = 19:14:49 UTC
+        assert_eq!(d.desfasaje, Duration::from_hours(3.) - Duration::from_seconds(17.));
+        // La controladora lleva hora GPS en UTC-3
+        assert_eq!(d.huso(), HusoHorario { utc_offset: Duration::from_hours(-3.), escala: TimeScale::GPST });
+    }
+
+    #[test]
+    fn test_huso_reloj_utc() {
+        let lineas = vec![
+            "GPS,PN1,LA-35.02154763,LN-58.26577623,EL1.244110,--esq",
+            "--GT,PN1,SW2205,ST242097000,EW2205,ET242107000",
+            "--DT04-12-2022",
+            "--TM16:14:49",
+        ];
+        let mut registros = lineas_a_registros(lineas).unwrap().registros;
+        for _ in 0..2 {
+            registros = combinar_registros(registros);
+        }
+        let refs: Vec<&Record> = registros.iter().collect();
+        let huso = estimar_desfasaje(&refs).unwrap().huso();
+
+        assert_eq!(huso, HusoHorario::utc(-3.));
+    }
+
+    #[test]
+    fn test_a_epoca() {
+        let local = NaiveDate::from_ymd_opt(2022, 4, 12).unwrap().and_hms_opt(16, 15, 6).unwrap();
+
+        let e = HusoHorario::utc(-3.).a_epoca(local);
+        assert_eq!(e, Epoch::from_gregorian_utc(2022, 4, 12, 19, 15, 6, 0));
+        assert_eq!(e.time_scale, TimeScale::UTC);
+
+        let huso = HusoHorario { utc_offset: Duration::from_hours(-3.), escala: TimeScale::GPST };
+        let e = huso.a_epoca(local);
+        assert_eq!(e, Epoch::from_gregorian_utc(2022, 4, 12, 19, 14, 48, 0));
+        assert_eq!(e.time_scale, TimeScale::GPST);
+    }
+
+    #[test]
+    fn test_estimar_desfasaje_archivo() {
+        let registros = de_archivo_a_registros(std::path::Path::new("tests/test.rw5"));
+        let refs: Vec<&Record> = registros.iter().collect();
+        let d = estimar_desfasaje(&refs).unwrap();
+
+        assert_eq!(d.pares, 555);
+        assert!((d.desfasaje - Duration::from_hours(3.)).abs() < Duration::from_seconds(30.));
+        assert!(d.maximo - d.minimo < Duration::from_seconds(5.));
+        assert_eq!(d.huso().escala, TimeScale::GPST);
+    }
+
+    fn lineas_combinadas(lineas: Vec<&str>) -> Vec<Record> {
+        let mut registros = lineas_a_registros(lineas).unwrap().registros;
+        for _ in 0..2 {
+            registros = combinar_registros(registros);
+        }
+        registros
+    }
+
+    #[test]
+    fn test_resolver_epocas() {
+        let registros = lineas_combinadas(vec![
+            "--Antenna Type: [HX-CSX049A],RA0.0645m,SHMP0.0925m,L10.0260m,L20.0222m,--",
+            "GPS,PN1,LA-35.02154763,LN-58.26577623,EL1.244110,--esq",
+            "--GT,PN1,SW2205,ST242097000,EW2205,ET242107000",
+            "--DT04-12-2022",
+            "--TM16:14:40",
+            "--Antenna Type: [HX-CSX049A],RA0.0645m,SHMP0.0925m,L10.0260m,L20.0222m,--",
+            "GPS,PN2,LA-35.02154763,LN-58.26577623,EL1.244110,--esq",
+            "--GT,PN2,SW2205,ST242130000,EW2205,ET242140000",
+        ]);
+        let refs: Vec<&Record> = registros.iter().collect();
+        assert!(matches!(refs[0], Record::AT(_)));
+        assert!(matches!(refs[3], Record::AT(_)));
+
+        let huso = HusoHorario::utc(-3.);
+        let inicio_1 = Epoch::from_gpst_duration(Duration::from_seconds(2205. * 604800. + 242097.));
+        let fin_1 = Epoch::from_gpst_duration(Duration::from_seconds(2205. * 604800. + 242107.));
+        let tm = Epoch::from_gregorian_utc(2022, 4, 12, 19, 14, 40, 0);
+
+        let v = resolver_epocas(&refs, &huso, Interpolacion::Vecino);
+        assert_eq!(v[0], Some(inicio_1));
+        assert_eq!(v[2], Some(tm));
+        assert_eq!(v[3], Some(tm));
+
+        let g = resolver_epocas(&refs, &huso, Interpolacion::EntreGT);
+        assert_eq!(g[0], Some(inicio_1));
+        assert_eq!(g[2], Some(tm));
+        // El DT/TM es anterior al fin del punto 1, la antena se acota a ese fin
+        assert_eq!(g[3], Some(fin_1));
+    }
+
+    #[test]
+    fn test_resolver_epocas_sin_hora() {
+        let registros = lineas_combinadas(vec![
+            "--Antenna Type: [HX-CSX049A],RA0.0645m,SHMP0.0925m,L10.0260m,L20.0222m,--",
+            "GPS,PN1,LA-35.02154763,LN-58.26577623,EL1.244110,--esq",
+        ]);
+        let refs: Vec<&Record> = registros.iter().collect();
+        let huso = HusoHorario::default();
+        assert_eq!(resolver_epocas(&refs, &huso, Interpolacion::Vecino), vec![None, None]);
+        assert_eq!(resolver_epocas(&refs, &huso, Interpolacion::EntreGT), vec![None, None]);
+    }
+}
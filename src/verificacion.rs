@@ -0,0 +1,108 @@
+use crate::proyeccion::{Geodesicas, TransversaMercator};
+use serde::Serialize;
+
+// Diferencia entre el N/E que guardó la controladora (--GS) y el que sale
+// de proyectar LA/LN con la proyección del trabajo. Residuo = GS - calculado.
+// En elevación se compara el EL del GS con el del registro, que la
+// controladora debería haber copiado tal cual.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResiduoGrilla {
+    pub nombre: String,
+    pub norte_gs: f64,
+    pub este_gs: f64,
+    pub norte_calculado: f64,
+    pub este_calculado: f64,
+    pub residuo_norte: f64,
+    pub residuo_este: f64,
+    pub elevacion_gs: Option<f64>,
+    pub residuo_elevacion: Option<f64>,
+}
+
+impl ResiduoGrilla {
+    pub fn horizontal(&self) -> f64 {
+        self.residuo_norte.hypot(self.residuo_este)
+    }
+}
+
+// None si el registro no tiene --GS
+pub fn residuo_grilla<G: Geodesicas>(proyeccion: &TransversaMercator, registro: &G) -> Option<ResiduoGrilla> {
+    let (norte_gs, este_gs) = registro.grilla()?;
+    let (latitud, longitud, elevacion) = registro.geodesicas();
+    let (norte_calculado, este_calculado) = proyeccion.a_plano(latitud, longitud);
+    Some(ResiduoGrilla {
+        nombre: registro.nombre().to_string(),
+        norte_gs,
+        este_gs,
+        norte_calculado,
+        este_calculado,
+        residuo_norte: norte_gs - norte_calculado,
+        residuo_este: este_gs - este_calculado,
+        elevacion_gs: registro.elevacion_grilla(),
+        residuo_elevacion: registro.elevacion_grilla().map(|h| h - elevacion),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ResumenGrilla {
+    pub puntos: usize,
+    // Media de los residuos: un corrimiento común indica una localización
+    // o un meridiano central distinto en la controladora
+    pub medio_norte: f64,
+    pub medio_este: f64,
+    pub maximo: f64,
+    pub fuera_de_tolerancia: usize,
+    pub maximo_elevacion: f64,
+    pub fuera_de_tolerancia_elevacion: usize,
+}
+
+pub fn resumir(residuos: &[ResiduoGrilla], tolerancia: f64) -> Option<ResumenGrilla> {
+    if residuos.is_empty() {
+        return None;
+    }
+    let n = residuos.len() as f64;
+    Some(ResumenGrilla {
+        puntos: residuos.len(),
+        medio_norte: residuos.iter().map(|r| r.residuo_norte).sum::<f64>() / n,
+        medio_este: residuos.iter().map(|r| r.residuo_este).sum::<f64>() / n,
+        maximo: residuos.iter().map(|r| r.horizontal()).fold(0., f64::max),
+        fuera_de_tolerancia: residuos.iter().filter(|r| r.horizontal() > tolerancia).count(),
+        maximo_elevacion: residuos.iter().filter_map(|r| r.residuo_elevacion).map(f64::abs).fold(0., f64::max),
+        fuera_de_tolerancia_elevacion: residuos.iter()
+            .filter(|r| r.residuo_elevacion.is_some_and(|h| h.abs() > tolerancia))
+            .count(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proyeccion::GRS80;
+    use crate::record_parser_gps::{parse_gps_record, parse_gs_record};
+
+    #[test]
+    fn test_residuo_grilla() {
+        let proy = TransversaMercator { latitud_origen: -90., ..TransversaMercator::new(GRS80, -58.5, 1., 500_000., 0.) };
+        let gps = parse_gps_record("GPS,PN7,LA-35.02153129,LN-58.26579176,EL1.6280,--").unwrap();
+        assert_eq!(residuo_grilla(&proy, &gps), None);
+
+        let (n, e) = proy.a_plano(gps.latitude, gps.longitude);
+        let gs = parse_gs_record(&format!("--GS,PN7,N {:.4},E {:.4},EL1.6280,--", n + 0.02, e - 0.01)).unwrap();
+        let gps = gps.aplicar_gs(gs).unwrap();
+
+        let r = residuo_grilla(&proy, &gps).unwrap();
+        assert!((r.residuo_norte - 0.02).abs() < 1e-4);
+        assert!((r.residuo_este + 0.01).abs() < 1e-4);
+        assert!(r.residuo_elevacion.unwrap().abs() < 1e-9);
+
+        // Una elevación distinta en el GS ya no se pierde
+        let gs = parse_gs_record(&format!("--GS,PN7,N {:.4},E {:.4},EL1.6580,--", n, e)).unwrap();
+        let distinta = residuo_grilla(&proy, &gps.clone().aplicar_gs(gs).unwrap()).unwrap();
+        assert!((distinta.residuo_elevacion.unwrap() - 0.03).abs() < 1e-9);
+
+        let resumen = resumir(&[r, distinta], 0.01).unwrap();
+        assert_eq!(resumen.fuera_de_tolerancia, 1);
+        assert_eq!(resumen.fuera_de_tolerancia_elevacion, 1);
+        assert!((resumen.maximo_elevacion - 0.03).abs() < 1e-9);
+        assert_eq!(resumir(&[], 0.01), None);
+    }
+}